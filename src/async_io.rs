@@ -0,0 +1,140 @@
+use crate::data_storage_manager::DSMgr;
+use crate::define::PageNo;
+use crate::disk_manager::DiskManager;
+use crate::page::PageData;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+struct Slot<T> {
+    value: Option<T>,
+    waker: Option<Waker>,
+}
+
+/// Future resolved by an I/O thread once the submitted job has run. It works
+/// with any executor: completion simply wakes the task that polled it last.
+///
+/// Dropping the future does not cancel the job. Its result is dropped
+/// instead of being returned: by the I/O thread if the job was still
+/// running, otherwise along with the future.
+pub struct IoFuture<T> {
+    slot: Arc<Mutex<Slot<T>>>,
+}
+
+impl<T> Future for IoFuture<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut slot = self.slot.lock().unwrap();
+        match slot.value.take() {
+            Some(value) => Poll::Ready(value),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Small pool of threads that run blocking I/O on behalf of async callers.
+pub struct IoPool {
+    sender: Mutex<Sender<Job>>,
+}
+
+impl IoPool {
+    pub fn new(threads: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads.max(1) {
+            let receiver = Arc::clone(&receiver);
+            // Workers exit once the pool, and with it the sender, is dropped.
+            thread::spawn(move || loop {
+                let job = receiver.lock().unwrap().recv();
+                match job {
+                    Ok(job) => job(),
+                    Err(_) => break,
+                }
+            });
+        }
+        Self {
+            sender: Mutex::new(sender),
+        }
+    }
+
+    /// Runs `f` on an I/O thread and returns a future for its result.
+    pub fn submit<T, F>(&self, f: F) -> IoFuture<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let slot = Arc::new(Mutex::new(Slot { value: None, waker: None }));
+        let completion = Arc::clone(&slot);
+        let job: Job = Box::new(move || {
+            let value = f();
+            let mut slot = completion.lock().unwrap();
+            slot.value = Some(value);
+            if let Some(waker) = slot.waker.take() {
+                waker.wake();
+            }
+        });
+        self.sender
+            .lock()
+            .unwrap()
+            .send(job)
+            .expect("I/O threads are running while the pool is alive");
+        IoFuture { slot }
+    }
+}
+
+/// Async front end of a disk manager: every call is submitted to an
+/// `IoPool` and the caller's task yields until it completes.
+pub struct AsyncDSMgr<D: DiskManager = DSMgr> {
+    disk_manager: Arc<D>,
+    pool: IoPool,
+}
+
+impl<D: DiskManager> AsyncDSMgr<D> {
+    pub fn new(disk_manager: Arc<D>, threads: usize) -> Self {
+        Self {
+            disk_manager,
+            pool: IoPool::new(threads),
+        }
+    }
+
+    pub async fn new_page(&self) -> std::io::Result<PageNo> {
+        let disk_manager = Arc::clone(&self.disk_manager);
+        self.pool.submit(move || disk_manager.allocate()).await
+    }
+
+    pub async fn read_page(&self, page_no: PageNo) -> std::io::Result<PageData> {
+        let disk_manager = Arc::clone(&self.disk_manager);
+        self.pool
+            .submit(move || {
+                let mut data = PageData::zeroed(disk_manager.page_size());
+                disk_manager.read_page(page_no, &mut data)?;
+                Ok(data)
+            })
+            .await
+    }
+
+    pub async fn write_page(&self, page_no: PageNo, data: PageData) -> std::io::Result<()> {
+        let disk_manager = Arc::clone(&self.disk_manager);
+        self.pool
+            .submit(move || disk_manager.write_page(page_no, &data))
+            .await
+    }
+
+    /// Runs arbitrary blocking work on this manager's I/O threads.
+    pub fn submit<T, F>(&self, f: F) -> IoFuture<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        self.pool.submit(f)
+    }
+}
@@ -0,0 +1,160 @@
+use adbs_lab::btree::{BPlusTree, BytewiseComparator};
+use adbs_lab::buffer_pool_manager::{BufferPoolManager, ReplacePolicyType};
+use adbs_lab::define::PageId;
+use adbs_lab::heap_file::RecordId;
+use adbs_lab::memory_disk_manager::MemDiskManager;
+use clap::Parser;
+use rand::Rng;
+use std::collections::BTreeSet;
+use std::io::ErrorKind;
+use std::ops::Bound;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+type Tree = BPlusTree<MemDiskManager, BytewiseComparator>;
+
+/// Hammers one B+ tree from many threads and checks every answer.
+///
+/// Each thread owns the keys equal to its number modulo the thread count,
+/// and is the only one changing them, so it knows exactly which of its keys
+/// any lookup or scan must find, whatever the other threads do meanwhile.
+#[derive(Parser, Debug)]
+#[command(author, version, about = "Concurrent B+ tree stress test", long_about = None)]
+struct Args {
+    /// Number of worker threads.
+    #[arg(short = 't', long = "threads", default_value_t = 8)]
+    threads: u64,
+
+    /// Operations per thread.
+    #[arg(short = 'n', long = "ops", default_value_t = 50000)]
+    ops: usize,
+
+    /// Distinct keys per thread.
+    #[arg(short = 'k', long = "keys", default_value_t = 20000)]
+    keys: u64,
+
+    /// Key size in bytes; large keys make small nodes and many splits.
+    #[arg(short = 's', long = "key-size", default_value_t = 16)]
+    key_size: usize,
+
+    /// Frames in the buffer pool.
+    #[arg(short = 'f', long = "frames", default_value_t = 1024)]
+    frames: usize,
+}
+
+/// The key for number `k`: big-endian, so byte order is numeric order.
+fn key(k: u64, key_size: usize) -> Vec<u8> {
+    let mut key = vec![0; key_size];
+    key[..8].copy_from_slice(&k.to_be_bytes());
+    key
+}
+
+fn key_no(key: &[u8]) -> u64 {
+    u64::from_be_bytes(key[..8].try_into().unwrap())
+}
+
+fn rid(k: u64) -> RecordId {
+    RecordId::new(PageId::new(1, k), (k % 1000) as u16)
+}
+
+/// Runs random operations on the keys of thread `id`, checking each result
+/// against the set of keys it has stored. Returns that set.
+fn work(tree: &Tree, args: &Args, id: u64) -> BTreeSet<u64> {
+    let mut rng = rand::thread_rng();
+    let mut stored = BTreeSet::new();
+    let own = |rng: &mut rand::rngs::ThreadRng| rng.gen_range(0..args.keys) * args.threads + id;
+    let total_keys = args.keys * args.threads;
+    for _ in 0..args.ops {
+        match rng.gen_range(0..100) {
+            0..=44 => {
+                let k = own(&mut rng);
+                match tree.insert(&key(k, args.key_size), rid(k)) {
+                    Ok(()) => assert!(stored.insert(k), "thread {}: inserted {} twice", id, k),
+                    Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                        assert!(stored.contains(&k), "thread {}: {} reported present", id, k)
+                    }
+                    Err(e) => panic!("thread {}: insert {}: {}", id, k, e),
+                }
+            }
+            45..=79 => {
+                let k = own(&mut rng);
+                match tree.delete(&key(k, args.key_size)) {
+                    Ok(()) => assert!(stored.remove(&k), "thread {}: deleted absent {}", id, k),
+                    Err(e) if e.kind() == ErrorKind::NotFound => {
+                        assert!(!stored.contains(&k), "thread {}: {} reported absent", id, k)
+                    }
+                    Err(e) => panic!("thread {}: delete {}: {}", id, k, e),
+                }
+            }
+            80..=97 => {
+                let k = own(&mut rng);
+                let found = tree.get(&key(k, args.key_size)).unwrap();
+                assert_eq!(found, stored.contains(&k).then(|| rid(k)), "thread {}: get {}", id, k);
+            }
+            _ => {
+                let start = rng.gen_range(0..total_keys);
+                let end = start + rng.gen_range(0..total_keys / 8 + 1);
+                let (start_key, end_key) = (key(start, args.key_size), key(end, args.key_size));
+                let mut last = None;
+                let mut own_keys = Vec::new();
+                for entry in tree.range(Bound::Included(&start_key), Bound::Excluded(&end_key)).unwrap() {
+                    let (found_key, found_rid) = entry.unwrap();
+                    let k = key_no(&found_key);
+                    assert!(last < Some(k), "thread {}: scan out of order at {}", id, k);
+                    assert!((start..end).contains(&k), "thread {}: scan returned {}", id, k);
+                    assert_eq!(found_rid, rid(k), "thread {}: scan record of {}", id, k);
+                    last = Some(k);
+                    if k % args.threads == id {
+                        own_keys.push(k);
+                    }
+                }
+                let expected: Vec<u64> = stored.range(start..end).copied().collect();
+                assert_eq!(own_keys, expected, "thread {}: scan of {}..{}", id, start, end);
+            }
+        }
+    }
+    stored
+}
+
+fn main() -> std::io::Result<()> {
+    let args = Arc::new(Args::parse());
+    let bmgr = Arc::new(BufferPoolManager::with_disk_manager(
+        MemDiskManager::new(),
+        ReplacePolicyType::Clock,
+        args.frames,
+    ));
+    let tree = Arc::new(BPlusTree::open(Arc::clone(&bmgr), 0, args.key_size, BytewiseComparator)?);
+
+    let start = Instant::now();
+    let threads: Vec<_> = (0..args.threads)
+        .map(|id| {
+            let (tree, args) = (Arc::clone(&tree), Arc::clone(&args));
+            thread::spawn(move || work(&tree, &args, id))
+        })
+        .collect();
+    let mut expected = BTreeSet::new();
+    for handle in threads {
+        expected.extend(handle.join().expect("Worker panicked"));
+    }
+    let elapsed = start.elapsed();
+
+    let found: Vec<u64> = tree.scan().map(|entry| entry.map(|(key, _)| key_no(&key))).collect::<Result<_, _>>()?;
+    assert!(found.iter().copied().eq(expected.iter().copied()), "final scan differs");
+    for &k in &expected {
+        assert_eq!(tree.get(&key(k, args.key_size))?, Some(rid(k)), "final get {}", k);
+    }
+
+    let ops = args.threads as usize * args.ops;
+    println!(
+        "{} threads, {} ops in {:.2?} ({:.0} ops/s); {} keys in {} pages, {} evictions",
+        args.threads,
+        ops,
+        elapsed,
+        ops as f64 / elapsed.as_secs_f64(),
+        expected.len(),
+        bmgr.get_num_pages(0).unwrap_or(0),
+        bmgr.get_clean_eviction_num() + bmgr.get_dirty_eviction_num(),
+    );
+    Ok(())
+}
@@ -0,0 +1,175 @@
+use adbs_lab::data_storage_manager::{DSMgr, DSMgrOptions};
+use adbs_lab::define::{PageNo, PAGE_SIZE};
+use adbs_lab::disk_manager::DiskManager;
+use adbs_lab::page::PageData;
+use clap::Parser;
+use rand::Rng;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Compares page read throughput of the storage backends.
+#[derive(Parser, Debug)]
+#[command(author, version, about = "Page I/O benchmark for DSMgr backends", long_about = None)]
+struct Args {
+    /// Database file to read from; created if missing.
+    #[arg(short = 'f', long = "file", default_value = "bench.dbf")]
+    file: String,
+
+    /// Number of pages in the file.
+    #[arg(short = 'p', long = "pages", default_value_t = 50000)]
+    pages: usize,
+
+    /// Random page reads per thread.
+    #[arg(short = 'n', long = "reads", default_value_t = 100000)]
+    reads: usize,
+
+    /// Number of reader threads.
+    #[arg(short = 't', long = "threads", default_value_t = 4)]
+    threads: usize,
+
+    /// Pages per `read_pages` batch.
+    #[arg(short = 'b', long = "batch", default_value_t = 32)]
+    batch: usize,
+
+    /// Page size used when the file is created.
+    #[arg(short = 's', long = "page-size", default_value_t = PAGE_SIZE)]
+    page_size: usize,
+
+    /// Open the DSMgr backends with O_DIRECT.
+    #[arg(short = 'd', long = "direct")]
+    direct: bool,
+}
+
+/// The storage manager as it used to be: one file behind a mutex, seek then
+/// read.
+struct SeekReadFile {
+    file: Mutex<File>,
+    page_size: usize,
+}
+
+impl SeekReadFile {
+    fn read_page(&self, page_no: PageNo, data: &mut [u8]) -> std::io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        // Skip the header page.
+        file.seek(SeekFrom::Start((page_no + 1) * self.page_size as u64))?;
+        file.read_exact(data)
+    }
+}
+
+fn run<F>(args: &Args, name: &str, batch: usize, read: F)
+where
+    F: Fn(&[PageNo]) + Send + Sync + 'static,
+{
+    let read = Arc::new(read);
+    let start = Instant::now();
+    let threads: Vec<_> = (0..args.threads)
+        .map(|_| {
+            let read = Arc::clone(&read);
+            let (pages, reads, batch) = (args.pages, args.reads, batch.max(1));
+            thread::spawn(move || {
+                let mut rng = rand::thread_rng();
+                let mut done = 0;
+                while done < reads {
+                    let n = batch.min(reads - done);
+                    let page_nos: Vec<PageNo> = (0..n).map(|_| rng.gen_range(0..pages) as PageNo).collect();
+                    read(&page_nos);
+                    done += n;
+                }
+            })
+        })
+        .collect();
+    for handle in threads {
+        handle.join().expect("Reader panicked");
+    }
+    report(name, args.threads * args.reads, start.elapsed());
+}
+
+fn report(name: &str, reads: usize, elapsed: Duration) {
+    println!(
+        "{:<28} {:>10.0} pages/s  ({:.2?})",
+        name,
+        reads as f64 / elapsed.as_secs_f64(),
+        elapsed
+    );
+}
+
+fn main() -> std::io::Result<()> {
+    let args = Args::parse();
+
+    let page_size = {
+        let dsmgr = DSMgr::open_with(
+            &args.file,
+            DSMgrOptions {
+                page_size: args.page_size,
+                ..DSMgrOptions::default()
+            },
+        )?;
+        let num_pages = dsmgr.get_num_pages();
+        if num_pages < args.pages as PageNo {
+            dsmgr.allocate_pages(args.pages as PageNo - num_pages)?;
+        }
+        dsmgr.get_page_size()
+    };
+
+    let seek_read = SeekReadFile {
+        file: Mutex::new(File::open(&args.file)?),
+        page_size,
+    };
+    run(&args, "seek+read Mutex<File>", 1, move |page_nos| {
+        let mut data = vec![0u8; page_size];
+        for &page_no in page_nos {
+            seek_read.read_page(page_no, &mut data).unwrap();
+        }
+    });
+
+    // The io-uring feature adds fields that stay at their defaults.
+    #[allow(clippy::needless_update)]
+    let options = DSMgrOptions {
+        direct_io: args.direct,
+        ..DSMgrOptions::default()
+    };
+    let dsmgr = DSMgr::open_with(&args.file, options)?;
+    run(&args, "pread", 1, move |page_nos| {
+        let mut data = PageData::zeroed(page_size);
+        for &page_no in page_nos {
+            dsmgr.read_page(page_no, &mut data).unwrap();
+        }
+    });
+
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    {
+        let options = DSMgrOptions {
+            uring_queue_depth: Some(args.batch as u32),
+            ..options
+        };
+        let dsmgr = DSMgr::open_with(&args.file, options)?;
+        run(&args, "io_uring", 1, move |page_nos| {
+            let mut data = PageData::zeroed(page_size);
+            for &page_no in page_nos {
+                dsmgr.read_page(page_no, &mut data).unwrap();
+            }
+        });
+
+        let dsmgr = DSMgr::open_with(&args.file, options)?;
+        run(&args, &format!("io_uring, batches of {}", args.batch), args.batch, move |page_nos| {
+            let mut buffers: Vec<PageData> = page_nos.iter().map(|_| PageData::zeroed(page_size)).collect();
+            let mut reads: Vec<(PageNo, &mut [u8])> = page_nos
+                .iter()
+                .zip(buffers.iter_mut())
+                .map(|(&page_no, data)| (page_no, &mut data[..]))
+                .collect();
+            for result in dsmgr.read_pages(&mut reads) {
+                result.unwrap();
+            }
+        });
+    }
+    #[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+    {
+        println!("io_uring: build with --features io-uring to include it");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,929 @@
+use crate::buffer_pool_manager::{BufferPoolManager, PinnedPage};
+use crate::define::{FileId, PageId, PageNo};
+use crate::disk_manager::DiskManager;
+use crate::heap_file::{RecordId, RECORD_ID_SIZE};
+use crate::page::{Page, PageType, PAGE_HEADER_SIZE};
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind};
+use std::ops::{Bound, Deref, DerefMut};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread;
+
+/// The meta page, first page of the tree's file.
+const META_PAGE_NO: PageNo = 0;
+
+/// In the meta page after the page header: u32 key size, u64 root page.
+const META_KEY_SIZE_OFFSET: usize = PAGE_HEADER_SIZE;
+const META_ROOT_OFFSET: usize = PAGE_HEADER_SIZE + 4;
+
+/// In a node after the page header: u32 number of entries, u32 level, u64
+/// link. Leaves are level 0, their parents level 1 and so on. The link of
+/// a leaf is the next leaf, that of an inner node its leftmost child.
+const NODE_LEN_OFFSET: usize = PAGE_HEADER_SIZE;
+const NODE_LEVEL_OFFSET: usize = PAGE_HEADER_SIZE + 4;
+const NODE_LINK_OFFSET: usize = PAGE_HEADER_SIZE + 8;
+const NODE_ENTRIES_OFFSET: usize = PAGE_HEADER_SIZE + 16;
+
+/// Link of the last leaf. The meta page is never a node, so its number is
+/// free to mean "none".
+const NO_PAGE: PageNo = META_PAGE_NO;
+
+/// Size of the child pointer in inner node entries.
+const CHILD_SIZE: usize = 8;
+
+/// Fewest entries a leaf must have room for.
+const MIN_NODE_CAPACITY: usize = 4;
+
+/// Order of the keys in a `BPlusTree`.
+pub trait KeyComparator: Send + Sync {
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering;
+}
+
+/// Compares keys byte by byte, which orders strings and unsigned big-endian
+/// integers naturally.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BytewiseComparator;
+
+impl KeyComparator for BytewiseComparator {
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+impl<F: Fn(&[u8], &[u8]) -> Ordering + Send + Sync> KeyComparator for F {
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        self(a, b)
+    }
+}
+
+/// B+ tree mapping fixed-size keys to record ids, stored in one data file
+/// of the pool: its meta page first, then the nodes. Keys are unique and
+/// ordered by the tree's `KeyComparator`.
+///
+/// Every node is a page fetched through `BufferPoolManager::fix_page`.
+/// Inner nodes hold `n` keys and `n + 1` children, leaves hold the entries
+/// and are chained in key order for range scans. Nodes other than the root
+/// stay at least half full: a full node is split on insert, and one
+/// falling below half borrows an entry from a sibling or is merged into it
+/// on delete. Pages of merged nodes go back to the tablespace.
+///
+/// Any number of threads may use the tree at once. They descend by latch
+/// coupling: a child's page latch is taken before the parent's is
+/// released. Lookups and scans take shared latches. Inserts and deletes
+/// first descend the same way and latch only the leaf exclusively; if the
+/// leaf would have to be split or rebalanced they start over, latching
+/// exclusively all the way down and keeping the latches of every node the
+/// change may reach up to.
+///
+/// The index is not crash-safe: neither entries nor structure changes are
+/// logged. Changes are durable once `flush` returns. A crash in between
+/// may keep some of the node pages a split or merge wrote and not others,
+/// leaving a tree that loses entries or is corrupt; such an index has to
+/// be rebuilt, e.g. from the heap it indexes.
+pub struct BPlusTree<D: DiskManager, C: KeyComparator = BytewiseComparator> {
+    bmgr: Arc<BufferPoolManager<D>>,
+    file_id: FileId,
+    key_size: usize,
+    comparator: C,
+    /// The root page. Latched like a parent of the root, so the root cannot
+    /// change under a thread descending from it.
+    root: RwLock<PageNo>,
+}
+
+impl<D: DiskManager, C: KeyComparator> BPlusTree<D, C> {
+    /// Opens the tree stored in `file_id`, which must already be in the
+    /// pool; an empty file becomes an empty tree with keys of `key_size`
+    /// bytes. An existing tree must have been created with the same key
+    /// size and an equivalent comparator.
+    pub fn open(
+        bmgr: Arc<BufferPoolManager<D>>,
+        file_id: FileId,
+        key_size: usize,
+        comparator: C,
+    ) -> std::io::Result<Self> {
+        let page_size = bmgr.get_page_size();
+        if key_size == 0 || (page_size - NODE_ENTRIES_OFFSET) / (key_size + RECORD_ID_SIZE) < MIN_NODE_CAPACITY {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("keys of {} bytes do not suit {}-byte pages", key_size, page_size),
+            ));
+        }
+        let num_pages = bmgr
+            .get_num_pages(file_id)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("no file {} in the pool", file_id)))?;
+        if num_pages == 0 {
+            bmgr.tablespace().allocate(file_id)?;
+        }
+        let tree = Self {
+            bmgr,
+            file_id,
+            key_size,
+            comparator,
+            root: RwLock::new(NO_PAGE),
+        };
+
+        let meta = tree.fix(META_PAGE_NO)?;
+        let page_type = meta.read().get_page_type();
+        let root = match page_type {
+            Some(PageType::BTreeMeta) => {
+                let page = meta.read();
+                let data = page.get_data();
+                let stored_key_size = u32::from_le_bytes(data[META_KEY_SIZE_OFFSET..][..4].try_into().unwrap());
+                if stored_key_size as usize != key_size {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("the tree in file {} has keys of {} bytes", file_id, stored_key_size),
+                    ));
+                }
+                PageNo::from_le_bytes(data[META_ROOT_OFFSET..][..8].try_into().unwrap())
+            }
+            Some(PageType::Unformatted) => {
+                let root = tree.allocate_node(0)?.pin.page_id.page_no;
+                let mut page = meta.write();
+                page.init(PageType::BTreeMeta);
+                page.set_dirty(true);
+                page.get_data_mut()[META_KEY_SIZE_OFFSET..][..4].copy_from_slice(&(key_size as u32).to_le_bytes());
+                page.get_data_mut()[META_ROOT_OFFSET..][..8].copy_from_slice(&root.to_le_bytes());
+                root
+            }
+            page_type => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("page {} should be a B+ tree meta page, found {:?}", meta.page_id, page_type),
+                ))
+            }
+        };
+        drop(meta);
+        *tree.root.write().unwrap() = root;
+        Ok(tree)
+    }
+
+    /// The record id stored under `key`, if any.
+    pub fn get(&self, key: &[u8]) -> std::io::Result<Option<RecordId>> {
+        self.check_key(key)?;
+        let leaf = self.find_leaf(Some(key))?;
+        Ok(leaf.node.search(key, &self.comparator).ok().map(|i| leaf.node.record_id(i)))
+    }
+
+    /// Stores `rid` under `key`. Fails with `AlreadyExists` if the key is
+    /// taken.
+    pub fn insert(&self, key: &[u8], rid: RecordId) -> std::io::Result<()> {
+        self.check_key(key)?;
+        let entry = [key, &rid.encode()].concat();
+        {
+            let (mut leaf, _) = self.lock_leaf(key)?;
+            let pos = self.insert_pos(&leaf.node, key)?;
+            if leaf.node.len() < leaf.node.capacity() {
+                leaf.node.insert(pos, &entry);
+                return Ok(());
+            }
+        }
+
+        // The leaf has to be split: start over, keeping every node latched
+        // that the split may reach.
+        let mut root = Some(self.root.write().unwrap());
+        let mut path = Vec::new();
+        let mut node = self.write_node(**root.as_ref().unwrap())?;
+        loop {
+            if node.node.len() < node.node.capacity() {
+                path.clear();
+                root = None;
+            }
+            if node.node.is_leaf() {
+                break;
+            }
+            let child_index = node.node.child_index(key, &self.comparator);
+            let child = self.write_node(node.node.child(child_index))?;
+            path.push((node, child_index));
+            node = child;
+        }
+
+        let pos = self.insert_pos(&node.node, key)?;
+        if node.node.len() < node.node.capacity() {
+            node.node.insert(pos, &entry);
+            return Ok(());
+        }
+        let mut right = self.allocate_node(0)?;
+        let mut key = Self::split(&mut node.node, &mut right.node, right.pin.page_id.page_no, pos, &entry);
+        let mut child = right.pin.page_id.page_no;
+        let mut level = 0;
+        drop((right, node));
+        while let Some((mut node, child_index)) = path.pop() {
+            let entry = [&key[..], &child.to_le_bytes()].concat();
+            if node.node.len() < node.node.capacity() {
+                node.node.insert(child_index, &entry);
+                return Ok(());
+            }
+            level = node.node.level();
+            let mut right = self.allocate_node(level)?;
+            key = Self::split(&mut node.node, &mut right.node, right.pin.page_id.page_no, child_index, &entry);
+            child = right.pin.page_id.page_no;
+        }
+
+        // The root was split, so it was latched all along.
+        let mut root = root.expect("the root is latched when it splits");
+        let mut new_root = self.allocate_node(level + 1)?;
+        new_root.node.set_link(*root);
+        new_root.node.insert(0, &[&key[..], &child.to_le_bytes()].concat());
+        self.set_root(&mut root, new_root.pin.page_id.page_no)
+    }
+
+    /// Removes `key` and its record id. Fails with `NotFound` if there is
+    /// no such key.
+    pub fn delete(&self, key: &[u8]) -> std::io::Result<()> {
+        self.check_key(key)?;
+        {
+            let (mut leaf, is_root) = self.lock_leaf(key)?;
+            let pos = self.delete_pos(&leaf.node, key)?;
+            if is_root || leaf.node.len() > leaf.node.min_len() {
+                leaf.node.remove(pos);
+                return Ok(());
+            }
+        }
+
+        // The leaf has to be rebalanced: start over, keeping every node
+        // latched that rebalancing may reach.
+        let mut root = Some(self.root.write().unwrap());
+        let mut path = Vec::new();
+        let mut node = self.write_node(**root.as_ref().unwrap())?;
+        loop {
+            let is_safe = match path.is_empty() && root.is_some() {
+                true => node.node.is_leaf() || node.node.len() > 1,
+                false => node.node.len() > node.node.min_len(),
+            };
+            if is_safe {
+                path.clear();
+                root = None;
+            }
+            if node.node.is_leaf() {
+                break;
+            }
+            let child_index = node.node.child_index(key, &self.comparator);
+            let child = self.write_node(node.node.child(child_index))?;
+            path.push((node, child_index));
+            node = child;
+        }
+
+        let pos = self.delete_pos(&node.node, key)?;
+        node.node.remove(pos);
+        while let Some((mut parent, child_index)) = path.pop() {
+            if node.node.len() >= node.node.min_len() {
+                return Ok(());
+            }
+            let sibling = if child_index > 0 {
+                parent.node.child(child_index - 1)
+            } else {
+                parent.node.child(1)
+            };
+            let mut sibling = self.write_node(sibling)?;
+            let (left, right, separator) = match child_index > 0 {
+                true => (&mut sibling, &mut node, child_index - 1),
+                false => (&mut node, &mut sibling, 0),
+            };
+            let is_right_short = child_index > 0;
+            let is_merged =
+                self.rebalance(&mut parent.node, &mut left.node, &mut right.node, separator, is_right_short);
+            let right = right.pin.page_id;
+            // Released before its page is freed, so the pool frees it
+            // right away unless a reader passing through still pins it.
+            drop((sibling, node));
+            if !is_merged {
+                return Ok(());
+            }
+            self.free_node(right)?;
+            node = parent;
+        }
+
+        // A root left with a single child hands over to it.
+        if let Some(mut root) = root {
+            if node.node.is_leaf() || node.node.len() > 0 {
+                return Ok(());
+            }
+            let child = node.node.child(0);
+            let page_id = node.pin.page_id;
+            drop(node);
+            self.set_root(&mut root, child)?;
+            self.free_node(page_id)?;
+        }
+        Ok(())
+    }
+
+    /// Iterates in key order over the entries with keys within `start` and
+    /// `end`. Only the leaf being read is latched, so changes made meanwhile
+    /// may or may not be seen, but every entry present throughout the scan
+    /// is returned exactly once.
+    pub fn range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> std::io::Result<BTreeRange<'_, D, C>> {
+        for key in [start, end] {
+            if let Bound::Included(key) | Bound::Excluded(key) = key {
+                self.check_key(key)?;
+            }
+        }
+        Ok(BTreeRange {
+            tree: self,
+            buffer: VecDeque::new(),
+            start: start.map(<[u8]>::to_vec),
+            end: end.map(<[u8]>::to_vec),
+            is_done: false,
+        })
+    }
+
+    /// Iterates over all entries in key order, see `range`.
+    pub fn scan(&self) -> BTreeRange<'_, D, C> {
+        self.range(Bound::Unbounded, Bound::Unbounded)
+            .expect("unbounded ranges have no keys to check")
+    }
+
+    /// Writes back every changed node, and the meta page, and syncs the
+    /// tree's file.
+    pub fn flush(&self) -> std::io::Result<()> {
+        self.bmgr.flush_file(self.file_id).map(|_| ())
+    }
+
+    pub fn get_file_id(&self) -> FileId {
+        self.file_id
+    }
+
+    pub fn get_key_size(&self) -> usize {
+        self.key_size
+    }
+
+    fn check_key(&self, key: &[u8]) -> std::io::Result<()> {
+        if key.len() != self.key_size {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("keys of this tree are {} bytes, got {}", self.key_size, key.len()),
+            ));
+        }
+        Ok(())
+    }
+
+    fn insert_pos<P: Deref<Target = Page>>(&self, leaf: &Node<P>, key: &[u8]) -> std::io::Result<usize> {
+        match leaf.search(key, &self.comparator) {
+            Ok(_) => Err(Error::new(ErrorKind::AlreadyExists, "the key is already in the tree")),
+            Err(pos) => Ok(pos),
+        }
+    }
+
+    fn delete_pos<P: Deref<Target = Page>>(&self, leaf: &Node<P>, key: &[u8]) -> std::io::Result<usize> {
+        leaf.search(key, &self.comparator)
+            .map_err(|_| Error::new(ErrorKind::NotFound, "the key is not in the tree"))
+    }
+
+    fn fix(&self, page_no: PageNo) -> std::io::Result<PinnedPage<'_, D>> {
+        PinnedPage::fix(&self.bmgr, PageId::new(self.file_id, page_no))
+    }
+
+    fn read_node(&self, page_no: PageNo) -> std::io::Result<ReadNode<'_, D>> {
+        let pin = self.fix(page_no)?;
+        Ok(Latched {
+            node: Node::new(pin.read(), self.key_size)?,
+            pin,
+        })
+    }
+
+    fn write_node(&self, page_no: PageNo) -> std::io::Result<WriteNode<'_, D>> {
+        let pin = self.fix(page_no)?;
+        Ok(Latched {
+            node: Node::new(pin.write(), self.key_size)?,
+            pin,
+        })
+    }
+
+    /// Allocates a page and formats it as an empty node of `level`.
+    fn allocate_node(&self, level: u32) -> std::io::Result<WriteNode<'_, D>> {
+        let pin = PinnedPage::fix_new(&self.bmgr, self.file_id)?;
+        Ok(Latched {
+            node: Node::init(pin.write(), self.key_size, level),
+            pin,
+        })
+    }
+
+    fn free_node(&self, page_id: PageId) -> std::io::Result<()> {
+        self.bmgr.deallocate_page(page_id)
+    }
+
+    fn set_root(&self, root: &mut RwLockWriteGuard<'_, PageNo>, page_no: PageNo) -> std::io::Result<()> {
+        let meta = self.fix(META_PAGE_NO)?;
+        let mut page = meta.write();
+        page.set_dirty(true);
+        page.get_data_mut()[META_ROOT_OFFSET..][..8].copy_from_slice(&page_no.to_le_bytes());
+        **root = page_no;
+        Ok(())
+    }
+
+    /// Descends with shared latches to the leaf that holds `key`, or to the
+    /// leftmost leaf if there is no key.
+    fn find_leaf(&self, key: Option<&[u8]>) -> std::io::Result<ReadNode<'_, D>> {
+        let root = self.root.read().unwrap();
+        let mut node = self.read_node(*root)?;
+        drop(root);
+        while !node.node.is_leaf() {
+            let child_index = key.map_or(0, |key| node.node.child_index(key, &self.comparator));
+            node = self.read_node(node.node.child(child_index))?;
+        }
+        Ok(node)
+    }
+
+    /// Descends with shared latches to the leaf that holds `key` and
+    /// latches it exclusively. Also tells whether the leaf is the root.
+    fn lock_leaf(&self, key: &[u8]) -> std::io::Result<(WriteNode<'_, D>, bool)> {
+        let root = self.root.read().unwrap();
+        let mut node = self.read_node(*root)?;
+        if node.node.is_leaf() {
+            // The root stays a leaf while `root` is held.
+            drop(node);
+            return Ok((self.write_node(*root)?, true));
+        }
+        drop(root);
+        loop {
+            let child = node.node.child(node.node.child_index(key, &self.comparator));
+            if node.node.level() == 1 {
+                return Ok((self.write_node(child)?, false));
+            }
+            node = self.read_node(child)?;
+        }
+    }
+
+    /// Splits the full node `left` while inserting `entry` at `pos`, moving
+    /// the upper half into the empty node `right` in page `right_page_no`.
+    /// Returns the key to insert into the parent along with `right_page_no`.
+    fn split<P: DerefMut<Target = Page>>(
+        left: &mut Node<P>,
+        right: &mut Node<P>,
+        right_page_no: PageNo,
+        pos: usize,
+        entry: &[u8],
+    ) -> Vec<u8> {
+        let entry_size = left.entry_size();
+        let mut entries = left.entries().to_vec();
+        entries.splice(pos * entry_size..pos * entry_size, entry.iter().copied());
+        let num_entries = entries.len() / entry_size;
+        if left.is_leaf() {
+            let mid = num_entries.div_ceil(2) * entry_size;
+            left.set_entries(&entries[..mid]);
+            right.set_entries(&entries[mid..]);
+            right.set_link(left.link());
+            left.set_link(right_page_no);
+            right.key(0).to_vec()
+        } else {
+            // The middle entry moves up; its child becomes the leftmost
+            // child of the right node.
+            let mid = num_entries / 2 * entry_size;
+            let (key, child) = entries[mid..mid + entry_size].split_at(left.key_size);
+            left.set_entries(&entries[..mid]);
+            right.set_entries(&entries[mid + entry_size..]);
+            right.set_link(PageNo::from_le_bytes(child.try_into().unwrap()));
+            key.to_vec()
+        }
+    }
+
+    /// Refills the node that fell below half full, which is `right` if
+    /// `is_right_short` and `left` otherwise, from its sibling: by moving one
+    /// entry over if the sibling can spare it, else by merging `right` into
+    /// `left`. `separator` is the index of the parent's key between them.
+    /// Returns whether they were merged, leaving `right` to be freed.
+    fn rebalance<P: DerefMut<Target = Page>>(
+        &self,
+        parent: &mut Node<P>,
+        left: &mut Node<P>,
+        right: &mut Node<P>,
+        separator: usize,
+        is_right_short: bool,
+    ) -> bool {
+        let sibling_len = if is_right_short { left.len() } else { right.len() };
+        let is_leaf = left.is_leaf();
+        let key_size = self.key_size;
+
+        if sibling_len > left.min_len() {
+            if is_right_short {
+                let last = left.entry(left.len() - 1).to_vec();
+                left.remove(left.len() - 1);
+                if is_leaf {
+                    right.insert(0, &last);
+                    parent.set_key(separator, &last[..key_size]);
+                } else {
+                    // Rotate through the parent: its key comes down in front
+                    // of the right node's old leftmost child.
+                    let down = [parent.key(separator), &right.link().to_le_bytes()].concat();
+                    right.insert(0, &down);
+                    right.set_link(PageNo::from_le_bytes(last[key_size..].try_into().unwrap()));
+                    parent.set_key(separator, &last[..key_size]);
+                }
+            } else {
+                let first = right.entry(0).to_vec();
+                right.remove(0);
+                if is_leaf {
+                    left.insert(left.len(), &first);
+                    let key = right.key(0).to_vec();
+                    parent.set_key(separator, &key);
+                } else {
+                    let down = [parent.key(separator), &right.link().to_le_bytes()].concat();
+                    left.insert(left.len(), &down);
+                    right.set_link(PageNo::from_le_bytes(first[key_size..].try_into().unwrap()));
+                    parent.set_key(separator, &first[..key_size]);
+                }
+            }
+            return false;
+        }
+
+        let mut entries = left.entries().to_vec();
+        if is_leaf {
+            left.set_link(right.link());
+        } else {
+            entries.extend_from_slice(parent.key(separator));
+            entries.extend_from_slice(&right.link().to_le_bytes());
+        }
+        entries.extend_from_slice(right.entries());
+        left.set_entries(&entries);
+        parent.remove(separator);
+        true
+    }
+}
+
+/// A pinned and latched node. The latch is released before the pin, as
+/// fields are dropped in order.
+struct Latched<'a, D: DiskManager, G> {
+    node: Node<G>,
+    pin: PinnedPage<'a, D>,
+}
+
+type ReadNode<'a, D> = Latched<'a, D, RwLockReadGuard<'a, Page>>;
+type WriteNode<'a, D> = Latched<'a, D, RwLockWriteGuard<'a, Page>>;
+
+/// A tree node in a page: the entries, each a key followed by a record id
+/// in leaves and by a child page in inner nodes, are kept sorted right
+/// after the node header. Modifying a node marks its page dirty.
+struct Node<P> {
+    page: P,
+    key_size: usize,
+}
+
+impl<P: Deref<Target = Page>> Node<P> {
+    fn new(page: P, key_size: usize) -> std::io::Result<Self> {
+        match page.get_page_type() {
+            Some(PageType::BTreeNode) => Ok(Self { page, key_size }),
+            page_type => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("expected a B+ tree node, found {:?}", page_type),
+            )),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.u32_at(NODE_LEN_OFFSET) as usize
+    }
+
+    fn level(&self) -> u32 {
+        self.u32_at(NODE_LEVEL_OFFSET)
+    }
+
+    fn is_leaf(&self) -> bool {
+        self.level() == 0
+    }
+
+    fn link(&self) -> PageNo {
+        PageNo::from_le_bytes(self.page.get_data()[NODE_LINK_OFFSET..][..8].try_into().unwrap())
+    }
+
+    fn entry_size(&self) -> usize {
+        self.key_size + if self.is_leaf() { RECORD_ID_SIZE } else { CHILD_SIZE }
+    }
+
+    fn capacity(&self) -> usize {
+        (self.page.get_data().len() - NODE_ENTRIES_OFFSET) / self.entry_size()
+    }
+
+    /// Fewest entries a node other than the root may have.
+    fn min_len(&self) -> usize {
+        self.capacity() / 2
+    }
+
+    fn entries(&self) -> &[u8] {
+        &self.page.get_data()[NODE_ENTRIES_OFFSET..][..self.len() * self.entry_size()]
+    }
+
+    fn entry(&self, i: usize) -> &[u8] {
+        let entry_size = self.entry_size();
+        &self.entries()[i * entry_size..][..entry_size]
+    }
+
+    fn key(&self, i: usize) -> &[u8] {
+        &self.entry(i)[..self.key_size]
+    }
+
+    fn record_id(&self, i: usize) -> RecordId {
+        RecordId::decode(&self.entry(i)[self.key_size..])
+    }
+
+    /// Child `i` of an inner node, from 0 to `len()`.
+    fn child(&self, i: usize) -> PageNo {
+        match i {
+            0 => self.link(),
+            _ => PageNo::from_le_bytes(self.entry(i - 1)[self.key_size..].try_into().unwrap()),
+        }
+    }
+
+    /// Index of the entry with `key`, or of where it would go.
+    fn search<C: KeyComparator>(&self, key: &[u8], comparator: &C) -> Result<usize, usize> {
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = (low + high) / 2;
+            match comparator.compare(self.key(mid), key) {
+                Ordering::Less => low = mid + 1,
+                Ordering::Greater => high = mid,
+                Ordering::Equal => return Ok(mid),
+            }
+        }
+        Err(low)
+    }
+
+    /// Index of the child of an inner node that covers `key`.
+    fn child_index<C: KeyComparator>(&self, key: &[u8], comparator: &C) -> usize {
+        match self.search(key, comparator) {
+            Ok(i) => i + 1,
+            Err(i) => i,
+        }
+    }
+
+    fn u32_at(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.page.get_data()[offset..][..4].try_into().unwrap())
+    }
+}
+
+impl<P: DerefMut<Target = Page>> Node<P> {
+    fn init(mut page: P, key_size: usize, level: u32) -> Self {
+        page.init(PageType::BTreeNode);
+        page.set_dirty(true);
+        page.get_data_mut()[NODE_LEVEL_OFFSET..][..4].copy_from_slice(&level.to_le_bytes());
+        Self { page, key_size }
+    }
+
+    fn set_len(&mut self, len: usize) {
+        self.page.set_dirty(true);
+        self.page.get_data_mut()[NODE_LEN_OFFSET..][..4].copy_from_slice(&(len as u32).to_le_bytes());
+    }
+
+    fn set_link(&mut self, link: PageNo) {
+        self.page.set_dirty(true);
+        self.page.get_data_mut()[NODE_LINK_OFFSET..][..8].copy_from_slice(&link.to_le_bytes());
+    }
+
+    fn set_key(&mut self, i: usize, key: &[u8]) {
+        let at = NODE_ENTRIES_OFFSET + i * self.entry_size();
+        self.page.set_dirty(true);
+        self.page.get_data_mut()[at..at + self.key_size].copy_from_slice(key);
+    }
+
+    /// Inserts `entry` at index `i`; the node must have room for it.
+    fn insert(&mut self, i: usize, entry: &[u8]) {
+        let (len, entry_size) = (self.len(), self.entry_size());
+        let at = NODE_ENTRIES_OFFSET + i * entry_size;
+        let end = NODE_ENTRIES_OFFSET + len * entry_size;
+        let data = self.page.get_data_mut();
+        data.copy_within(at..end, at + entry_size);
+        data[at..at + entry_size].copy_from_slice(entry);
+        self.set_len(len + 1);
+    }
+
+    fn remove(&mut self, i: usize) {
+        let (len, entry_size) = (self.len(), self.entry_size());
+        let at = NODE_ENTRIES_OFFSET + i * entry_size;
+        let end = NODE_ENTRIES_OFFSET + len * entry_size;
+        self.page.get_data_mut().copy_within(at + entry_size..end, at);
+        self.set_len(len - 1);
+    }
+
+    /// Replaces all entries with those in `entries`.
+    fn set_entries(&mut self, entries: &[u8]) {
+        let len = entries.len() / self.entry_size();
+        self.page.get_data_mut()[NODE_ENTRIES_OFFSET..][..entries.len()].copy_from_slice(entries);
+        self.set_len(len);
+    }
+}
+
+/// Entries of a `BPlusTree` in key order, see `BPlusTree::range`. Reads a
+/// leaf at a time and holds no latch or pin between calls.
+pub struct BTreeRange<'a, D: DiskManager, C: KeyComparator> {
+    tree: &'a BPlusTree<D, C>,
+    /// Entries of the last leaf read, not yet returned.
+    buffer: VecDeque<(Vec<u8>, RecordId)>,
+    /// Lower bound of the entries not read yet; moves past every leaf read.
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    is_done: bool,
+}
+
+impl<D: DiskManager, C: KeyComparator> BTreeRange<'_, D, C> {
+    /// Reads the entries in range from the next leaf that has any. Leaves
+    /// are followed by their links, latching the next before releasing the
+    /// current one. That goes against the order in which deletes latch
+    /// siblings, so if the next leaf is taken the scan backs off and
+    /// descends again from the root.
+    fn fill(&mut self) -> std::io::Result<()> {
+        let tree = self.tree;
+        'descend: loop {
+            let key = match &self.start {
+                Bound::Included(key) | Bound::Excluded(key) => Some(&key[..]),
+                Bound::Unbounded => None,
+            };
+            let mut leaf = tree.find_leaf(key)?;
+            loop {
+                self.read_leaf(&leaf.node);
+                if !self.buffer.is_empty() || self.is_done {
+                    return Ok(());
+                }
+                let next = leaf.node.link();
+                if next == NO_PAGE {
+                    self.is_done = true;
+                    return Ok(());
+                }
+                let pin = tree.fix(next)?;
+                let page = match tree.bmgr.try_get_page_shared(pin.frame_id) {
+                    Some(page) => page,
+                    None => {
+                        drop((pin, leaf));
+                        thread::yield_now();
+                        continue 'descend;
+                    }
+                };
+                leaf = Latched {
+                    node: Node::new(page, tree.key_size)?,
+                    pin,
+                };
+            }
+        }
+    }
+
+    /// Buffers the entries of `leaf` within the range and after `start`,
+    /// and moves `start` past them.
+    fn read_leaf<P: Deref<Target = Page>>(&mut self, leaf: &Node<P>) {
+        let comparator = &self.tree.comparator;
+        let first = match &self.start {
+            Bound::Included(key) => leaf.search(key, comparator).unwrap_or_else(|i| i),
+            Bound::Excluded(key) => leaf.search(key, comparator).map_or_else(|i| i, |i| i + 1),
+            Bound::Unbounded => 0,
+        };
+        for i in first..leaf.len() {
+            let key = leaf.key(i);
+            let is_past_end = match &self.end {
+                Bound::Included(end) => comparator.compare(key, end) == Ordering::Greater,
+                Bound::Excluded(end) => comparator.compare(key, end) != Ordering::Less,
+                Bound::Unbounded => false,
+            };
+            if is_past_end {
+                self.is_done = true;
+                break;
+            }
+            self.buffer.push_back((key.to_vec(), leaf.record_id(i)));
+        }
+        if let Some((key, _)) = self.buffer.back() {
+            self.start = Bound::Excluded(key.clone());
+        }
+    }
+}
+
+impl<D: DiskManager, C: KeyComparator> Iterator for BTreeRange<'_, D, C> {
+    type Item = std::io::Result<(Vec<u8>, RecordId)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buffer.is_empty() && !self.is_done {
+            if let Err(e) = self.fill() {
+                self.is_done = true;
+                return Some(Err(e));
+            }
+        }
+        self.buffer.pop_front().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer_pool_manager::ReplacePolicyType;
+    use crate::data_storage_manager::DSMgr;
+    use crate::memory_disk_manager::MemDiskManager;
+    use crate::page_cleaner::{PageCleaner, PageCleanerConfig};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::collections::BTreeSet;
+    use std::thread;
+    use std::time::Duration;
+
+    fn open(path: &str) -> BPlusTree<DSMgr> {
+        let bmgr = BufferPoolManager::with_disk_manager(DSMgr::open_file(path).unwrap(), ReplacePolicyType::LRU, 16);
+        BPlusTree::open(Arc::new(bmgr), 0, 8, BytewiseComparator).unwrap()
+    }
+
+    fn rid(k: u64) -> RecordId {
+        RecordId::new(PageId::new(1, k), (k % 100) as u16)
+    }
+
+    #[test]
+    fn flushed_tree_survives_a_restart() {
+        let path = std::env::temp_dir().join(format!("adbs-btree-{}.dbf", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+
+        // Enough keys to split leaves many times over, most deleted again
+        // to merge them back.
+        let tree = open(path);
+        let keys: Vec<u64> = (0..5_000u64).map(|i| i * 7919 % 5_000).collect();
+        for &k in &keys {
+            tree.insert(&k.to_be_bytes(), rid(k)).unwrap();
+        }
+        for &k in keys.iter().filter(|&&k| k % 5 != 0) {
+            tree.delete(&k.to_be_bytes()).unwrap();
+        }
+        tree.flush().unwrap();
+        drop(tree);
+
+        let tree = open(path);
+        let found: Vec<(Vec<u8>, RecordId)> = tree.scan().collect::<std::io::Result<_>>().unwrap();
+        let expected: Vec<(Vec<u8>, RecordId)> = (0..5_000u64)
+            .step_by(5)
+            .map(|k| (k.to_be_bytes().to_vec(), rid(k)))
+            .collect();
+        assert_eq!(found, expected);
+        assert_eq!(tree.get(&3u64.to_be_bytes()).unwrap(), None);
+        assert_eq!(tree.get(&5u64.to_be_bytes()).unwrap(), Some(rid(5)));
+        let _ = std::fs::remove_file(path);
+    }
+
+    const THREADS: u64 = 8;
+    const KEY_SIZE: usize = 64;
+
+    fn wide_key(k: u64) -> Vec<u8> {
+        let mut key = vec![0; KEY_SIZE];
+        key[..8].copy_from_slice(&k.to_be_bytes());
+        key
+    }
+
+    fn wide_tree() -> Arc<BPlusTree<MemDiskManager>> {
+        let bmgr = BufferPoolManager::with_disk_manager(MemDiskManager::new(), ReplacePolicyType::Clock, 64);
+        Arc::new(BPlusTree::open(Arc::new(bmgr), 0, KEY_SIZE, BytewiseComparator).unwrap())
+    }
+
+    /// A scaled-down `btree_stress`: each thread owns the keys equal to its
+    /// number modulo the thread count and checks every answer about them.
+    /// Large keys make small nodes, so nodes split and merge all the time,
+    /// freed while other threads still pass through them.
+    fn run_concurrent_workload(tree: &Arc<BPlusTree<MemDiskManager>>) {
+        let threads: Vec<_> = (0..THREADS)
+            .map(|id| {
+                let tree = Arc::clone(tree);
+                thread::spawn(move || {
+                    let mut rng = StdRng::seed_from_u64(id);
+                    let mut stored = BTreeSet::new();
+                    for _ in 0..10_000 {
+                        let k = rng.gen_range(0..300) * THREADS + id;
+                        match rng.gen_range(0..10) {
+                            0..=4 => match tree.insert(&wide_key(k), rid(k)) {
+                                Ok(()) => assert!(stored.insert(k)),
+                                Err(e) => assert_eq!(e.kind(), ErrorKind::AlreadyExists, "insert {}: {}", k, e),
+                            },
+                            5..=8 => match tree.delete(&wide_key(k)) {
+                                Ok(()) => assert!(stored.remove(&k)),
+                                Err(e) => assert_eq!(e.kind(), ErrorKind::NotFound, "delete {}: {}", k, e),
+                            },
+                            _ => assert_eq!(tree.get(&wide_key(k)).unwrap(), stored.contains(&k).then(|| rid(k))),
+                        }
+                    }
+                    stored
+                })
+            })
+            .collect();
+        let mut expected = BTreeSet::new();
+        for handle in threads {
+            expected.extend(handle.join().unwrap());
+        }
+
+        let found: Vec<Vec<u8>> =
+            tree.scan().map(|entry| entry.map(|(key, _)| key)).collect::<std::io::Result<_>>().unwrap();
+        assert_eq!(found, expected.iter().map(|&k| wide_key(k)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn concurrent_splits_and_merges_keep_every_key() {
+        run_concurrent_workload(&wide_tree());
+    }
+
+    /// The cleaner latches frames it found unpinned, which threads coupling
+    /// latches may have pinned and latched meanwhile.
+    #[test]
+    fn page_cleaner_runs_beside_concurrent_splits_and_merges() {
+        let tree = wide_tree();
+        let mut cleaner = PageCleaner::start(
+            Arc::clone(&tree.bmgr),
+            PageCleanerConfig {
+                interval: Duration::ZERO,
+                batch_size: 64,
+                min_dirty_ratio: 0.0,
+                max_dirty_ratio: 0.0,
+            },
+        );
+        run_concurrent_workload(&tree);
+        cleaner.stop().unwrap();
+        assert!(tree.bmgr.get_cleaner_write_num() > 0);
+    }
+}
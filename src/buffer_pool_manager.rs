@@ -1,137 +1,1114 @@
-use crate::define::{PageId, FrameId, PAGE_SIZE};
-use crate::page::Page;
+use crate::define::{FileId, FrameId, PageId, PageNo};
+use crate::page::{Page, PageData};
 use crate::replacer::Replacer;
 use crate::lru_replacer::LRUReplacer;
 use crate::clock_replacer::ClockReplacer;
 use crate::data_storage_manager::DSMgr;
-use std::collections::HashMap;
-use std::sync::{Mutex, Arc};
-use std::sync::atomic::{AtomicI32, Ordering};
+use crate::disk_manager::DiskManager;
+use crate::async_io::IoPool;
+use crate::read_ahead::{ReadAheadConfig, SequentialDetector};
+use crate::tablespace::Tablespace;
+use crate::wal::{LogManager, Lsn, INVALID_LSN};
+use crate::page::{page_lsn, stamp_checksum, verify_checksum};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::mpsc::Sender;
+use std::sync::{Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError, Arc, OnceLock};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+/// Number of I/O threads serving the async API.
+const ASYNC_IO_THREADS: usize = 4;
 
 pub enum ReplacePolicyType {
     LRU,
     Clock,
 }
 
+/// Completion latch shared by every thread interested in a page whose frame
+/// is currently being written back and/or loaded.
+struct IoLatch {
+    state: Mutex<LatchState>,
+    cond: Condvar,
+}
+
+struct LatchState {
+    done: bool,
+    wakers: Vec<Waker>,
+}
 
-pub struct BufferPoolManager {
-    disk_manager: Arc<DSMgr>,
+impl IoLatch {
+    fn new() -> Self {
+        Self {
+            state: Mutex::new(LatchState {
+                done: false,
+                wakers: Vec::new(),
+            }),
+            cond: Condvar::new(),
+        }
+    }
+
+    fn wait(&self) {
+        let mut state = self.state.lock().unwrap();
+        while !state.done {
+            state = self.cond.wait(state).unwrap();
+        }
+    }
+
+    /// Async counterpart of `wait`.
+    fn wait_async(self: Arc<Self>) -> LatchWait {
+        LatchWait { latch: self }
+    }
+
+    fn complete(&self) {
+        let wakers = {
+            let mut state = self.state.lock().unwrap();
+            state.done = true;
+            std::mem::take(&mut state.wakers)
+        };
+        self.cond.notify_all();
+        wakers.into_iter().for_each(Waker::wake);
+    }
+}
+
+struct LatchWait {
+    latch: Arc<IoLatch>,
+}
+
+impl Future for LatchWait {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.latch.state.lock().unwrap();
+        if state.done {
+            Poll::Ready(())
+        } else {
+            state.wakers.push(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+/// What a caller of `begin_fetch` has to do next.
+enum FetchStep {
+    /// Finished; holds the pinned frame, if any.
+    Done(Option<FrameId>),
+    /// Someone else is moving the page; wait and start over.
+    Wait(Arc<IoLatch>),
+    /// The caller owns the miss and must run `load_frame`, then complete the
+    /// latch.
+    Load {
+        frame_id: FrameId,
+        victim: Option<PageId>,
+        latch: Arc<IoLatch>,
+    },
+}
+
+enum PageTableEntry {
+    /// The page is loaded in the given frame.
+    Resident(FrameId),
+    /// The page is being moved in or out of a frame; wait and look again.
+    InFlight(Arc<IoLatch>),
+}
+
+type PageTableGuard<'a> = MutexGuard<'a, HashMap<PageId, PageTableEntry>>;
+
+/// The pin an async fetch holds on the page it loads on an I/O thread. If
+/// the fetch is dropped before it takes the pin over, e.g. because its task
+/// was cancelled, the pin is released with this.
+struct LoadPin<D: DiskManager> {
+    bmgr: Arc<BufferPoolManager<D>>,
+    page_id: PageId,
+    frame_id: Option<FrameId>,
+}
+
+impl<D: DiskManager> LoadPin<D> {
+    fn take(mut self) -> FrameId {
+        self.frame_id.take().expect("a load pin is taken once")
+    }
+}
+
+impl<D: DiskManager> Drop for LoadPin<D> {
+    fn drop(&mut self) {
+        if self.frame_id.is_some() {
+            self.bmgr.unfix_page(self.page_id);
+        }
+    }
+}
+
+/// Buffer pool over a tablespace of data files, each behind a `DiskManager`;
+/// by default the file-backed `DSMgr`.
+pub struct BufferPoolManager<D: DiskManager = DSMgr> {
+    tablespace: Tablespace<D>,
     frame_num: usize,
-    pages: Vec<Mutex<Page>>,
+    page_size: usize,
+    /// Each frame's page behind its latch, shared for readers and exclusive
+    /// for writers. The pool itself never waits for the latch of a pinned
+    /// frame while it holds the page table, so a thread may latch a page and
+    /// fix another, as latch coupling does.
+    pages: Vec<RwLock<Page>>,
+    /// Pins of each frame, kept outside the latch so that pinning and
+    /// unpinning never wait for a latch holder.
+    pin_counts: Vec<AtomicI32>,
+    /// Held by whoever is writing a frame's contents back to disk, so a
+    /// background write and an eviction of the same frame never overlap.
+    /// Only ever tried, not waited for, with the page table locked, and
+    /// never held while waiting for the latch of another frame.
+    write_back_latches: Vec<Mutex<()>>,
     free_list: Mutex<Vec<FrameId>>,
-    page_table: Mutex<HashMap<PageId, FrameId>>,
+    page_table: Mutex<HashMap<PageId, PageTableEntry>>,
+    /// Pages deallocated while pinned, each freed by the `unfix_page` that
+    /// releases its last pin. Locked after the page table.
+    pending_frees: Mutex<HashSet<PageId>>,
     num_io: AtomicI32,
     num_hits: AtomicI32,
+    num_clean_evictions: AtomicI32,
+    num_dirty_evictions: AtomicI32,
+    num_cleaner_writes: AtomicI32,
+    num_prefetches: AtomicI32,
     replacer: Arc<dyn Replacer>,
+    read_ahead: Mutex<SequentialDetector>,
+    /// Whether `read_ahead` is enabled, so that fixes do not lock it while
+    /// read-ahead is off, as it is by default.
+    is_read_ahead_enabled: AtomicBool,
+    read_ahead_worker: Mutex<Option<Sender<Vec<PageId>>>>,
+    io_pool: OnceLock<IoPool>,
+    /// Write-ahead log that has to be flushed past a page's `page_lsn`
+    /// before the page is written.
+    log_manager: OnceLock<Arc<LogManager>>,
 }
 
-impl BufferPoolManager {
+impl BufferPoolManager<DSMgr> {
+    /// Opens `filename` as file 0 of a new pool, with its write-ahead log in
+    /// `<filename>.wal`. If the database was not shut down cleanly it is
+    /// recovered from the log before this returns. A database of several
+    /// files has to be opened with `open` instead.
     pub fn new(filename: &str, policy: ReplacePolicyType, frame_num: usize) -> std::io::Result<Self> {
-        let disk_manager = Arc::new(DSMgr::open_file(filename)?);
-        
+        Self::open(&[filename], policy, frame_num)
+    }
+
+    /// Opens the data files of a database as files 0, 1, ... of a new pool,
+    /// with the write-ahead log in `<first file>.wal`, and recovers them
+    /// from the log, which may refer to any of them. The files must be
+    /// given in the same order every time.
+    pub fn open(filenames: &[&str], policy: ReplacePolicyType, frame_num: usize) -> std::io::Result<Self> {
+        let (first, rest) = filenames
+            .split_first()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "a database needs a data file"))?;
+        let bmgr = Self::with_disk_manager(DSMgr::open_file(first)?, policy, frame_num);
+        for filename in rest {
+            bmgr.open_file(filename)?;
+        }
+        let log_manager = LogManager::open(&format!("{}.wal", first))?;
+        bmgr.set_log_manager(Arc::new(log_manager))?;
+        bmgr.recover()?;
+        Ok(bmgr)
+    }
+
+    /// Opens another data file and adds it to the pool. Files holding
+    /// logged pages must be added before recovery, see `open`.
+    pub fn open_file(&self, filename: &str) -> std::io::Result<FileId> {
+        self.add_file(DSMgr::open_file(filename)?)
+    }
+}
+
+impl<D: DiskManager> BufferPoolManager<D> {
+    /// Builds a pool on top of an already opened disk manager, e.g. a
+    /// `DSMgr` using io_uring or a `MemDiskManager`. It becomes file 0; all
+    /// files added later must have the same page size.
+    pub fn with_disk_manager(disk_manager: D, policy: ReplacePolicyType, frame_num: usize) -> Self {
+        let page_size = disk_manager.page_size();
+        let tablespace = Tablespace::new(page_size);
+        tablespace
+            .add_file(disk_manager)
+            .expect("the first file defines the tablespace page size");
+
         let mut pages = Vec::with_capacity(frame_num);
         for _ in 0..frame_num {
-            pages.push(Mutex::new(Page::new()));
+            pages.push(RwLock::new(Page::new(page_size)));
         }
-        
-        let free_list = (0..frame_num as FrameId).collect();
-        
+
+        let free_list = (0..frame_num).collect();
+
         let replacer: Arc<dyn Replacer> = match policy {
             ReplacePolicyType::LRU => Arc::new(LRUReplacer::new(frame_num)),
             ReplacePolicyType::Clock => Arc::new(ClockReplacer::new(frame_num)),
         };
 
-        Ok(Self {
-            disk_manager,
+        Self {
+            tablespace,
             frame_num,
+            page_size,
             pages,
+            pin_counts: (0..frame_num).map(|_| AtomicI32::new(0)).collect(),
+            write_back_latches: (0..frame_num).map(|_| Mutex::new(())).collect(),
             free_list: Mutex::new(free_list),
             page_table: Mutex::new(HashMap::new()),
+            pending_frees: Mutex::new(HashSet::new()),
             num_io: AtomicI32::new(0),
             num_hits: AtomicI32::new(0),
+            num_clean_evictions: AtomicI32::new(0),
+            num_dirty_evictions: AtomicI32::new(0),
+            num_cleaner_writes: AtomicI32::new(0),
+            num_prefetches: AtomicI32::new(0),
             replacer,
+            read_ahead: Mutex::new(SequentialDetector::new(ReadAheadConfig {
+                trigger: 0,
+                window: 0,
+            })),
+            is_read_ahead_enabled: AtomicBool::new(false),
+            read_ahead_worker: Mutex::new(None),
+            io_pool: OnceLock::new(),
+            log_manager: OnceLock::new(),
+        }
+    }
+
+    /// Attaches a write-ahead log. From then on a dirty page is only written
+    /// back once the log is durable up to its `page_lsn`. Fails if the pool
+    /// already has a log.
+    pub fn set_log_manager(&self, log_manager: Arc<LogManager>) -> std::io::Result<()> {
+        self.log_manager.set(log_manager).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::AlreadyExists, "the pool already has a log")
         })
     }
 
+    pub fn log_manager(&self) -> Option<&Arc<LogManager>> {
+        self.log_manager.get()
+    }
+
+    pub(crate) fn require_log(&self) -> std::io::Result<&Arc<LogManager>> {
+        self.log_manager
+            .get()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::Unsupported, "the pool has no log"))
+    }
+
+    /// Pins `page_id` in a frame, reading it from disk on a miss.
+    ///
+    /// Concurrent misses on the same page coalesce: the first thread installs
+    /// an in-flight entry and performs the I/O, the others wait on it and then
+    /// retry the lookup. Every successful call must be paired with
+    /// `unfix_page`.
     pub fn fix_page(&self, page_id: PageId, is_dirty: bool) -> std::io::Result<FrameId> {
-        
-        {
-            let page_table = self.page_table.lock().unwrap();
-            if let Some(&frame_id) = page_table.get(&page_id) {
-                self.num_hits.fetch_add(1, Ordering::SeqCst);
-                
-                {
-                    let mut page = self.pages[frame_id as usize].lock().unwrap();
-                    if is_dirty {
-                        page.set_dirty(true);
-                    }
+        let frame_id = self
+            .fetch_page(page_id, is_dirty, true)?
+            .expect("a pinning fetch always yields a frame");
+        if is_dirty {
+            self.mark_dirty(frame_id);
+        }
+
+        if let Some(page_ids) = self.detect_read_ahead(page_id) {
+            match self.read_ahead_worker.lock().unwrap().as_ref() {
+                Some(worker) => {
+                    let _ = worker.send(page_ids);
+                }
+                None => {
+                    // Read-ahead is best effort; a full pool simply loads less.
+                    let _ = self.prefetch(&page_ids);
                 }
-                
-                return Ok(frame_id);
             }
         }
 
-        
-        
-        let frame_id = {
-            let mut free_list = self.free_list.lock().unwrap();
-            if let Some(frame_id) = free_list.pop() {
-                frame_id
-            } else {
-                
-                
-                drop(free_list);
-                match self.replacer.victim() {
-                    Some(victim) => victim,
-                    None => return Err(std::io::Error::new(std::io::ErrorKind::Other, "No available frame")),
+        Ok(frame_id)
+    }
+
+    /// Async variant of `fix_page`. A miss hands the eviction and read to the
+    /// pool's I/O threads and yields; waiting on another task's in-flight
+    /// load yields as well. Works with any executor.
+    pub async fn fix_page_async(self: &Arc<Self>, page_id: PageId, is_dirty: bool) -> std::io::Result<FrameId> {
+        let frame_id = loop {
+            let step = self.begin_fetch(page_id, true)?;
+            match step {
+                FetchStep::Done(frame_id) => {
+                    break frame_id.expect("a pinning fetch always yields a frame");
+                }
+                FetchStep::Wait(latch) => latch.wait_async().await,
+                FetchStep::Load { frame_id, victim, latch } => {
+                    let bmgr = Arc::clone(self);
+                    let pin = self
+                        .io_pool()
+                        .submit(move || {
+                            let result = bmgr.load_frame(frame_id, victim, page_id, is_dirty);
+                            latch.complete();
+                            result.map(|_| LoadPin {
+                                bmgr,
+                                page_id,
+                                frame_id: Some(frame_id),
+                            })
+                        })
+                        .await?;
+                    break pin.take();
                 }
             }
         };
+        if is_dirty {
+            self.mark_dirty(frame_id);
+        }
 
-        
-        {
-            let mut page = self.pages[frame_id as usize].lock().unwrap();
-            let old_page_id = page.get_page_id();
-            if old_page_id != -1 {
-                if page.is_dirty() {
-                    self.disk_manager.write_page(old_page_id, page.get_data())?;
-                }
-                
-                {
-                    let mut page_table = self.page_table.lock().unwrap();
-                    page_table.remove(&old_page_id);
+        if let Some(page_ids) = self.detect_read_ahead(page_id) {
+            match self.read_ahead_worker.lock().unwrap().as_ref() {
+                Some(worker) => {
+                    let _ = worker.send(page_ids);
+                }
+                None => {
+                    let bmgr = Arc::clone(self);
+                    // Not awaited: the read-ahead runs on its own.
+                    drop(self.io_pool().submit(move || bmgr.prefetch(&page_ids)));
+                }
+            }
+        }
+
+        Ok(frame_id)
+    }
+
+    /// Async variant of `fix_new_page`.
+    pub async fn fix_new_page_async(self: &Arc<Self>, file_id: FileId) -> std::io::Result<(PageId, FrameId)> {
+        let bmgr = Arc::clone(self);
+        let page_id = self.io_pool().submit(move || bmgr.tablespace.allocate(file_id)).await?;
+        let frame_id = self.fix_page_async(page_id, false).await?;
+        Ok((page_id, frame_id))
+    }
+
+    /// The I/O threads backing the async API, started on first use.
+    fn io_pool(&self) -> &IoPool {
+        self.io_pool.get_or_init(|| IoPool::new(ASYNC_IO_THREADS))
+    }
+
+    fn detect_read_ahead(&self, page_id: PageId) -> Option<Vec<PageId>> {
+        if !self.is_read_ahead_enabled.load(Ordering::SeqCst) {
+            return None;
+        }
+        let num_pages = self.tablespace.num_pages(page_id.file_id)?;
+        self.read_ahead.lock().unwrap().on_access(page_id, num_pages)
+    }
+
+    /// Loads the given pages into frames without pinning them, skipping pages
+    /// that are already resident, being loaded, or past the end of their
+    /// file. Returns how many pages were read.
+    ///
+    /// Frames for all missing pages are claimed up front and the pages are
+    /// read with one batched `DiskManager::read_pages` call per file.
+    pub fn prefetch(&self, page_ids: &[PageId]) -> std::io::Result<usize> {
+        let mut num_pages: HashMap<FileId, Option<PageNo>> = HashMap::new();
+        let mut first_error = None;
+
+        let mut loads = Vec::new();
+        for &page_id in page_ids {
+            let file_pages = *num_pages
+                .entry(page_id.file_id)
+                .or_insert_with(|| self.tablespace.num_pages(page_id.file_id));
+            if file_pages.is_none_or(|file_pages| page_id.page_no >= file_pages) {
+                continue;
+            }
+            match self.begin_fetch(page_id, false) {
+                Ok(FetchStep::Load { frame_id, victim, latch }) => {
+                    loads.push((page_id, frame_id, victim, latch));
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    first_error = Some(e);
+                    break;
                 }
             }
+        }
+
+        loads.retain(|(page_id, frame_id, victim, latch)| {
+            match self.evict_victim(*frame_id, *victim, *page_id) {
+                Ok(()) => true,
+                Err(e) => {
+                    latch.complete();
+                    first_error.get_or_insert(e);
+                    false
+                }
+            }
+        });
+
+        let mut buffers: Vec<PageData> = loads.iter().map(|_| PageData::zeroed(self.page_size)).collect();
+        let mut reads: Vec<(PageId, &mut [u8])> = loads
+            .iter()
+            .zip(buffers.iter_mut())
+            .map(|((page_id, ..), data)| (*page_id, &mut data[..]))
+            .collect();
+        let results = self.tablespace.read_pages(&mut reads);
+
+        let mut loaded = 0;
+        for (((page_id, frame_id, victim, latch), data), result) in loads.into_iter().zip(&buffers).zip(results) {
+            match self.finish_load(frame_id, victim, page_id, false, result.map(|_| &**data)) {
+                Ok(()) => {
+                    // The page is published by now, so others may already have
+                    // pinned it; the load's pin goes like any other.
+                    self.unfix_page(page_id);
+                    self.num_prefetches.fetch_add(1, Ordering::SeqCst);
+                    loaded += 1;
+                }
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+            latch.complete();
+        }
+
+        match first_error {
+            Some(e) if loaded == 0 => Err(e),
+            _ => Ok(loaded),
+        }
+    }
+
+    /// Enables or reconfigures automatic sequential read-ahead in `fix_page`.
+    pub fn set_read_ahead(&self, config: ReadAheadConfig) {
+        let mut read_ahead = self.read_ahead.lock().unwrap();
+        *read_ahead = SequentialDetector::new(config);
+        self.is_read_ahead_enabled.store(read_ahead.is_enabled(), Ordering::SeqCst);
+    }
+
+    pub(crate) fn set_read_ahead_worker(&self, worker: Option<Sender<Vec<PageId>>>) {
+        *self.read_ahead_worker.lock().unwrap() = worker;
+    }
+
+    /// Returns the frame holding `page_id`, pinned, loading it on a miss.
+    /// With `pin == false` (prefetching) a page that is already resident or
+    /// in flight is left alone and `None` is returned; a freshly loaded page
+    /// is still returned pinned once so the caller can release it.
+    fn fetch_page(&self, page_id: PageId, is_dirty: bool, pin: bool) -> std::io::Result<Option<FrameId>> {
+        loop {
+            match self.begin_fetch(page_id, pin)? {
+                FetchStep::Done(frame_id) => return Ok(frame_id),
+                FetchStep::Wait(latch) => latch.wait(),
+                FetchStep::Load { frame_id, victim, latch } => {
+                    let result = self.load_frame(frame_id, victim, page_id, is_dirty);
+                    latch.complete();
+                    return result.map(|_| Some(frame_id));
+                }
+            }
+        }
+    }
+
+    /// The non-blocking part of a fetch: looks `page_id` up and, on a miss,
+    /// claims a frame and marks both the new page and the victim in flight.
+    fn begin_fetch(&self, page_id: PageId, pin: bool) -> std::io::Result<FetchStep> {
+        let mut page_table = self.page_table.lock().unwrap();
+        match page_table.get(&page_id) {
+            Some(_) if !pin => return Ok(FetchStep::Done(None)),
+            Some(PageTableEntry::Resident(frame_id)) => {
+                let frame_id = *frame_id;
+                self.num_hits.fetch_add(1, Ordering::SeqCst);
+                self.pin_frame(frame_id);
+                return Ok(FetchStep::Done(Some(frame_id)));
+            }
+            Some(PageTableEntry::InFlight(latch)) => {
+                return Ok(FetchStep::Wait(Arc::clone(latch)));
+            }
+            None => {}
+        }
+
+        let (frame_id, victim) = self.acquire_frame()?;
+        let latch = Arc::new(IoLatch::new());
+        if let Some(old_page_id) = victim {
+            page_table.insert(old_page_id, PageTableEntry::InFlight(Arc::clone(&latch)));
+        }
+        page_table.insert(page_id, PageTableEntry::InFlight(Arc::clone(&latch)));
+        Ok(FetchStep::Load { frame_id, victim, latch })
+    }
+
+    /// Picks a frame for a new page, either from the free list or by asking
+    /// the replacer for a victim, and pins it for the caller. Also returns the
+    /// page the frame currently holds. Must be called with the page table
+    /// locked.
+    fn acquire_frame(&self) -> std::io::Result<(FrameId, Option<PageId>)> {
+        let frame_id = match self.free_list.lock().unwrap().pop() {
+            Some(frame_id) => frame_id,
+            None => match self.replacer.victim() {
+                Some(victim) => victim,
+                None => return Err(std::io::Error::other("No available frame")),
+            },
+        };
+        self.pin_counts[frame_id].fetch_add(1, Ordering::SeqCst);
+        let old_page_id = self.pages[frame_id].read().unwrap().get_page_id();
+        Ok((frame_id, old_page_id))
+    }
+
+    /// Writes back the victim (if dirty) and reads `page_id` into `frame_id`,
+    /// then publishes the result in the page table. On failure the page table
+    /// is restored so that no entry points at a half-loaded frame.
+    ///
+    /// Both pages are marked in flight, so nobody else touches the frame
+    /// until this returns. The frame latch is only held to copy data in and
+    /// out; the disk I/O itself runs without any lock held.
+    fn load_frame(
+        &self,
+        frame_id: FrameId,
+        victim: Option<PageId>,
+        page_id: PageId,
+        is_dirty: bool,
+    ) -> std::io::Result<()> {
+        self.evict_victim(frame_id, victim, page_id)?;
+        let mut data = PageData::zeroed(self.page_size);
+        let read = self.tablespace.read_page(page_id, &mut data);
+        self.finish_load(frame_id, victim, page_id, is_dirty, read.map(|_| &*data))
+    }
 
-            
-            let mut data = [0u8; PAGE_SIZE];
-            self.disk_manager.read_page(page_id, &mut data)?;
+    /// First half of `load_frame`: writes the victim back if it is dirty. If
+    /// that fails the victim stays resident and dirty and the frame is handed
+    /// back to the replacer.
+    fn evict_victim(&self, frame_id: FrameId, victim: Option<PageId>, page_id: PageId) -> std::io::Result<()> {
+        let old_page_id = match victim {
+            Some(old_page_id) => old_page_id,
+            None => return Ok(()),
+        };
+
+        // Wait out a background write of the victim still in progress.
+        let _write_back = self.write_back_latches[frame_id].lock().unwrap();
+        let dirty_data = {
+            let page = self.pages[frame_id].read().unwrap();
+            if page.is_dirty() {
+                Some(PageData::from(page.get_data()))
+            } else {
+                None
+            }
+        };
+        let mut data = match dirty_data {
+            Some(data) => data,
+            None => {
+                self.num_clean_evictions.fetch_add(1, Ordering::SeqCst);
+                return Ok(());
+            }
+        };
+
+        self.num_dirty_evictions.fetch_add(1, Ordering::SeqCst);
+        if let Err(e) = self.write_back(old_page_id, &mut data) {
+            let mut page_table = self.page_table.lock().unwrap();
+            page_table.insert(old_page_id, PageTableEntry::Resident(frame_id));
+            page_table.remove(&page_id);
+            self.pin_counts[frame_id].fetch_sub(1, Ordering::SeqCst);
+            self.replacer.insert(frame_id);
+            return Err(e);
+        }
+        let mut page = self.pages[frame_id].write().unwrap();
+        page.set_dirty(false);
+        page.set_rec_lsn(INVALID_LSN);
+        Ok(())
+    }
+
+    /// Second half of `load_frame`: installs the data read for `page_id`, or
+    /// on a failed read, or a page failing its checksum, releases the frame
+    /// to the free list.
+    fn finish_load(
+        &self,
+        frame_id: FrameId,
+        victim: Option<PageId>,
+        page_id: PageId,
+        is_dirty: bool,
+        read: std::io::Result<&[u8]>,
+    ) -> std::io::Result<()> {
+        let read = read.and_then(|data| {
+            if verify_checksum(data) {
+                Ok(data)
+            } else {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("page {} failed its checksum", page_id),
+                ))
+            }
+        });
+        let data = match read {
+            Ok(data) => data,
+            Err(e) => {
+                *self.pages[frame_id].write().unwrap() = Page::new(self.page_size);
+                self.pin_counts[frame_id].store(0, Ordering::SeqCst);
+                let mut page_table = self.page_table.lock().unwrap();
+                if let Some(old_page_id) = victim {
+                    page_table.remove(&old_page_id);
+                }
+                page_table.remove(&page_id);
+                self.free_list.lock().unwrap().push(frame_id);
+                return Err(e);
+            }
+        };
+        {
+            let mut page = self.pages[frame_id].write().unwrap();
             page.set_page_id(page_id);
             page.set_dirty(is_dirty);
-            page.set_data(&data);
+            page.set_rec_lsn(INVALID_LSN);
+            page.set_data(data);
+        }
+        self.num_io.fetch_add(1, Ordering::SeqCst);
 
-            
-            self.num_io.fetch_add(1, Ordering::SeqCst);
+        let mut page_table = self.page_table.lock().unwrap();
+        if let Some(old_page_id) = victim {
+            page_table.remove(&old_page_id);
         }
+        page_table.insert(page_id, PageTableEntry::Resident(frame_id));
+        Ok(())
+    }
 
-        
-        {
-            let mut page_table = self.page_table.lock().unwrap();
-            page_table.insert(page_id, frame_id);
+    /// Pins a resident frame. Must be called with the page table locked.
+    fn pin_frame(&self, frame_id: FrameId) {
+        if self.pin_counts[frame_id].fetch_add(1, Ordering::SeqCst) == 0 {
+            self.replacer.remove(frame_id);
         }
+    }
 
-        Ok(frame_id)
+    /// Marks the page in a frame the caller has pinned dirty. Done after
+    /// the fetch rather than while pinning, as the page table must not wait
+    /// for a frame latch.
+    fn mark_dirty(&self, frame_id: FrameId) {
+        self.pages[frame_id].write().unwrap().set_dirty(true);
+    }
+
+    /// Makes `image`, logged at `lsn`, the contents of `page_id` and marks
+    /// the page dirty. A miss does not read the copy on disk, which a crash
+    /// may have torn. Used by redo.
+    pub(crate) fn restore_page(&self, page_id: PageId, image: &[u8], lsn: Lsn) -> std::io::Result<()> {
+        if image.len() != self.page_size {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("image of page {} is {} bytes, not {}", page_id, image.len(), self.page_size),
+            ));
+        }
+        // Fails like a read would for a file the pool lacks.
+        self.tablespace.file(page_id.file_id)?;
+        let frame_id = loop {
+            match self.begin_fetch(page_id, true)? {
+                FetchStep::Done(frame_id) => break frame_id.expect("a pinning fetch always yields a frame"),
+                FetchStep::Wait(latch) => latch.wait(),
+                FetchStep::Load { frame_id, victim, latch } => {
+                    let mut data = PageData::from(image);
+                    stamp_checksum(&mut data);
+                    let result = self
+                        .evict_victim(frame_id, victim, page_id)
+                        .and_then(|()| self.finish_load(frame_id, victim, page_id, true, Ok(&data)));
+                    latch.complete();
+                    result?;
+                    break frame_id;
+                }
+            }
+        };
+        {
+            let mut page = self.get_page(frame_id);
+            page.set_data(image);
+            page.set_dirty(true);
+            page.set_page_lsn(lsn);
+        }
+        self.unfix_page(page_id);
+        Ok(())
     }
 
-    pub fn fix_new_page(&self, page_id: &mut PageId) -> std::io::Result<FrameId> {
-        let new_page_id = self.disk_manager.new_page()?;
+    /// Allocates a page in `file_id` and pins it.
+    pub fn fix_new_page(&self, file_id: FileId, page_id: &mut PageId) -> std::io::Result<FrameId> {
+        let new_page_id = self.tablespace.allocate(file_id)?;
         *page_id = new_page_id;
         self.fix_page(new_page_id, false)
     }
 
-    pub fn unfix_page(&self, _page_id: PageId) {
-        
-        
+    /// Frees `page_id` in its file for a later allocation. Its frame, if it
+    /// has one, is discarded without being written back, so a reallocation
+    /// never sees the old contents. A pinned page is freed once its last pin
+    /// is released; the caller must make sure nobody pins it anew. Fails like
+    /// `Tablespace::deallocate` for a page that is not allocated, and with
+    /// `InvalidInput` for one already waiting to be freed.
+    pub fn deallocate_page(&self, page_id: PageId) -> std::io::Result<()> {
+        if self.pending_frees.lock().unwrap().contains(&page_id) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("page {} is already being freed", page_id),
+            ));
+        }
+        self.release_page(page_id)
+    }
+
+    /// Frees `page_id` now if it is not pinned, and otherwise leaves it to
+    /// the `unfix_page` releasing its last pin.
+    fn release_page(&self, page_id: PageId) -> std::io::Result<()> {
+        // A pinned page may be latched by a thread waiting for a latch the
+        // caller holds, and whoever holds its write-back latch may be
+        // waiting for the page latch. As nobody pins the page anew, one
+        // that is unpinned here stays unlatched while the write-back latch
+        // is waited for.
+        if let Some(PageTableEntry::Resident(frame_id)) = self.page_table.lock().unwrap().get(&page_id) {
+            if self.pin_counts[*frame_id].load(Ordering::SeqCst) > 0 {
+                self.pending_frees.lock().unwrap().insert(page_id);
+                return Ok(());
+            }
+        }
+
+        // A background write of the page, which takes no page table lock,
+        // would otherwise land after the page is reused.
+        if let Some((frame_id, _write_back, mut page_table)) = self.lock_write_back(page_id) {
+            if self.pin_counts[frame_id].load(Ordering::SeqCst) > 0 {
+                self.pending_frees.lock().unwrap().insert(page_id);
+                return Ok(());
+            }
+            page_table.remove(&page_id);
+            *self.pages[frame_id].write().unwrap() = Page::new(self.page_size);
+            self.replacer.remove(frame_id);
+            self.free_list.lock().unwrap().push(frame_id);
+        }
+        self.pending_frees.lock().unwrap().remove(&page_id);
+        self.tablespace.deallocate(page_id)
+    }
+
+    /// The frame `page_id` is resident in, once a load or eviction of it in
+    /// progress is done; `None` if it is not resident.
+    fn wait_resident(&self, page_id: PageId) -> Option<FrameId> {
+        loop {
+            let page_table = self.page_table.lock().unwrap();
+            match page_table.get(&page_id) {
+                Some(PageTableEntry::Resident(frame_id)) => return Some(*frame_id),
+                Some(PageTableEntry::InFlight(latch)) => {
+                    let latch = Arc::clone(latch);
+                    drop(page_table);
+                    latch.wait();
+                }
+                None => return None,
+            }
+        }
+    }
+
+    /// Takes the write-back latch of the frame holding `page_id`, which
+    /// keeps the page from being written back or evicted by anyone else,
+    /// and then the page table. The latch is waited for without the page
+    /// table held, as its holder may be waiting for a page latch or for
+    /// I/O. `None` if the page is not resident.
+    fn lock_write_back(&self, page_id: PageId) -> Option<(FrameId, MutexGuard<'_, ()>, PageTableGuard<'_>)> {
+        loop {
+            let frame_id = self.wait_resident(page_id)?;
+            let write_back = self.write_back_latches[frame_id].lock().unwrap();
+            let page_table = self.page_table.lock().unwrap();
+            // The frame may have been given to another page meanwhile.
+            if matches!(page_table.get(&page_id), Some(PageTableEntry::Resident(f)) if *f == frame_id) {
+                return Some((frame_id, write_back, page_table));
+            }
+        }
+    }
+
+    /// Releases one pin on `page_id`. Once the pin count drops to zero the
+    /// frame becomes a candidate for replacement, or the page is freed if it
+    /// was deallocated meanwhile.
+    pub fn unfix_page(&self, page_id: PageId) {
+        let page_table = self.page_table.lock().unwrap();
+        if let Some(PageTableEntry::Resident(frame_id)) = page_table.get(&page_id) {
+            if self.unpin_frame(*frame_id) && self.pending_frees.lock().unwrap().contains(&page_id) {
+                drop(page_table);
+                // Nobody is left to report a failure to; the page then
+                // merely stays allocated.
+                let _ = self.release_page(page_id);
+            }
+        }
+    }
+
+    /// Releases one pin on a resident frame and returns whether it was the
+    /// last. Must be called with the page table locked, so it cannot
+    /// interleave with `pin_frame`.
+    fn unpin_frame(&self, frame_id: FrameId) -> bool {
+        let pin_count = &self.pin_counts[frame_id];
+        if pin_count.load(Ordering::SeqCst) > 0 && pin_count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.replacer.insert(frame_id);
+            return true;
+        }
+        false
+    }
+
+    /// Adds a data file to the pool. Its page size must match the pool's.
+    pub fn add_file(&self, disk_manager: D) -> std::io::Result<FileId> {
+        self.tablespace.add_file(disk_manager)
+    }
+
+    /// Removes a data file from the pool and hands back its disk manager.
+    /// Its pages are discarded from the pool without being written back.
+    /// Fails with `ResourceBusy` while any of them is pinned or has I/O in
+    /// progress.
+    pub fn drop_file(&self, file_id: FileId) -> std::io::Result<Arc<D>> {
+        let mut page_table = self.page_table.lock().unwrap();
+        let busy = |page_id: &PageId| {
+            std::io::Error::new(
+                std::io::ErrorKind::ResourceBusy,
+                format!("page {} of file {} is in use", page_id, file_id),
+            )
+        };
+
+        let mut frames = Vec::new();
+        let mut write_backs = Vec::new();
+        for (page_id, entry) in page_table.iter().filter(|(page_id, _)| page_id.file_id == file_id) {
+            let frame_id = match entry {
+                PageTableEntry::Resident(frame_id) => *frame_id,
+                PageTableEntry::InFlight(_) => return Err(busy(page_id)),
+            };
+            // A background write of the page may still be running.
+            match self.write_back_latches[frame_id].try_lock() {
+                Ok(guard) => write_backs.push(guard),
+                Err(_) => return Err(busy(page_id)),
+            }
+            if self.pin_counts[frame_id].load(Ordering::SeqCst) > 0 {
+                return Err(busy(page_id));
+            }
+            frames.push((*page_id, frame_id));
+        }
+
+        let disk_manager = self.tablespace.drop_file(file_id)?;
+        for (page_id, frame_id) in frames {
+            page_table.remove(&page_id);
+            *self.pages[frame_id].write().unwrap() = Page::new(self.page_size);
+            self.replacer.remove(frame_id);
+            self.free_list.lock().unwrap().push(frame_id);
+        }
+        Ok(disk_manager)
+    }
+
+    /// Writes back up to `max_writes` dirty, unpinned frames among the next
+    /// `scan_depth` victims of the replacer, so that later evictions find
+    /// clean frames. Returns how many pages were written.
+    ///
+    /// The pages are written in page order with one `write_pages` call per
+    /// file, so adjacent pages go to disk together. A page whose write fails
+    /// stays dirty and the first such error is returned. Each page stays
+    /// resident and usable while it is being written. Frames that are pinned
+    /// or latched at the time are skipped.
+    pub fn clean_pages(&self, scan_depth: usize, max_writes: usize) -> std::io::Result<usize> {
+        let mut copies = Vec::new();
+        let mut write_backs = HashMap::new();
+        for frame_id in self.replacer.peek_victims(scan_depth) {
+            if copies.len() == max_writes {
+                break;
+            }
+
+            // The frame may have been pinned since it was peeked at, and its
+            // latch taken by a thread about to fix another page. The pin
+            // count cannot change under the page table, and the latch is
+            // only tried, as no frame latch is waited for under the page
+            // table.
+            let _page_table = self.page_table.lock().unwrap();
+            if self.pin_counts[frame_id].load(Ordering::SeqCst) > 0 {
+                continue;
+            }
+            let write_back = match self.write_back_latches[frame_id].try_lock() {
+                Ok(guard) => guard,
+                Err(_) => continue,
+            };
+            let mut page = match self.pages[frame_id].try_write() {
+                Ok(page) => page,
+                Err(TryLockError::WouldBlock) => continue,
+                Err(TryLockError::Poisoned(e)) => panic!("{}", e),
+            };
+            let page_id = match page.get_page_id() {
+                Some(page_id) if page.is_dirty() => page_id,
+                _ => continue,
+            };
+            // Clear the dirty bit before the copy is written; a concurrent
+            // fix_page that dirties the page again simply sets it back.
+            page.set_dirty(false);
+            copies.push((frame_id, page_id, PageData::from(page.get_data())));
+            write_backs.insert(frame_id, write_back);
+        }
+
+        copies.sort_by_key(|(_, page_id, _)| *page_id);
+        let results = self.write_back_copies(&mut copies);
+
+        // The frames may have been pinned and latched since they were
+        // copied, by threads that go on to evict another frame of the batch
+        // and wait for its write-back latch. So no frame latch is waited for
+        // while the batch holds other write-back latches; each latch is
+        // released as soon as its frame is updated.
+        let mut unfinished: Vec<_> = copies
+            .iter()
+            .zip(&results)
+            .map(|((frame_id, ..), result)| (*frame_id, result.is_ok(), write_backs.remove(frame_id)))
+            .collect();
+        while !unfinished.is_empty() {
+            unfinished.retain(|(frame_id, is_written, _)| match self.pages[*frame_id].try_write() {
+                Ok(mut page) => {
+                    Self::finish_write_back(&mut page, *is_written);
+                    false
+                }
+                Err(TryLockError::WouldBlock) => true,
+                Err(TryLockError::Poisoned(e)) => panic!("{}", e),
+            });
+            if !unfinished.is_empty() {
+                thread::yield_now();
+            }
+        }
+        let written = results.iter().filter(|result| result.is_ok()).count();
+        self.num_cleaner_writes.fetch_add(written as i32, Ordering::SeqCst);
+        match results.into_iter().find_map(Result::err) {
+            Some(e) => Err(e),
+            None => Ok(written),
+        }
+    }
+
+    /// Writes `page_id` back if it is resident and dirty, pinned or not, and
+    /// returns whether it was. The page stays resident. Its file is not
+    /// synced; `flush_all` does that.
+    pub fn flush_page(&self, page_id: PageId) -> std::io::Result<bool> {
+        // Waits out a background write, which may have started before the
+        // latest changes. The page table is released before latching the
+        // frame, whose holder may be fixing other pages.
+        let (frame_id, write_back, page_table) = match self.lock_write_back(page_id) {
+            Some(locked) => locked,
+            None => return Ok(false),
+        };
+        drop(page_table);
+        let mut data = {
+            let mut page = self.pages[frame_id].write().unwrap();
+            if !page.is_dirty() {
+                return Ok(false);
+            }
+            page.set_dirty(false);
+            PageData::from(page.get_data())
+        };
+        let result = self.write_back_copy(frame_id, page_id, &mut data);
+        drop(write_back);
+        result.map(|_| true)
+    }
+
+    /// Writes back every dirty page and syncs all files, so everything
+    /// changed so far survives a crash. Pages dirtied meanwhile may or may
+    /// not be included. Returns how many pages were written.
+    pub fn flush_all(&self) -> std::io::Result<usize> {
+        let written = self.flush_resident(|_| true)?;
+        self.tablespace.sync()?;
+        Ok(written)
+    }
+
+    /// Like `flush_all`, for the pages of `file_id` only.
+    pub fn flush_file(&self, file_id: FileId) -> std::io::Result<usize> {
+        let written = self.flush_resident(|page_id| page_id.file_id == file_id)?;
+        self.tablespace.file(file_id)?.sync()?;
+        Ok(written)
+    }
+
+    /// Writes back the dirty resident pages `filter` picks.
+    fn flush_resident(&self, filter: impl Fn(&PageId) -> bool) -> std::io::Result<usize> {
+        let page_ids: Vec<PageId> = self.page_table.lock().unwrap().keys().copied().filter(filter).collect();
+        let mut written = 0;
+        for page_id in page_ids {
+            if self.flush_page(page_id)? {
+                written += 1;
+            }
+        }
+        Ok(written)
+    }
+
+    /// Shuts the pool down cleanly: writes back every dirty page and, with a
+    /// log, takes a checkpoint, which leaves recovery nothing to redo and
+    /// drops the log segments no longer needed. The pool should not be used
+    /// afterwards. A pool dropped without `close` is left as by a crash and
+    /// recovered the next time it is opened.
+    pub fn close(&self) -> std::io::Result<()> {
+        self.flush_all()?;
+        if self.log_manager.get().is_some() {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    /// Writes a copy of the page in `frame_id`, taken with its dirty bit
+    /// cleared, while the caller holds the frame's write-back latch. On
+    /// failure the page is marked dirty again.
+    fn write_back_copy(&self, frame_id: FrameId, page_id: PageId, data: &mut [u8]) -> std::io::Result<()> {
+        let result = self.write_back(page_id, data);
+        Self::finish_write_back(&mut self.pages[frame_id].write().unwrap(), result.is_ok());
+        result
+    }
+
+    /// Like `write_back_copy` for a batch of copies, with the log flushed
+    /// once and one `write_pages` call per file, except that the frames are
+    /// left for the caller to update with `finish_write_back`. Results are
+    /// returned per copy, in order.
+    fn write_back_copies(&self, copies: &mut [(FrameId, PageId, PageData)]) -> Vec<std::io::Result<()>> {
+        let lsn = copies.iter().map(|(_, _, data)| page_lsn(data)).max();
+        if let (Some(log_manager), Some(lsn)) = (self.log_manager.get(), lsn) {
+            if let Err(e) = log_manager.flush(lsn) {
+                return copies.iter().map(|_| Err(std::io::Error::new(e.kind(), e.to_string()))).collect();
+            }
+        }
+        for (_, _, data) in copies.iter_mut() {
+            stamp_checksum(data);
+        }
+        let writes: Vec<(PageId, &[u8])> = copies.iter().map(|(_, page_id, data)| (*page_id, &data[..])).collect();
+        self.tablespace.write_pages(&writes)
+    }
+
+    /// Updates a page after a copy of it was written: a failed write leaves
+    /// the page dirty, a successful one clean unless it was changed
+    /// meanwhile.
+    fn finish_write_back(page: &mut Page, is_written: bool) {
+        if !is_written {
+            page.set_dirty(true);
+        } else if !page.is_dirty() {
+            page.set_rec_lsn(INVALID_LSN);
+        }
+        // Otherwise it changed again meanwhile and the old rec_lsn is still
+        // a safe bound.
+    }
+
+    /// Writes a copy of a dirty page to its file with a fresh checksum,
+    /// after flushing the log up to the copy's `page_lsn` if the pool has a
+    /// log.
+    fn write_back(&self, page_id: PageId, data: &mut [u8]) -> std::io::Result<()> {
+        if let Some(log_manager) = self.log_manager.get() {
+            log_manager.flush(page_lsn(data))?;
+        }
+        stamp_checksum(data);
+        self.tablespace.write_page(page_id, data)
+    }
+
+    /// The page in `frame_id`, which the caller must have pinned with
+    /// `fix_page`, latched exclusively. Other pages may be fixed while the
+    /// latch is held, but it must be released before the page's own
+    /// `unfix_page`.
+    ///
+    /// A caller modifying the page is expected to have fixed it dirty and,
+    /// with a log attached, to log the change and stamp the page with the
+    /// record's LSN before dropping the guard.
+    pub fn get_page(&self, frame_id: FrameId) -> RwLockWriteGuard<'_, Page> {
+        self.pages[frame_id].write().unwrap()
+    }
+
+    /// Like `get_page`, but latched shared, so any number of readers can
+    /// hold the page at once.
+    pub fn get_page_shared(&self, frame_id: FrameId) -> RwLockReadGuard<'_, Page> {
+        self.pages[frame_id].read().unwrap()
+    }
+
+    /// Like `get_page_shared`, but `None` instead of waiting while a writer
+    /// holds the page. Lets a thread latching pages out of the usual order,
+    /// e.g. moving right along the leaves of an index, back off instead of
+    /// deadlocking.
+    pub fn try_get_page_shared(&self, frame_id: FrameId) -> Option<RwLockReadGuard<'_, Page>> {
+        match self.pages[frame_id].try_read() {
+            Ok(page) => Some(page),
+            Err(TryLockError::WouldBlock) => None,
+            Err(TryLockError::Poisoned(e)) => panic!("{}", e),
+        }
+    }
+
+    /// The dirty page table: every resident page with logged changes that
+    /// may be missing from disk, with the LSN of the oldest one. Frames are
+    /// latched one at a time, so fetches carry on while it is collected.
+    pub fn dirty_page_table(&self) -> Vec<(PageId, Lsn)> {
+        let mut dirty_pages: Vec<(PageId, Lsn)> = self
+            .pages
+            .iter()
+            .filter_map(|page| {
+                let page = page.read().unwrap();
+                match page.get_page_id() {
+                    Some(page_id) if page.get_rec_lsn() != INVALID_LSN => Some((page_id, page.get_rec_lsn())),
+                    _ => None,
+                }
+            })
+            .collect();
+        dirty_pages.sort_unstable();
+        dirty_pages
+    }
+
+    /// Fraction of frames holding a dirty page.
+    pub fn get_dirty_ratio(&self) -> f64 {
+        let dirty = self
+            .pages
+            .iter()
+            .filter(|page| page.read().unwrap().is_dirty())
+            .count();
+        dirty as f64 / self.frame_num as f64
     }
 
     pub fn get_io_num(&self) -> i32 {
@@ -142,16 +1119,368 @@ impl BufferPoolManager {
         self.num_hits.load(Ordering::SeqCst)
     }
 
-    pub fn get_num_pages(&self) -> PageId {
-        self.disk_manager.get_num_pages()
+    pub fn get_clean_eviction_num(&self) -> i32 {
+        self.num_clean_evictions.load(Ordering::SeqCst)
+    }
+
+    pub fn get_dirty_eviction_num(&self) -> i32 {
+        self.num_dirty_evictions.load(Ordering::SeqCst)
+    }
+
+    pub fn get_prefetch_num(&self) -> i32 {
+        self.num_prefetches.load(Ordering::SeqCst)
+    }
+
+    pub fn get_cleaner_write_num(&self) -> i32 {
+        self.num_cleaner_writes.load(Ordering::SeqCst)
+    }
+
+    /// Number of pages in `file_id`, or `None` if the pool has no such file.
+    pub fn get_num_pages(&self, file_id: FileId) -> Option<PageNo> {
+        self.tablespace.num_pages(file_id)
+    }
+
+    pub fn tablespace(&self) -> &Tablespace<D> {
+        &self.tablespace
+    }
+
+    pub fn get_frame_num(&self) -> usize {
+        self.frame_num
+    }
+
+    pub fn get_page_size(&self) -> usize {
+        self.page_size
     }
 
     pub fn print_page_table(&self) {
         let page_table = self.page_table.lock().unwrap();
-        println!("Page Table: {:?}", *page_table);
+        let mut resident: Vec<(PageId, FrameId)> = page_table
+            .iter()
+            .filter_map(|(&page_id, entry)| match entry {
+                PageTableEntry::Resident(frame_id) => Some((page_id, *frame_id)),
+                PageTableEntry::InFlight(_) => None,
+            })
+            .collect();
+        resident.sort_unstable();
+        println!("Page Table: {:?}", resident);
     }
 
     pub fn print_replacer(&self) {
         self.replacer.print();
     }
 }
+
+/// A page pinned in the pool, unpinned when dropped, which must not happen
+/// while its latch is held. Lets index code hold pins and latches as plain
+/// values that unwind on every return path.
+pub(crate) struct PinnedPage<'a, D: DiskManager> {
+    bmgr: &'a BufferPoolManager<D>,
+    pub(crate) page_id: PageId,
+    pub(crate) frame_id: FrameId,
+}
+
+impl<'a, D: DiskManager> PinnedPage<'a, D> {
+    pub(crate) fn fix(bmgr: &'a BufferPoolManager<D>, page_id: PageId) -> std::io::Result<Self> {
+        let frame_id = bmgr.fix_page(page_id, false)?;
+        Ok(Self { bmgr, page_id, frame_id })
+    }
+
+    /// Allocates a page in `file_id` and pins it.
+    pub(crate) fn fix_new(bmgr: &'a BufferPoolManager<D>, file_id: FileId) -> std::io::Result<Self> {
+        let mut page_id = PageId::new(file_id, 0);
+        let frame_id = bmgr.fix_new_page(file_id, &mut page_id)?;
+        Ok(Self { bmgr, page_id, frame_id })
+    }
+
+    pub(crate) fn read(&self) -> RwLockReadGuard<'a, Page> {
+        self.bmgr.get_page_shared(self.frame_id)
+    }
+
+    pub(crate) fn write(&self) -> RwLockWriteGuard<'a, Page> {
+        self.bmgr.get_page(self.frame_id)
+    }
+}
+
+impl<D: DiskManager> Drop for PinnedPage<'_, D> {
+    fn drop(&mut self) {
+        self.bmgr.unfix_page(self.page_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extent::EXTENT_SIZE;
+    use crate::fault_injection::{FaultConfig, FaultRule, FaultyDiskManager};
+    use crate::memory_disk_manager::MemDiskManager;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    type FaultyPool = BufferPoolManager<FaultyDiskManager<MemDiskManager>>;
+
+    fn faulty_pool(num_pages: usize, frame_num: usize) -> Arc<FaultyPool> {
+        let disk_manager = FaultyDiskManager::new(MemDiskManager::with_pages(num_pages), FaultConfig::default(), 0);
+        Arc::new(BufferPoolManager::with_disk_manager(disk_manager, ReplacePolicyType::LRU, frame_num))
+    }
+
+    fn set_faults(bmgr: &FaultyPool, config: FaultConfig) {
+        bmgr.tablespace().file(0).unwrap().set_config(config);
+    }
+
+    /// Pins of `page_id` if it is resident.
+    fn pin_count(bmgr: &FaultyPool, page_id: PageId) -> Option<i32> {
+        match bmgr.page_table.lock().unwrap().get(&page_id) {
+            Some(PageTableEntry::Resident(frame_id)) => Some(bmgr.pin_counts[*frame_id].load(Ordering::SeqCst)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn cancelled_async_fix_releases_its_pin() {
+        let bmgr = faulty_pool(2, 1);
+        let page_id = PageId::new(0, 0);
+        set_faults(
+            &bmgr,
+            FaultConfig {
+                latency: FaultRule::on_pages([0]),
+                delay: Duration::from_millis(100),
+                ..FaultConfig::default()
+            },
+        );
+
+        // Start the load on an I/O thread, then give up on it while the read
+        // is still sleeping.
+        let mut fix = Box::pin(bmgr.fix_page_async(page_id, false));
+        assert!(fix.as_mut().poll(&mut Context::from_waker(Waker::noop())).is_pending());
+        drop(fix);
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while pin_count(&bmgr, page_id) != Some(0) {
+            assert!(Instant::now() < deadline, "the abandoned load kept its pin");
+            thread::sleep(Duration::from_millis(1));
+        }
+        // The only frame can be reused.
+        let other = PageId::new(0, 1);
+        bmgr.fix_page(other, false).unwrap();
+        bmgr.unfix_page(other);
+    }
+
+    /// Fixes `page_id` dirty and writes `byte` into it.
+    fn write_byte(bmgr: &FaultyPool, page_id: PageId, byte: u8) {
+        let frame_id = bmgr.fix_page(page_id, true).unwrap();
+        bmgr.get_page(frame_id).get_data_mut()[crate::page::PAGE_HEADER_SIZE] = byte;
+        bmgr.unfix_page(page_id);
+    }
+
+    fn read_byte(bmgr: &FaultyPool, page_id: PageId) -> u8 {
+        let frame_id = bmgr.fix_page(page_id, false).unwrap();
+        let byte = bmgr.get_page_shared(frame_id).get_data()[crate::page::PAGE_HEADER_SIZE];
+        bmgr.unfix_page(page_id);
+        byte
+    }
+
+    #[test]
+    fn failed_eviction_write_keeps_the_victim_resident_and_dirty() {
+        let bmgr = faulty_pool(2, 1);
+        let (victim, page_id) = (PageId::new(0, 0), PageId::new(0, 1));
+        write_byte(&bmgr, victim, 42);
+        set_faults(
+            &bmgr,
+            FaultConfig {
+                write_error: FaultRule::on_pages([0]),
+                ..FaultConfig::default()
+            },
+        );
+
+        assert!(bmgr.fix_page(page_id, false).is_err());
+        assert_eq!(pin_count(&bmgr, victim), Some(0));
+        assert!(!bmgr.page_table.lock().unwrap().contains_key(&page_id));
+        assert!(bmgr.pages[0].read().unwrap().is_dirty());
+        assert_eq!(bmgr.replacer.size(), 1);
+
+        // Still the victim's frame, so reading it is a hit with the change.
+        let num_hits = bmgr.get_hit_num();
+        assert_eq!(read_byte(&bmgr, victim), 42);
+        assert_eq!(bmgr.get_hit_num(), num_hits + 1);
+
+        // Once the disk recovers, the eviction goes through and the change
+        // reaches it.
+        set_faults(&bmgr, FaultConfig::default());
+        bmgr.fix_page(page_id, false).unwrap();
+        bmgr.unfix_page(page_id);
+        assert_eq!(pin_count(&bmgr, victim), None);
+        assert_eq!(read_byte(&bmgr, victim), 42);
+    }
+
+    #[test]
+    fn failed_prefetch_eviction_keeps_the_victim_resident_and_dirty() {
+        let bmgr = faulty_pool(3, 1);
+        let victim = PageId::new(0, 0);
+        write_byte(&bmgr, victim, 7);
+        set_faults(
+            &bmgr,
+            FaultConfig {
+                write_error: FaultRule::always(),
+                ..FaultConfig::default()
+            },
+        );
+
+        assert!(bmgr.prefetch(&[PageId::new(0, 1), PageId::new(0, 2)]).is_err());
+        assert_eq!(pin_count(&bmgr, victim), Some(0));
+        assert_eq!(bmgr.page_table.lock().unwrap().len(), 1);
+        assert!(bmgr.pages[0].read().unwrap().is_dirty());
+        assert_eq!(bmgr.replacer.size(), 1);
+        assert_eq!(read_byte(&bmgr, victim), 7);
+    }
+
+    #[test]
+    fn failed_read_returns_the_frame() {
+        let bmgr = faulty_pool(2, 1);
+        let page_id = PageId::new(0, 1);
+        set_faults(
+            &bmgr,
+            FaultConfig {
+                read_error: FaultRule::on_pages([1]),
+                ..FaultConfig::default()
+            },
+        );
+
+        assert!(bmgr.fix_page(page_id, false).is_err());
+        assert!(bmgr.page_table.lock().unwrap().is_empty());
+        assert_eq!(bmgr.free_list.lock().unwrap().len(), 1);
+        assert_eq!(bmgr.replacer.size(), 0);
+
+        // A page failing its checksum is a failed read too, and the clean
+        // victim it was to replace is gone all the same.
+        set_faults(&bmgr, FaultConfig::default());
+        write_byte(&bmgr, PageId::new(0, 0), 1);
+        bmgr.fix_page(page_id, false).unwrap();
+        bmgr.unfix_page(page_id);
+        set_faults(
+            &bmgr,
+            FaultConfig {
+                bit_flip: FaultRule::on_pages([0]),
+                ..FaultConfig::default()
+            },
+        );
+        let error = bmgr.fix_page(PageId::new(0, 0), false).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert!(bmgr.page_table.lock().unwrap().is_empty());
+        assert_eq!(bmgr.free_list.lock().unwrap().len(), 1);
+        assert_eq!(bmgr.replacer.size(), 0);
+    }
+
+    #[test]
+    fn concurrent_misses_on_one_page_share_a_read_and_a_frame() {
+        const THREADS: usize = 8;
+        let bmgr = faulty_pool(1, THREADS);
+        let page_id = PageId::new(0, 0);
+        // Long enough for every thread to miss while the first read sleeps.
+        set_faults(
+            &bmgr,
+            FaultConfig {
+                latency: FaultRule::on_pages([0]),
+                delay: Duration::from_millis(200),
+                ..FaultConfig::default()
+            },
+        );
+
+        let barrier = Arc::new(std::sync::Barrier::new(THREADS));
+        let frame_ids: Vec<FrameId> = (0..THREADS)
+            .map(|_| {
+                let (bmgr, barrier) = (Arc::clone(&bmgr), Arc::clone(&barrier));
+                thread::spawn(move || {
+                    barrier.wait();
+                    bmgr.fix_page(page_id, false).unwrap()
+                })
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect();
+
+        assert_eq!(bmgr.get_io_num(), 1);
+        assert!(frame_ids.iter().all(|&frame_id| frame_id == frame_ids[0]));
+        assert_eq!(bmgr.page_table.lock().unwrap().len(), 1);
+        assert_eq!(bmgr.free_list.lock().unwrap().len(), THREADS - 1);
+        assert_eq!(pin_count(&bmgr, page_id), Some(THREADS as i32));
+        for _ in 0..THREADS {
+            bmgr.unfix_page(page_id);
+        }
+        assert_eq!(pin_count(&bmgr, page_id), Some(0));
+    }
+
+    fn is_dirty(bmgr: &FaultyPool, page_id: PageId) -> bool {
+        match bmgr.page_table.lock().unwrap().get(&page_id) {
+            Some(PageTableEntry::Resident(frame_id)) => bmgr.pages[*frame_id].read().unwrap().is_dirty(),
+            _ => panic!("page {} is not resident", page_id),
+        }
+    }
+
+    #[test]
+    fn cleaned_pages_are_written_in_one_batch_and_only_failed_ones_stay_dirty() {
+        let bmgr = faulty_pool(8, 8);
+        for page_no in [3, 0, 2, 1] {
+            write_byte(&bmgr, PageId::new(0, page_no), page_no as u8 + 1);
+        }
+        set_faults(
+            &bmgr,
+            FaultConfig {
+                write_error: FaultRule::on_pages([2]),
+                ..FaultConfig::default()
+            },
+        );
+
+        assert!(bmgr.clean_pages(8, 8).is_err());
+        let file = bmgr.tablespace().file(0).unwrap();
+        assert_eq!(file.get_write_batch_num(), 1);
+        assert_eq!(bmgr.get_cleaner_write_num(), 3);
+        for page_no in 0..4 {
+            let page_id = PageId::new(0, page_no);
+            assert_eq!(is_dirty(&bmgr, page_id), page_no == 2, "page {}", page_id);
+            let mut data = PageData::zeroed(bmgr.get_page_size());
+            file.inner().read_page(page_no, &mut data).unwrap();
+            let expected = if page_no == 2 { 0 } else { page_no as u8 + 1 };
+            assert_eq!(data[crate::page::PAGE_HEADER_SIZE], expected, "page {}", page_id);
+        }
+
+        // The next pass writes only the page left dirty.
+        set_faults(&bmgr, FaultConfig::default());
+        assert_eq!(bmgr.clean_pages(8, 8).unwrap(), 1);
+        assert!(!is_dirty(&bmgr, PageId::new(0, 2)));
+        assert_eq!(file.get_write_batch_num(), 2);
+    }
+
+    #[test]
+    fn deallocated_page_comes_back_zeroed_and_only_once() {
+        let bmgr = faulty_pool(0, 4);
+        let mut page_id = PageId::new(0, 0);
+        bmgr.fix_new_page(0, &mut page_id).unwrap();
+        bmgr.get_page(bmgr.fix_page(page_id, true).unwrap()).get_data_mut()[crate::page::PAGE_HEADER_SIZE] = 9;
+        bmgr.unfix_page(page_id);
+        // Still pinned, so the page is freed by the last unfix.
+        bmgr.deallocate_page(page_id).unwrap();
+        assert_eq!(pin_count(&bmgr, page_id), Some(1));
+        let error = bmgr.deallocate_page(page_id).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        bmgr.unfix_page(page_id);
+        assert_eq!(pin_count(&bmgr, page_id), None);
+        assert!(bmgr.pending_frees.lock().unwrap().is_empty());
+        assert_eq!(bmgr.free_list.lock().unwrap().len(), 4);
+        assert_eq!(bmgr.deallocate_page(page_id).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+
+        // The page comes back once the rest of its extent is used up, and
+        // the dirty frame was dropped, not written over the zeroed page.
+        let mut reused = PageId::new(0, 0);
+        for _ in 0..EXTENT_SIZE {
+            bmgr.fix_new_page(0, &mut reused).unwrap();
+            bmgr.unfix_page(reused);
+            if reused == page_id {
+                break;
+            }
+        }
+        assert_eq!(reused, page_id);
+        assert_eq!(read_byte(&bmgr, reused), 0);
+        assert_eq!(bmgr.get_dirty_eviction_num(), 0);
+    }
+}
@@ -0,0 +1,534 @@
+use crate::buffer_pool_manager::BufferPoolManager;
+use crate::checksum::crc32;
+use crate::define::PageId;
+use crate::disk_manager::DiskManager;
+use crate::wal::{sync_parent_dir, Lsn, TxnId, TxnState};
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Write};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const CHECKPOINT_MAGIC: &[u8; 8] = b"ADBSCKPT";
+
+/// Magic, u32 checksum of everything after it, u64 begin LSN, u32 number of
+/// dirty pages, u32 number of transactions.
+const CHECKPOINT_HEADER_SIZE: usize = 28;
+
+/// u32 file id, u64 page number, u64 recLSN.
+const DIRTY_PAGE_SIZE: usize = 20;
+
+/// u64 transaction id, u8 state, u64 last LSN.
+const TRANSACTION_SIZE: usize = 17;
+
+/// A fuzzy checkpoint: the dirty page table and the transaction table,
+/// collected while the pool kept running after the log had reached
+/// `begin_lsn`. Together with the log from `begin_lsn` on they are
+/// everything recovery needs to know about the time before.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub begin_lsn: Lsn,
+    /// Pages with changes that may be missing from disk, with the LSN of
+    /// the oldest; redo starts at the smallest of them.
+    pub dirty_pages: Vec<(PageId, Lsn)>,
+    /// Transactions without an end record, with their state and last LSN.
+    pub transactions: Vec<(TxnId, TxnState, Lsn)>,
+}
+
+impl Checkpoint {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(
+            CHECKPOINT_HEADER_SIZE
+                + self.dirty_pages.len() * DIRTY_PAGE_SIZE
+                + self.transactions.len() * TRANSACTION_SIZE,
+        );
+        bytes.extend_from_slice(CHECKPOINT_MAGIC);
+        bytes.extend_from_slice(&[0; 4]);
+        bytes.extend_from_slice(&self.begin_lsn.to_le_bytes());
+        bytes.extend_from_slice(&(self.dirty_pages.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&(self.transactions.len() as u32).to_le_bytes());
+        for (page_id, rec_lsn) in &self.dirty_pages {
+            bytes.extend_from_slice(&page_id.file_id.to_le_bytes());
+            bytes.extend_from_slice(&page_id.page_no.to_le_bytes());
+            bytes.extend_from_slice(&rec_lsn.to_le_bytes());
+        }
+        for (txn_id, txn_state, last_lsn) in &self.transactions {
+            bytes.extend_from_slice(&txn_id.to_le_bytes());
+            bytes.push(match txn_state {
+                TxnState::Running => 1,
+                TxnState::Committed => 2,
+                TxnState::Aborting => 3,
+            });
+            bytes.extend_from_slice(&last_lsn.to_le_bytes());
+        }
+        let checksum = crc32(&bytes[12..]);
+        bytes[8..12].copy_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < CHECKPOINT_HEADER_SIZE || !bytes.starts_with(CHECKPOINT_MAGIC) {
+            return None;
+        }
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+        if crc32(&bytes[12..]) != u32_at(8) {
+            return None;
+        }
+        let num_pages = u32_at(20) as usize;
+        let num_transactions = u32_at(24) as usize;
+        let transactions_at = CHECKPOINT_HEADER_SIZE + num_pages * DIRTY_PAGE_SIZE;
+        if bytes.len() != transactions_at + num_transactions * TRANSACTION_SIZE {
+            return None;
+        }
+        let dirty_pages = (0..num_pages)
+            .map(|i| CHECKPOINT_HEADER_SIZE + i * DIRTY_PAGE_SIZE)
+            .map(|at| (PageId::new(u32_at(at), u64_at(at + 4)), u64_at(at + 12)))
+            .collect();
+        let transactions = (0..num_transactions)
+            .map(|i| transactions_at + i * TRANSACTION_SIZE)
+            .map(|at| {
+                let txn_state = match bytes[at + 8] {
+                    1 => TxnState::Running,
+                    2 => TxnState::Committed,
+                    3 => TxnState::Aborting,
+                    _ => return None,
+                };
+                Some((u64_at(at), txn_state, u64_at(at + 9)))
+            })
+            .collect::<Option<_>>()?;
+        Some(Self {
+            begin_lsn: u64_at(12),
+            dirty_pages,
+            transactions,
+        })
+    }
+
+    /// Replaces the checkpoint in `path`. The new one is written next to it
+    /// and renamed over it, so a crash leaves one or the other.
+    pub(crate) fn write(&self, path: &str) -> std::io::Result<()> {
+        let temp_path = format!("{}.tmp", path);
+        let mut file = File::create(&temp_path)?;
+        file.write_all(&self.encode())?;
+        file.sync_all()?;
+        fs::rename(&temp_path, path)?;
+        sync_parent_dir(path)
+    }
+
+    /// Reads the checkpoint in `path`, or `None` if there is none yet.
+    pub(crate) fn read(path: &str) -> std::io::Result<Option<Self>> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        match Self::decode(&bytes) {
+            Some(checkpoint) => Ok(Some(checkpoint)),
+            None => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("corrupt checkpoint in {}", path),
+            )),
+        }
+    }
+}
+
+impl<D: DiskManager> BufferPoolManager<D> {
+    /// Takes a fuzzy checkpoint and records it next to the log, so that
+    /// recovery starts from it instead of the beginning of the log. Pages
+    /// are neither written nor blocked; each frame is latched just long
+    /// enough to read its `rec_lsn`.
+    ///
+    /// Pages already written back are left out of the dirty page table, so
+    /// the data files are synced before the checkpoint is recorded and the
+    /// log cut; otherwise a write the OS had not made durable yet could be
+    /// lost together with the records redoing it.
+    ///
+    /// Afterwards the log segments older than anything recovery could still
+    /// need, i.e. the checkpoint itself, the oldest `rec_lsn` and the first
+    /// record of the oldest active transaction, are dropped. Fails if the
+    /// pool has no log.
+    pub fn checkpoint(&self) -> std::io::Result<Checkpoint> {
+        let log_manager = self.require_log()?;
+        // Anything logged before `begin_lsn` is either covered by the tables
+        // collected below or will be seen again by analysis.
+        let begin_lsn = log_manager.get_next_lsn();
+        let oldest_active_lsn = log_manager.oldest_active_lsn();
+        let checkpoint = Checkpoint {
+            begin_lsn,
+            transactions: log_manager.active_transactions(),
+            dirty_pages: self.dirty_page_table(),
+        };
+        self.tablespace().sync()?;
+        log_manager.flush(Lsn::MAX)?;
+        checkpoint.write(&log_manager.checkpoint_path())?;
+
+        let keep_lsn = checkpoint
+            .dirty_pages
+            .iter()
+            .map(|&(_, rec_lsn)| rec_lsn)
+            .chain(oldest_active_lsn)
+            .fold(begin_lsn, Lsn::min);
+        log_manager.truncate(keep_lsn)?;
+        Ok(checkpoint)
+    }
+
+    /// The checkpoint recovery would start from, if one has been taken.
+    pub fn last_checkpoint(&self) -> std::io::Result<Option<Checkpoint>> {
+        Checkpoint::read(&self.require_log()?.checkpoint_path())
+    }
+}
+
+/// Background thread taking a checkpoint at a fixed interval, skipped while
+/// nothing has been logged since the last one. A failed checkpoint is
+/// retried at the next interval; the last error is kept and returned by
+/// `stop`. Stops when dropped.
+pub struct Checkpointer {
+    stop: Option<Sender<()>>,
+    /// Returns the last error a checkpoint failed with.
+    handle: Option<JoinHandle<Option<std::io::Error>>>,
+}
+
+impl Checkpointer {
+    pub fn start<D: DiskManager>(bmgr: Arc<BufferPoolManager<D>>, interval: Duration) -> Self {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            let (mut last_lsn, mut last_error) = (None, None);
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                if let Err(e) = checkpoint_if_logged(&bmgr, &mut last_lsn) {
+                    last_error = Some(e);
+                }
+            }
+            last_error
+        });
+
+        Self {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+
+    /// Stops the thread and returns the last error a checkpoint failed
+    /// with, even if later ones succeeded. Only the first call can fail.
+    pub fn stop(&mut self) -> std::io::Result<()> {
+        self.stop.take();
+        match self.handle.take().map(|handle| handle.join().expect("Checkpointer panicked")) {
+            Some(Some(e)) => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for Checkpointer {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+/// One round of the checkpointer: takes a checkpoint unless the log is
+/// still where it was at `last_lsn`, the end of the log at the last one.
+/// Returns whether it took one.
+fn checkpoint_if_logged<D: DiskManager>(
+    bmgr: &BufferPoolManager<D>,
+    last_lsn: &mut Option<Lsn>,
+) -> std::io::Result<bool> {
+    let next_lsn = bmgr.log_manager().map(|log_manager| log_manager.get_next_lsn());
+    if next_lsn == *last_lsn {
+        return Ok(false);
+    }
+    bmgr.checkpoint()?;
+    *last_lsn = next_lsn;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer_pool_manager::ReplacePolicyType;
+    use crate::data_storage_manager::DSMgr;
+    use crate::page::PAGE_HEADER_SIZE;
+    use crate::wal::LogManager;
+    use std::time::Instant;
+
+    /// A fresh directory for one test's database.
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("adbs-checkpoint-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    const OFFSET: usize = PAGE_HEADER_SIZE + 8;
+    const SEGMENT_SIZE: u64 = 16384;
+
+    /// Opens the database in `path` on a pool large enough that nothing is
+    /// evicted, so dirty pages stay dirty until flushed. A log segment fits
+    /// a few page images.
+    fn open(path: &str) -> BufferPoolManager<DSMgr> {
+        let bmgr = BufferPoolManager::with_disk_manager(DSMgr::open_file(path).unwrap(), ReplacePolicyType::LRU, 16);
+        let log_manager = LogManager::with_segment_size(&format!("{}.wal", path), SEGMENT_SIZE).unwrap();
+        bmgr.set_log_manager(Arc::new(log_manager)).unwrap();
+        bmgr
+    }
+
+    fn read(bmgr: &BufferPoolManager<DSMgr>, page_no: u64) -> Vec<u8> {
+        let page_id = PageId::new(0, page_no);
+        bmgr.with_page(page_id, |frame_id| {
+            Ok(bmgr.get_page(frame_id).get_data()[OFFSET..OFFSET + 4].to_vec())
+        })
+        .unwrap()
+    }
+
+    fn new_pages(bmgr: &BufferPoolManager<DSMgr>, num: usize) {
+        for _ in 0..num {
+            let mut page_id = PageId::new(0, 0);
+            bmgr.fix_new_page(0, &mut page_id).unwrap();
+            bmgr.unfix_page(page_id);
+        }
+        bmgr.flush_all().unwrap();
+    }
+
+    /// Commits `num` updates of page 2, each written back right away, so
+    /// that every one logs a full page image and the log grows by about a
+    /// segment per three of them while page 2 stays clean.
+    fn fill_log(bmgr: &BufferPoolManager<DSMgr>, num: u8) {
+        for i in 0..num {
+            let mut txn = bmgr.begin().unwrap();
+            bmgr.update(&mut txn, PageId::new(0, 2), OFFSET, &[i; 4]).unwrap();
+            bmgr.commit(txn).unwrap();
+            bmgr.flush_page(PageId::new(0, 2)).unwrap();
+        }
+    }
+
+    fn rec_lsn(bmgr: &BufferPoolManager<DSMgr>, page_no: u64) -> Lsn {
+        let page_id = PageId::new(0, page_no);
+        bmgr.dirty_page_table()
+            .into_iter()
+            .find(|&(id, _)| id == page_id)
+            .unwrap()
+            .1
+    }
+
+    fn first_segment(bmgr: &BufferPoolManager<DSMgr>) -> u64 {
+        bmgr.log_manager().unwrap().get_first_lsn() / SEGMENT_SIZE
+    }
+
+    #[test]
+    fn checkpoints_round_trip_and_damaged_ones_are_rejected() {
+        let checkpoint = Checkpoint {
+            begin_lsn: 12345,
+            dirty_pages: vec![(PageId::new(0, 7), 100), (PageId::new(3, 1 << 40), 200)],
+            transactions: vec![
+                (1, TxnState::Running, 300),
+                (2, TxnState::Committed, 400),
+                (u64::MAX, TxnState::Aborting, 500),
+            ],
+        };
+        let bytes = checkpoint.encode();
+        assert_eq!(
+            bytes.len(),
+            CHECKPOINT_HEADER_SIZE + 2 * DIRTY_PAGE_SIZE + 3 * TRANSACTION_SIZE
+        );
+        assert_eq!(Checkpoint::decode(&bytes), Some(checkpoint));
+        assert_eq!(
+            Checkpoint::decode(&Checkpoint::default().encode()),
+            Some(Checkpoint::default())
+        );
+
+        for at in 0..bytes.len() {
+            let mut damaged = bytes.clone();
+            damaged[at] ^= 0x10;
+            assert_eq!(Checkpoint::decode(&damaged), None, "byte {} flipped", at);
+        }
+        assert_eq!(Checkpoint::decode(&bytes[..bytes.len() - 1]), None);
+        assert_eq!(Checkpoint::decode(&bytes[..CHECKPOINT_HEADER_SIZE - 1]), None);
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert_eq!(Checkpoint::decode(&longer), None);
+    }
+
+    #[test]
+    fn a_corrupt_checkpoint_file_is_invalid_data() {
+        let dir = temp_dir("corrupt");
+        let path = dir.join("ckpt").to_str().unwrap().to_owned();
+        assert_eq!(Checkpoint::read(&path).unwrap(), None);
+
+        let checkpoint = Checkpoint {
+            begin_lsn: 42,
+            ..Default::default()
+        };
+        checkpoint.write(&path).unwrap();
+        assert_eq!(Checkpoint::read(&path).unwrap(), Some(checkpoint));
+        std::fs::write(&path, b"ADBSCKPT").unwrap();
+        assert_eq!(Checkpoint::read(&path).unwrap_err().kind(), ErrorKind::InvalidData);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn recovery_after_a_fuzzy_checkpoint_redoes_from_the_oldest_rec_lsn_and_undoes_the_loser() {
+        let dir = temp_dir("crash");
+        let path = dir.join("db").to_str().unwrap().to_owned();
+
+        let bmgr = open(&path);
+        new_pages(&bmgr, 4);
+        fill_log(&bmgr, 6);
+        let mut winner = bmgr.begin().unwrap();
+        bmgr.update(&mut winner, PageId::new(0, 0), OFFSET, &[1; 4]).unwrap();
+        bmgr.commit(winner).unwrap();
+        let rec_lsn_0 = rec_lsn(&bmgr, 0);
+        fill_log(&bmgr, 6);
+        let mut loser = bmgr.begin().unwrap();
+        bmgr.update(&mut loser, PageId::new(0, 1), OFFSET, &[2; 4]).unwrap();
+        let rec_lsn_1 = rec_lsn(&bmgr, 1);
+        fill_log(&bmgr, 6);
+
+        // Both pages are still dirty and the loser still running: the
+        // checkpoint records them, and the log is only cut below page 0's
+        // first change.
+        let checkpoint = bmgr.checkpoint().unwrap();
+        assert_eq!(
+            checkpoint.dirty_pages,
+            vec![(PageId::new(0, 0), rec_lsn_0), (PageId::new(0, 1), rec_lsn_1)]
+        );
+        assert_eq!(
+            checkpoint.transactions,
+            vec![(loser.id(), TxnState::Running, loser.last_lsn())]
+        );
+        assert!(rec_lsn_0 < checkpoint.begin_lsn);
+        assert!(first_segment(&bmgr) > 0);
+        assert_eq!(first_segment(&bmgr), rec_lsn_0 / SEGMENT_SIZE);
+        assert_eq!(bmgr.last_checkpoint().unwrap(), Some(checkpoint.clone()));
+
+        let mut late = bmgr.begin().unwrap();
+        bmgr.update(&mut late, PageId::new(0, 3), OFFSET, &[3; 4]).unwrap();
+        bmgr.commit(late).unwrap();
+        let log_manager = Arc::clone(bmgr.log_manager().unwrap());
+        log_manager.flush(Lsn::MAX).unwrap();
+        let analyzed_num = log_manager.iter(checkpoint.begin_lsn).unwrap().count();
+        // Pages 0, 1 and 3 were never written back.
+        drop((bmgr, log_manager));
+
+        let bmgr = open(&path);
+        let report = bmgr.recover().unwrap();
+        // Analysis starts at the checkpoint, redo before it: page 0 is only
+        // rebuilt if redo goes back to its recLSN.
+        assert_eq!(report.analyzed_num, analyzed_num);
+        assert_eq!(report.restored_num, 3);
+        assert_eq!(report.redone_num, 3);
+        assert_eq!((report.rolled_back_num, report.undone_num), (1, 1));
+        assert_eq!(read(&bmgr, 0), [1; 4]);
+        assert_eq!(read(&bmgr, 1), [0; 4]);
+        assert_eq!(read(&bmgr, 2), [5; 4]);
+        assert_eq!(read(&bmgr, 3), [3; 4]);
+        assert!(bmgr.log_manager().unwrap().active_transactions().is_empty());
+        drop(bmgr);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn checkpoints_keep_the_log_from_the_oldest_rec_lsn_active_transaction_or_checkpoint() {
+        let dir = temp_dir("truncate");
+        let path = dir.join("db").to_str().unwrap().to_owned();
+
+        let bmgr = open(&path);
+        new_pages(&bmgr, 3);
+        fill_log(&bmgr, 6);
+        let mut winner = bmgr.begin().unwrap();
+        bmgr.update(&mut winner, PageId::new(0, 0), OFFSET, &[1; 4]).unwrap();
+        bmgr.commit(winner).unwrap();
+        fill_log(&bmgr, 6);
+        let mut loser = bmgr.begin().unwrap();
+        let begin_lsn = loser.last_lsn();
+        fill_log(&bmgr, 6);
+        bmgr.update(&mut loser, PageId::new(0, 1), OFFSET, &[2; 4]).unwrap();
+        fill_log(&bmgr, 6);
+
+        // The oldest recLSN comes first.
+        let rec_lsn_0 = rec_lsn(&bmgr, 0);
+        bmgr.checkpoint().unwrap();
+        assert!(first_segment(&bmgr) > 0);
+        assert_eq!(first_segment(&bmgr), rec_lsn_0 / SEGMENT_SIZE);
+
+        // Page 0 is clean, the loser's first record is older than page 1's
+        // first change.
+        bmgr.flush_page(PageId::new(0, 0)).unwrap();
+        assert!(begin_lsn / SEGMENT_SIZE > rec_lsn_0 / SEGMENT_SIZE);
+        let rec_lsn_1 = rec_lsn(&bmgr, 1);
+        assert!(begin_lsn / SEGMENT_SIZE < rec_lsn_1 / SEGMENT_SIZE);
+        bmgr.checkpoint().unwrap();
+        assert_eq!(first_segment(&bmgr), begin_lsn / SEGMENT_SIZE);
+
+        // Nothing is dirty or active any more: only the checkpoint counts.
+        bmgr.abort(loser).unwrap();
+        fill_log(&bmgr, 6);
+        bmgr.flush_all().unwrap();
+        let checkpoint = bmgr.checkpoint().unwrap();
+        assert!(checkpoint.dirty_pages.is_empty());
+        assert!(checkpoint.transactions.is_empty());
+        assert!(checkpoint.begin_lsn / SEGMENT_SIZE > rec_lsn_1 / SEGMENT_SIZE);
+        assert_eq!(first_segment(&bmgr), checkpoint.begin_lsn / SEGMENT_SIZE);
+        drop(bmgr);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn the_checkpointer_skips_intervals_without_new_log_records() {
+        let dir = temp_dir("idle");
+        let path = dir.join("db").to_str().unwrap().to_owned();
+
+        let bmgr = Arc::new(open(&path));
+        new_pages(&bmgr, 3);
+        let mut last_lsn = None;
+        assert!(checkpoint_if_logged(&bmgr, &mut last_lsn).unwrap());
+        let checkpoint = bmgr.last_checkpoint().unwrap().unwrap();
+        assert_eq!(last_lsn, Some(checkpoint.begin_lsn));
+
+        // Nothing was logged: no checkpoint file reappears.
+        std::fs::remove_file(bmgr.log_manager().unwrap().checkpoint_path()).unwrap();
+        assert!(!checkpoint_if_logged(&bmgr, &mut last_lsn).unwrap());
+        assert_eq!(bmgr.last_checkpoint().unwrap(), None);
+
+        fill_log(&bmgr, 1);
+        assert!(checkpoint_if_logged(&bmgr, &mut last_lsn).unwrap());
+        let next = bmgr.last_checkpoint().unwrap().unwrap();
+        assert!(next.begin_lsn > checkpoint.begin_lsn);
+
+        // The thread takes one on its own once there is something new.
+        fill_log(&bmgr, 1);
+        let logged_lsn = bmgr.log_manager().unwrap().get_next_lsn();
+        let mut checkpointer = Checkpointer::start(Arc::clone(&bmgr), Duration::from_millis(1));
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while bmgr.last_checkpoint().unwrap().unwrap().begin_lsn < logged_lsn {
+            assert!(Instant::now() < deadline, "no checkpoint was taken");
+            thread::yield_now();
+        }
+        checkpointer.stop().unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn stopping_the_checkpointer_returns_the_last_failed_checkpoint() {
+        let dir = temp_dir("failed");
+        let path = dir.join("db").to_str().unwrap().to_owned();
+
+        let bmgr = Arc::new(open(&path));
+        let log_manager = Arc::clone(bmgr.log_manager().unwrap());
+        // The new checkpoint cannot be written where it would go.
+        std::fs::create_dir(format!("{}.tmp", log_manager.checkpoint_path())).unwrap();
+
+        // A record left for the checkpoint to flush shows when it has run.
+        let flush_num = log_manager.get_flush_num();
+        bmgr.begin().unwrap();
+        let mut checkpointer = Checkpointer::start(Arc::clone(&bmgr), Duration::from_millis(1));
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while log_manager.get_flush_num() == flush_num {
+            assert!(Instant::now() < deadline, "no checkpoint was attempted");
+            thread::yield_now();
+        }
+        assert!(checkpointer.stop().is_err());
+        assert!(checkpointer.stop().is_ok());
+        assert_eq!(bmgr.last_checkpoint().unwrap(), None);
+        drop((bmgr, log_manager));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
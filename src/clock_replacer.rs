@@ -26,8 +26,9 @@ impl Replacer for ClockReplacer {
             return None;
         }
 
+        // Two sweeps: the first may only clear second chances.
         let frames_len = frames.len();
-        for _ in 0..frames_len {
+        for _ in 0..2 * frames_len {
             if *pointer >= frames.len() {
                 *pointer = 0;
             }
@@ -55,6 +56,26 @@ impl Replacer for ClockReplacer {
         }
     }
 
+    fn peek_victims(&self, n: usize) -> Vec<FrameId> {
+        let frames = self.frames.lock().unwrap();
+        let pointer = *self.pointer.lock().unwrap();
+        if frames.is_empty() {
+            return Vec::new();
+        }
+
+        // Walk from the hand: frames without a second chance go first, then
+        // the ones the hand would clear on its way round.
+        let start = pointer % frames.len();
+        let order = (0..frames.len()).map(|i| frames[(start + i) % frames.len()]);
+        order
+            .clone()
+            .filter(|&(_, second_chance)| !second_chance)
+            .chain(order.filter(|&(_, second_chance)| second_chance))
+            .map(|(frame_id, _)| frame_id)
+            .take(n)
+            .collect()
+    }
+
     fn size(&self) -> usize {
         let frames = self.frames.lock().unwrap();
         frames.len()
@@ -0,0 +1,483 @@
+use crate::define::PageNo;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind};
+use std::os::unix::fs::FileExt;
+use std::sync::Mutex;
+
+/// Granularity of slots in the data file. A compressed page occupies its
+/// length rounded up to this.
+const SLOT_UNIT: u64 = 512;
+
+/// Size of one page-map entry: u64 slot start in units, u32 stored length,
+/// u8 codec, 3 bytes padding.
+const MAP_ENTRY_SIZE: u64 = 16;
+
+const CODEC_RAW: u8 = 0;
+const CODEC_LZ4: u8 = 1;
+const CODEC_ZSTD: u8 = 2;
+
+/// Algorithm used to compress pages on write. Each stored page records its
+/// own codec, so a file can be reopened with a different choice.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    #[cfg(feature = "lz4")]
+    Lz4,
+    /// zstd at the given level, 1 (fastest) to 22.
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+}
+
+/// Where a page lives in the data file. A zero `len` means the page was
+/// never written and reads as zeros.
+#[derive(Clone, Copy, Default)]
+struct Slot {
+    start: u64,
+    len: u32,
+    codec: u8,
+}
+
+impl Slot {
+    fn units(&self) -> u64 {
+        u64::from(self.len).div_ceil(SLOT_UNIT)
+    }
+
+    fn encode(&self) -> [u8; MAP_ENTRY_SIZE as usize] {
+        let mut entry = [0; MAP_ENTRY_SIZE as usize];
+        entry[..8].copy_from_slice(&self.start.to_le_bytes());
+        entry[8..12].copy_from_slice(&self.len.to_le_bytes());
+        entry[12] = self.codec;
+        entry
+    }
+
+    fn decode(entry: &[u8]) -> Self {
+        Self {
+            start: u64::from_le_bytes(entry[..8].try_into().unwrap()),
+            len: u32::from_le_bytes(entry[8..12].try_into().unwrap()),
+            codec: entry[12],
+        }
+    }
+}
+
+struct SlotState {
+    /// The page-mapping table, indexed by page number.
+    slots: Vec<Slot>,
+    /// Unused runs of units between slots: length by start, and the same
+    /// runs ordered by (length, start) for best-fit lookups.
+    free: BTreeMap<u64, u64>,
+    free_by_len: BTreeSet<(u64, u64)>,
+    /// Slots replaced since the last sync, as (start, units). The map on
+    /// disk may still point at them, so they are only freed by `sync`.
+    pending: Vec<(u64, u64)>,
+    /// First unit past the last slot.
+    end: u64,
+    /// Bytes stored for all written pages, and how many pages that is.
+    stored_bytes: u64,
+    stored_pages: u64,
+}
+
+impl SlotState {
+    /// Rebuilds the free runs from the gaps between the slots in use.
+    fn new(slots: Vec<Slot>) -> Self {
+        let mut used: Vec<(u64, u64)> = slots
+            .iter()
+            .filter(|slot| slot.len > 0)
+            .map(|slot| (slot.start, slot.units()))
+            .collect();
+        used.sort_unstable();
+        let mut state = Self {
+            free: BTreeMap::new(),
+            free_by_len: BTreeSet::new(),
+            pending: Vec::new(),
+            end: 0,
+            stored_bytes: slots.iter().map(|slot| u64::from(slot.len)).sum(),
+            stored_pages: used.len() as u64,
+            slots,
+        };
+        for (start, units) in used {
+            if start > state.end {
+                state.release(state.end, start - state.end);
+            }
+            state.end = state.end.max(start + units);
+        }
+        state
+    }
+
+    /// Best fit among the free runs, else the end of the file.
+    fn claim(&mut self, units: u64) -> u64 {
+        let Some(&(len, start)) = self.free_by_len.range((units, 0)..).next() else {
+            let start = self.end;
+            self.end += units;
+            return start;
+        };
+        self.free_by_len.remove(&(len, start));
+        self.free.remove(&start);
+        self.release(start + units, len - units);
+        start
+    }
+
+    /// Returns a run to the free space, merging it with the free runs on
+    /// either side. A run reaching the end of the file moves the end back.
+    fn release(&mut self, mut start: u64, mut units: u64) {
+        if units == 0 {
+            return;
+        }
+        if let Some((&before, &len)) = self.free.range(..start).next_back() {
+            if before + len == start {
+                self.free.remove(&before);
+                self.free_by_len.remove(&(len, before));
+                start = before;
+                units += len;
+            }
+        }
+        if let Some(len) = self.free.remove(&(start + units)) {
+            self.free_by_len.remove(&(len, start + units));
+            units += len;
+        }
+        if start + units == self.end {
+            self.end = start;
+        } else {
+            self.free.insert(start, units);
+            self.free_by_len.insert((units, start));
+        }
+    }
+
+    /// Points `page_no` at `slot`; the slot it had is freed by the next
+    /// sync.
+    fn set(&mut self, page_no: usize, slot: Slot) {
+        let old = std::mem::replace(&mut self.slots[page_no], slot);
+        if old.len > 0 {
+            self.pending.push((old.start, old.units()));
+        }
+        self.stored_bytes = self.stored_bytes - u64::from(old.len) + u64::from(slot.len);
+        self.stored_pages = self.stored_pages - u64::from(old.len > 0) + u64::from(slot.len > 0);
+    }
+}
+
+/// Compressed page storage behind a `DSMgr`.
+///
+/// Pages are compressed on write and stored in variable-size slots in the
+/// data file, after its header page; a page that does not shrink is stored
+/// as is. The page-mapping table says where each page's slot is and lives in
+/// a side file, `<data file>.map`. Pages that were never written take no
+/// space at all.
+///
+/// A page is never rewritten in place: each write goes to a fresh slot and
+/// then points the page's map entry at it. Nothing is synced on write; the
+/// slots pages moved away from are only freed by `sync`, which makes the
+/// data file and then the map durable first. So an old slot is never
+/// reused while the map on disk may still point at it, and free space is
+/// rebuilt from the map at open.
+pub(crate) struct CompressedPages {
+    map: File,
+    compression: Compression,
+    page_size: usize,
+    /// Byte offset of unit 0 in the data file.
+    base: u64,
+    state: Mutex<SlotState>,
+}
+
+impl CompressedPages {
+    pub(crate) fn open(
+        filename: &str,
+        compression: Compression,
+        page_size: usize,
+        base: u64,
+    ) -> std::io::Result<Self> {
+        let map = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(format!("{}.map", filename))?;
+        let len = map.metadata()?.len();
+        let mut entries = vec![0; (len - len % MAP_ENTRY_SIZE) as usize];
+        map.read_exact_at(&mut entries, 0)?;
+        let slots = entries
+            .chunks_exact(MAP_ENTRY_SIZE as usize)
+            .map(Slot::decode)
+            .collect();
+        Ok(Self {
+            map,
+            compression,
+            page_size,
+            base,
+            state: Mutex::new(SlotState::new(slots)),
+        })
+    }
+
+    pub(crate) fn num_pages(&self) -> PageNo {
+        self.state.lock().unwrap().slots.len() as PageNo
+    }
+
+    pub(crate) fn read_page(&self, file: &File, page_no: PageNo, data: &mut [u8]) -> std::io::Result<()> {
+        let slot = self.slot(page_no, data.len())?;
+        if slot.len == 0 {
+            data.fill(0);
+            return Ok(());
+        }
+        let mut stored = vec![0; slot.len as usize];
+        file.read_exact_at(&mut stored, self.base + slot.start * SLOT_UNIT)?;
+        let len = match slot.codec {
+            CODEC_RAW if stored.len() == data.len() => {
+                data.copy_from_slice(&stored);
+                data.len()
+            }
+            #[cfg(feature = "lz4")]
+            CODEC_LZ4 => lz4_flex::block::decompress_into(&stored, data)
+                .map_err(|e| Self::corrupt(page_no, &e.to_string()))?,
+            #[cfg(feature = "zstd")]
+            CODEC_ZSTD => zstd::bulk::decompress_to_buffer(&stored, data)
+                .map_err(|e| Self::corrupt(page_no, &e.to_string()))?,
+            #[cfg(not(feature = "lz4"))]
+            CODEC_LZ4 => return Err(Self::unsupported(page_no, "lz4")),
+            #[cfg(not(feature = "zstd"))]
+            CODEC_ZSTD => return Err(Self::unsupported(page_no, "zstd")),
+            _ => return Err(Self::corrupt(page_no, "bad slot")),
+        };
+        if len != data.len() {
+            return Err(Self::corrupt(page_no, "short page"));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn write_page(&self, file: &File, page_no: PageNo, data: &[u8]) -> std::io::Result<()> {
+        self.slot(page_no, data.len())?;
+        let compressed = match self.compression {
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => (CODEC_LZ4, lz4_flex::block::compress(data)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => (CODEC_ZSTD, zstd::bulk::compress(data, level)?),
+        };
+        let (codec, stored) = if compressed.1.len() < data.len() {
+            (compressed.0, &compressed.1[..])
+        } else {
+            (CODEC_RAW, data)
+        };
+        let mut slot = Slot {
+            start: 0,
+            len: stored.len() as u32,
+            codec,
+        };
+        slot.start = self.state.lock().unwrap().claim(slot.units());
+        if let Err(e) = file.write_all_at(stored, self.base + slot.start * SLOT_UNIT) {
+            self.state.lock().unwrap().release(slot.start, slot.units());
+            return Err(e);
+        }
+        // On failure the new slot stays claimed, as the map may point at it
+        // in part; it is free again after the next open.
+        self.map.write_all_at(&slot.encode(), page_no * MAP_ENTRY_SIZE)?;
+        self.state.lock().unwrap().set(page_no as usize, slot);
+        Ok(())
+    }
+
+    /// Makes a page read as zeros again; its slot is freed by the next sync.
+    pub(crate) fn clear(&self, page_no: PageNo) -> std::io::Result<()> {
+        self.map
+            .write_all_at(&Slot::default().encode(), page_no * MAP_ENTRY_SIZE)?;
+        self.state.lock().unwrap().set(page_no as usize, Slot::default());
+        Ok(())
+    }
+
+    /// Grows the page map to `num_pages` pages, all reading as zeros.
+    pub(crate) fn extend(&self, num_pages: PageNo) -> std::io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if num_pages as usize > state.slots.len() {
+            self.map.set_len(num_pages * MAP_ENTRY_SIZE)?;
+            state.slots.resize(num_pages as usize, Slot::default());
+        }
+        Ok(())
+    }
+
+    /// Makes the pages written so far durable, `file` first and then the
+    /// map pointing into it, and frees the slots they replaced.
+    pub(crate) fn sync(&self, file: &File) -> std::io::Result<()> {
+        // Slots replaced later may still be in the map synced below.
+        let pending = std::mem::take(&mut self.state.lock().unwrap().pending);
+        let result = file.sync_data().and_then(|()| self.map.sync_data());
+        let mut state = self.state.lock().unwrap();
+        match result {
+            Ok(()) => pending
+                .into_iter()
+                .for_each(|(start, units)| state.release(start, units)),
+            Err(_) => state.pending.extend(pending),
+        }
+        result
+    }
+
+    /// Bytes the written pages take up compressed, slot padding excluded.
+    pub(crate) fn stored_bytes(&self) -> u64 {
+        self.state.lock().unwrap().stored_bytes
+    }
+
+    /// Uncompressed over compressed size of the written pages; 1.0 if none
+    /// have been written yet.
+    pub(crate) fn ratio(&self) -> f64 {
+        let state = self.state.lock().unwrap();
+        if state.stored_bytes == 0 {
+            return 1.0;
+        }
+        (state.stored_pages * self.page_size as u64) as f64 / state.stored_bytes as f64
+    }
+
+    fn slot(&self, page_no: PageNo, len: usize) -> std::io::Result<Slot> {
+        let state = self.state.lock().unwrap();
+        match state.slots.get(page_no as usize) {
+            Some(slot) if len == self.page_size => Ok(*slot),
+            _ => Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("page {} is past the end of the file", page_no),
+            )),
+        }
+    }
+
+    #[cfg(not(all(feature = "lz4", feature = "zstd")))]
+    fn unsupported(page_no: PageNo, codec: &str) -> Error {
+        Error::new(
+            ErrorKind::Unsupported,
+            format!("page {} is {}-compressed, which this build does not include", page_no, codec),
+        )
+    }
+
+    fn corrupt(page_no: PageNo, what: &str) -> Error {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("cannot decompress page {}: {}", page_no, what),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_storage_manager::{DSMgr, DSMgrOptions};
+    use crate::define::PAGE_SIZE;
+    use rand::{Rng, SeedableRng};
+
+    fn compression() -> Compression {
+        #[cfg(feature = "lz4")]
+        return Compression::Lz4;
+        #[cfg(not(feature = "lz4"))]
+        Compression::Zstd(3)
+    }
+
+    fn open(path: &str) -> DSMgr {
+        let options = DSMgrOptions {
+            compression: Some(compression()),
+            ..DSMgrOptions::default()
+        };
+        DSMgr::open_with(path, options).unwrap()
+    }
+
+    fn remove(path: &str) {
+        let _ = std::fs::remove_file(path);
+        let _ = std::fs::remove_file(format!("{}.map", path));
+    }
+
+    #[test]
+    fn pages_round_trip_compressed_or_raw() {
+        let path = std::env::temp_dir().join(format!("adbs-compression-{}.dbf", std::process::id()));
+        let path = path.to_str().unwrap();
+        remove(path);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let random: Vec<u8> = (0..PAGE_SIZE).map(|_| rng.gen()).collect();
+        let pages = [vec![7; PAGE_SIZE], random.clone(), vec![0; PAGE_SIZE]];
+
+        let dsmgr = open(path);
+        for page in &pages {
+            let page_no = dsmgr.new_page().unwrap();
+            dsmgr.write_page(page_no, page).unwrap();
+        }
+        // Incompressible pages are stored as they are.
+        let stored = dsmgr.get_stored_bytes().unwrap();
+        assert!(stored >= PAGE_SIZE as u64 && stored < 2 * PAGE_SIZE as u64);
+        // Pages growing and shrinking move to fresh slots.
+        dsmgr.write_page(0, &random).unwrap();
+        dsmgr.write_page(1, &pages[0]).unwrap();
+        dsmgr.close_file().unwrap();
+        drop(dsmgr);
+
+        let dsmgr = open(path);
+        assert!(dsmgr.get_num_pages() >= 3);
+        for (page_no, expected) in [random.clone(), pages[0].clone(), pages[2].clone()].iter().enumerate() {
+            let mut data = vec![1; PAGE_SIZE];
+            dsmgr.read_page(page_no as PageNo, &mut data).unwrap();
+            assert_eq!(&data, expected, "page {}", page_no);
+        }
+        drop(dsmgr);
+        remove(path);
+    }
+
+    #[test]
+    fn rewrites_leave_the_old_slot_intact_until_the_map_is_synced() {
+        let path = std::env::temp_dir().join(format!("adbs-compression-slots-{}.dbf", std::process::id()));
+        let path = path.to_str().unwrap();
+        remove(path);
+        let slot_of = |page_no: usize| {
+            let map = std::fs::read(format!("{}.map", path)).unwrap();
+            Slot::decode(&map[page_no * MAP_ENTRY_SIZE as usize..][..MAP_ENTRY_SIZE as usize])
+        };
+        let stored_at = |slot: Slot| {
+            let start = (PAGE_SIZE as u64 + slot.start * SLOT_UNIT) as usize;
+            std::fs::read(path).unwrap()[start..start + slot.len as usize].to_vec()
+        };
+
+        let dsmgr = open(path);
+        for byte in 7..9 {
+            let page_no = dsmgr.new_page().unwrap();
+            dsmgr.write_page(page_no, &[byte; PAGE_SIZE]).unwrap();
+        }
+        let old = slot_of(0);
+        let old_stored = stored_at(old);
+
+        // The same size would fit in place, but the page moves anyway, and
+        // the version the map pointed at is still there.
+        dsmgr.write_page(0, &[9; PAGE_SIZE]).unwrap();
+        let new = slot_of(0);
+        assert_ne!(new.start, old.start);
+        assert_eq!(stored_at(old), old_stored);
+
+        // Until the map is synced, the old slot is not reused.
+        dsmgr.write_page(1, &[10; PAGE_SIZE]).unwrap();
+        assert!(slot_of(1).start > new.start);
+        assert_eq!(stored_at(old), old_stored);
+
+        // Afterwards it is.
+        dsmgr.sync().unwrap();
+        dsmgr.write_page(0, &[11; PAGE_SIZE]).unwrap();
+        assert_eq!(slot_of(0).start, old.start);
+        let mut data = vec![0; PAGE_SIZE];
+        dsmgr.read_page(0, &mut data).unwrap();
+        assert_eq!(data, [11; PAGE_SIZE]);
+        dsmgr.read_page(1, &mut data).unwrap();
+        assert_eq!(data, [10; PAGE_SIZE]);
+        drop(dsmgr);
+        remove(path);
+    }
+
+    #[test]
+    fn damaged_page_map_is_reported() {
+        let path = std::env::temp_dir().join(format!("adbs-compression-map-{}.dbf", std::process::id()));
+        let path = path.to_str().unwrap();
+        remove(path);
+        let dsmgr = open(path);
+        for byte in 0..2 {
+            let page_no = dsmgr.new_page().unwrap();
+            dsmgr.write_page(page_no, &[byte; PAGE_SIZE]).unwrap();
+        }
+        drop(dsmgr);
+
+        let map = OpenOptions::new().write(true).open(format!("{}.map", path)).unwrap();
+        // An unknown codec for page 0, a stored length cut short for page 1.
+        map.write_all_at(&[9], 12).unwrap();
+        map.write_all_at(&1u32.to_le_bytes(), MAP_ENTRY_SIZE + 8).unwrap();
+        let dsmgr = open(path);
+        let mut data = vec![0; PAGE_SIZE];
+        for page_no in 0..2 {
+            let error = dsmgr.read_page(page_no, &mut data).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData, "page {}", page_no);
+        }
+        drop(dsmgr);
+        remove(path);
+    }
+}
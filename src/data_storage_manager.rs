@@ -1,30 +1,196 @@
-use crate::define::PageId;
+use crate::define::{PageNo, PAGE_SIZE};
+use crate::disk_manager::DiskManager;
+use crate::extent::{Allocation, ExtentAllocator, SegmentId};
+use crate::file_header::FileHeader;
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+use crate::compression::{CompressedPages, Compression};
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+use crate::file_header::FLAG_COMPRESSED;
+#[cfg(feature = "encryption")]
+use crate::encryption::{EncryptionKey, PageCipher};
+#[cfg(feature = "encryption")]
+use crate::file_header::FLAG_ENCRYPTED;
+use crate::page::PageData;
 use std::fs::{File, OpenOptions};
-use std::io::{Seek, SeekFrom, Read, Write};
-use std::sync::Mutex;
+use std::io::Write;
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use crate::uring::UringIo;
 
+/// When written pages are forced to stable storage.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Leave it to the OS (or to explicit `sync` calls).
+    None,
+    /// `fdatasync` after every `write_page` / `write_pages` call.
+    DataSync,
+    /// `fsync` after every `write_page` / `write_pages` call.
+    FullSync,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DSMgrOptions {
+    /// Open the file with `O_DIRECT`, bypassing the OS page cache. Every
+    /// buffer passed in must then be 4096-aligned, e.g. a `PageData`.
+    pub direct_io: bool,
+    pub sync_policy: SyncPolicy,
+    /// Page size of a newly created file. An existing file keeps the page
+    /// size recorded in its header.
+    pub page_size: usize,
+    /// Serve all page I/O through an io_uring with this many registered
+    /// buffers.
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    pub uring_queue_depth: Option<u32>,
+    /// Store pages compressed, see `compression`. Whether a file is
+    /// compressed is fixed when it is created; the algorithm only applies to
+    /// pages written from now on. Cannot be combined with `direct_io` or
+    /// io_uring.
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    pub compression: Option<Compression>,
+    /// Encrypt pages with this key, see `encryption`. Like compression,
+    /// whether a file is encrypted is fixed when it is created. Cannot be
+    /// combined with compression.
+    #[cfg(feature = "encryption")]
+    pub encryption_key: Option<EncryptionKey>,
+}
+
+impl Default for DSMgrOptions {
+    fn default() -> Self {
+        Self {
+            direct_io: false,
+            sync_policy: SyncPolicy::None,
+            page_size: PAGE_SIZE,
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            uring_queue_depth: None,
+            #[cfg(any(feature = "lz4", feature = "zstd"))]
+            compression: None,
+            #[cfg(feature = "encryption")]
+            encryption_key: None,
+        }
+    }
+}
+
+/// Page-granular access to a single database file.
+///
+/// The file starts with a header page recording its page size (see
+/// `FileHeader`); data pages follow, each `get_page_size()` bytes. Files
+/// from before headers existed are opened as bare 4096-byte pages.
+/// All reads and writes are positional (`pread`/`pwrite`), so the file needs
+/// no lock and I/O on different pages can proceed in parallel. With the
+/// `io-uring` feature a manager opened with `uring_queue_depth` submits all
+/// page I/O through an io_uring instead. With the `lz4` or `zstd` feature a
+/// manager opened with `compression` stores pages compressed, in slots of
+/// varying size rather than at fixed offsets. With the `encryption` feature
+/// a manager opened with `encryption_key` encrypts every page it writes and
+/// authenticates every page it reads.
 pub struct DSMgr {
-    file: Mutex<File>,
-    num_pages: Mutex<PageId>,
-    write_num: Mutex<PageId>,
+    file: File,
+    header: FileHeader,
+    sync_policy: SyncPolicy,
+    /// Decides which pages to hand out; the file itself grows an extent at a
+    /// time through `extend`.
+    extents: ExtentAllocator,
+    read_num: AtomicU64,
+    write_num: AtomicU64,
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    uring: Option<UringIo>,
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    compressed: Option<CompressedPages>,
+    #[cfg(feature = "encryption")]
+    cipher: Option<PageCipher>,
 }
 
 impl DSMgr {
     pub fn new(filename: &str) -> std::io::Result<Self> {
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(filename)?;
-        
-        let metadata = file.metadata()?;
-        let file_size = metadata.len();
-        let num_pages = (file_size / crate::define::PAGE_SIZE as u64) as PageId;
-        
+        Self::open_with(filename, DSMgrOptions::default())
+    }
+
+    pub fn open_with(filename: &str, options: DSMgrOptions) -> std::io::Result<Self> {
+        let mut open_options = OpenOptions::new();
+        open_options.read(true).write(true).create(true).truncate(false);
+        if options.direct_io {
+            #[cfg(target_os = "linux")]
+            open_options.custom_flags(libc::O_DIRECT);
+            #[cfg(not(target_os = "linux"))]
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "O_DIRECT is only supported on Linux",
+            ));
+        }
+        #[allow(unused_mut)]
+        let mut flags = 0;
+        #[cfg(any(feature = "lz4", feature = "zstd"))]
+        if options.compression.is_some() {
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            let uring = options.uring_queue_depth.is_some();
+            #[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+            let uring = false;
+            if options.direct_io || uring {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "compression cannot be combined with O_DIRECT or io_uring",
+                ));
+            }
+            flags |= FLAG_COMPRESSED;
+        }
+        #[cfg(feature = "encryption")]
+        if options.encryption_key.is_some() {
+            if flags != 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "encryption cannot be combined with compression",
+                ));
+            }
+            flags |= FLAG_ENCRYPTED;
+        }
+        let file = open_options.open(filename)?;
+        let header = FileHeader::init(&file, options.page_size, flags)?;
+
+        #[cfg(any(feature = "lz4", feature = "zstd"))]
+        let compressed = match options.compression {
+            Some(compression) => Some(CompressedPages::open(
+                filename,
+                compression,
+                header.page_size,
+                header.offset(0),
+            )?),
+            None => None,
+        };
+        #[cfg(feature = "encryption")]
+        let cipher = match &options.encryption_key {
+            Some(key) => Some(PageCipher::open(filename, key, header.salt, header.page_size, |page_no, data| {
+                file.read_exact_at(data, header.offset(page_no))
+            })?),
+            None => None,
+        };
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        let uring = match options.uring_queue_depth {
+            Some(queue_depth) => Some(UringIo::new(queue_depth, header.page_size)?),
+            None => None,
+        };
+        let file_size = file.metadata()?.len();
+        #[allow(unused_mut)]
+        let mut num_pages = header.num_pages(file_size) as PageNo;
+        #[cfg(any(feature = "lz4", feature = "zstd"))]
+        if let Some(compressed) = &compressed {
+            num_pages = compressed.num_pages();
+        }
+
         Ok(Self {
-            file: Mutex::new(file),
-            num_pages: Mutex::new(num_pages),
-            write_num: Mutex::new(0),
+            file,
+            header,
+            sync_policy: options.sync_policy,
+            extents: ExtentAllocator::new(num_pages),
+            read_num: AtomicU64::new(0),
+            write_num: AtomicU64::new(0),
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            uring,
+            #[cfg(any(feature = "lz4", feature = "zstd"))]
+            compressed,
+            #[cfg(feature = "encryption")]
+            cipher,
         })
     }
 
@@ -32,45 +198,506 @@ impl DSMgr {
         Self::new(filename)
     }
 
+    /// Opens `filename` with an io_uring of `queue_depth` registered page
+    /// buffers serving every read and write.
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    pub fn open_file_with_uring(filename: &str, queue_depth: u32) -> std::io::Result<Self> {
+        Self::open_with(
+            filename,
+            DSMgrOptions {
+                uring_queue_depth: Some(queue_depth),
+                ..DSMgrOptions::default()
+            },
+        )
+    }
+
     pub fn close_file(&self) -> std::io::Result<()> {
-        let mut file = self.file.lock().unwrap();
-        file.flush()
+        (&self.file).flush()?;
+        self.sync()
+    }
+
+    /// Forces everything written so far to stable storage.
+    pub fn sync(&self) -> std::io::Result<()> {
+        #[cfg(any(feature = "lz4", feature = "zstd"))]
+        if let Some(compressed) = &self.compressed {
+            // Syncs the file itself, before the page map.
+            return compressed.sync(&self.file);
+        }
+        self.file.sync_all()?;
+        #[cfg(feature = "encryption")]
+        if let Some(cipher) = &self.cipher {
+            cipher.sync()?;
+        }
+        Ok(())
     }
 
-    pub fn new_page(&self) -> std::io::Result<PageId> {
-        let mut num_pages = self.num_pages.lock().unwrap();
-        let page_id = *num_pages;
-        *num_pages += 1;
-        // Initialize the new page with zeros
-        let mut file = self.file.lock().unwrap();
-        file.seek(SeekFrom::End(0))?;
-        file.write_all(&vec![0u8; crate::define::PAGE_SIZE])?;
-        Ok(page_id)
+    pub fn new_page(&self) -> std::io::Result<PageNo> {
+        self.allocate()
     }
 
-    pub fn read_page(&self, page_id: PageId, data: &mut [u8]) -> std::io::Result<()> {
-        let mut file = self.file.lock().unwrap();
-        file.seek(SeekFrom::Start(page_id as u64 * crate::define::PAGE_SIZE as u64))?;
-        file.read_exact(data)?;
+    pub fn read_page(&self, page_no: PageNo, data: &mut [u8]) -> std::io::Result<()> {
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if self.uring.is_some() {
+            return self.read_pages(&mut [(page_no, data)]).remove(0);
+        }
+        self.read_at(page_no, data)?;
+        self.read_num.fetch_add(1, Ordering::SeqCst);
         Ok(())
     }
 
-    pub fn write_page(&self, page_id: PageId, data: &[u8]) -> std::io::Result<()> {
-        let mut file = self.file.lock().unwrap();
-        file.seek(SeekFrom::Start(page_id as u64 * crate::define::PAGE_SIZE as u64))?;
-        file.write_all(data)?;
-        let mut write_num = self.write_num.lock().unwrap();
-        *write_num += 1;
+    pub fn write_page(&self, page_no: PageNo, data: &[u8]) -> std::io::Result<()> {
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if self.uring.is_some() {
+            return self.write_pages(&[(page_no, data)]).remove(0);
+        }
+        #[cfg(feature = "encryption")]
+        if self.cipher.is_some() {
+            return self.write_pages(&[(page_no, data)]).remove(0);
+        }
+        self.write_at(page_no, data)?;
+        self.write_num.fetch_add(1, Ordering::SeqCst);
+        self.apply_sync_policy()
+    }
+
+    /// Reads a batch of pages. Results are returned per page, in order; with
+    /// io_uring the whole batch is submitted at once.
+    pub fn read_pages(&self, reads: &mut [(PageNo, &mut [u8])]) -> Vec<std::io::Result<()>> {
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if let Some(uring) = &self.uring {
+            let mut offsets: Vec<(u64, &mut [u8])> = reads
+                .iter_mut()
+                .map(|(page_no, data)| (self.offset(*page_no), &mut **data))
+                .collect();
+            let mut results = uring.read_at(&self.file, &mut offsets);
+            let read = results.iter().filter(|result| result.is_ok()).count();
+            self.read_num.fetch_add(read as u64, Ordering::SeqCst);
+            for (result, (page_no, data)) in results.iter_mut().zip(reads.iter_mut()) {
+                if result.is_ok() {
+                    *result = self.open_page(*page_no, data);
+                }
+            }
+            return results;
+        }
+        reads
+            .iter_mut()
+            .map(|(page_no, data)| {
+                self.read_at(*page_no, data)?;
+                self.read_num.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            })
+            .collect()
+    }
+
+    /// Writes a batch of pages. Results are returned per page, in order; with
+    /// io_uring the whole batch is submitted at once. The sync policy is
+    /// applied once for the whole batch.
+    pub fn write_pages(&self, writes: &[(PageNo, &[u8])]) -> Vec<std::io::Result<()>> {
+        // Encrypted files get the ciphertexts written in place of the pages.
+        #[cfg(feature = "encryption")]
+        let sealed: Option<Vec<_>> = self.cipher.as_ref().map(|cipher| {
+            writes
+                .iter()
+                .map(|(page_no, data)| cipher.seal(*page_no, data))
+                .collect()
+        });
+        // Their tags have to be durable before any ciphertext is written.
+        #[cfg(feature = "encryption")]
+        if let (Some(cipher), Some(sealed)) = (&self.cipher, &sealed) {
+            let staged: Vec<_> = writes.iter().zip(sealed).map(|((page_no, _), sealed)| (*page_no, sealed)).collect();
+            if let Err(e) = cipher.stage(&staged, |page_no, data| self.file.read_exact_at(data, self.offset(page_no))) {
+                return writes
+                    .iter()
+                    .map(|_| Err(std::io::Error::new(e.kind(), e.to_string())))
+                    .collect();
+            }
+        }
+        #[cfg(feature = "encryption")]
+        let ciphertexts: Vec<(PageNo, &[u8])>;
+        #[cfg(feature = "encryption")]
+        let writes = match &sealed {
+            Some(sealed) => {
+                ciphertexts = writes
+                    .iter()
+                    .zip(sealed)
+                    .map(|((page_no, _), sealed)| (*page_no, &sealed.data[..]))
+                    .collect();
+                &ciphertexts[..]
+            }
+            None => writes,
+        };
+
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        let mut results = if let Some(uring) = &self.uring {
+            let offsets: Vec<(u64, &[u8])> = writes
+                .iter()
+                .map(|(page_no, data)| (self.offset(*page_no), *data))
+                .collect();
+            uring.write_at(&self.file, &offsets)
+        } else {
+            self.write_each(writes)
+        };
+        #[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+        let mut results = self.write_each(writes);
+
+        #[cfg(feature = "encryption")]
+        if let (Some(cipher), Some(sealed)) = (&self.cipher, &sealed) {
+            for ((result, (page_no, _)), sealed) in results.iter_mut().zip(writes).zip(sealed) {
+                if result.is_ok() {
+                    *result = cipher.commit(*page_no, sealed);
+                }
+            }
+        }
+        let written = results.iter().filter(|result| result.is_ok()).count();
+        self.write_num.fetch_add(written as u64, Ordering::SeqCst);
+        if written > 0 {
+            if let Err(e) = self.apply_sync_policy() {
+                // Nothing written in this batch is known to be durable.
+                for result in results.iter_mut().filter(|result| result.is_ok()) {
+                    *result = Err(std::io::Error::new(e.kind(), e.to_string()));
+                }
+            }
+        }
+        results
+    }
+
+    pub fn get_num_pages(&self) -> PageNo {
+        self.extents.num_pages()
+    }
+
+    /// Pages read from the file. With `direct_io` this is the number of
+    /// device reads.
+    pub fn get_read_num(&self) -> u64 {
+        self.read_num.load(Ordering::SeqCst)
+    }
+
+    pub fn get_write_num(&self) -> u64 {
+        self.write_num.load(Ordering::SeqCst)
+    }
+
+    pub fn get_page_size(&self) -> usize {
+        self.header.page_size
+    }
+
+    pub fn get_sync_policy(&self) -> SyncPolicy {
+        self.sync_policy
+    }
+
+    /// Uncompressed over stored size of the pages written so far, or `None`
+    /// if the file is not compressed.
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    pub fn get_compression_ratio(&self) -> Option<f64> {
+        self.compressed.as_ref().map(CompressedPages::ratio)
+    }
+
+    /// Bytes the written pages take up after compression, or `None` if the
+    /// file is not compressed.
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    pub fn get_stored_bytes(&self) -> Option<u64> {
+        self.compressed.as_ref().map(CompressedPages::stored_bytes)
+    }
+
+    fn write_each(&self, writes: &[(PageNo, &[u8])]) -> Vec<std::io::Result<()>> {
+        writes
+            .iter()
+            .map(|(page_no, data)| self.write_at(*page_no, data))
+            .collect()
+    }
+
+    fn read_at(&self, page_no: PageNo, data: &mut [u8]) -> std::io::Result<()> {
+        #[cfg(any(feature = "lz4", feature = "zstd"))]
+        if let Some(compressed) = &self.compressed {
+            return compressed.read_page(&self.file, page_no, data);
+        }
+        self.file.read_exact_at(data, self.offset(page_no))?;
+        self.open_page(page_no, data)
+    }
+
+    /// Decrypts a page just read from the file; a no-op unless the file is
+    /// encrypted.
+    #[allow(unused_variables)]
+    fn open_page(&self, page_no: PageNo, data: &mut [u8]) -> std::io::Result<()> {
+        #[cfg(feature = "encryption")]
+        if let Some(cipher) = &self.cipher {
+            return cipher.open_page(page_no, data);
+        }
         Ok(())
     }
 
-    pub fn get_num_pages(&self) -> PageId {
-        let num_pages = self.num_pages.lock().unwrap();
-        *num_pages
+    fn write_at(&self, page_no: PageNo, data: &[u8]) -> std::io::Result<()> {
+        #[cfg(any(feature = "lz4", feature = "zstd"))]
+        if let Some(compressed) = &self.compressed {
+            return compressed.write_page(&self.file, page_no, data);
+        }
+        self.file.write_all_at(data, self.offset(page_no))
     }
 
-    pub fn get_write_num(&self) -> PageId {
-        let write_num = self.write_num.lock().unwrap();
-        *write_num
+    fn apply_sync_policy(&self) -> std::io::Result<()> {
+        match self.sync_policy {
+            SyncPolicy::None => Ok(()),
+            SyncPolicy::DataSync => {
+                // The page map or tags have to reach the disk with the pages
+                // they describe.
+                #[cfg(any(feature = "lz4", feature = "zstd"))]
+                if let Some(compressed) = &self.compressed {
+                    return compressed.sync(&self.file);
+                }
+                self.file.sync_data()?;
+                #[cfg(feature = "encryption")]
+                if let Some(cipher) = &self.cipher {
+                    cipher.sync()?;
+                }
+                Ok(())
+            }
+            SyncPolicy::FullSync => self.sync(),
+        }
+    }
+
+    /// Grows the file to hold `num_pages` pages. The new pages read as
+    /// zeros; where the file system supports it they are allocated up front
+    /// with `fallocate`, so later writes do not fragment the file.
+    fn extend(&self, num_pages: PageNo) -> std::io::Result<()> {
+        #[cfg(any(feature = "lz4", feature = "zstd"))]
+        if let Some(compressed) = &self.compressed {
+            return compressed.extend(num_pages);
+        }
+        let len = self.file.metadata()?.len();
+        let new_len = self.offset(num_pages);
+        if new_len <= len {
+            return Ok(());
+        }
+        #[cfg(target_os = "linux")]
+        {
+            // SAFETY: a plain syscall on a descriptor this manager owns.
+            let ret = unsafe {
+                libc::fallocate(
+                    self.file.as_raw_fd(),
+                    0,
+                    len as libc::off_t,
+                    (new_len - len) as libc::off_t,
+                )
+            };
+            if ret == 0 {
+                return Ok(());
+            }
+            let e = std::io::Error::last_os_error();
+            if e.raw_os_error() != Some(libc::EOPNOTSUPP) {
+                return Err(e);
+            }
+        }
+        self.file.set_len(new_len)
+    }
+
+    fn offset(&self, page_no: PageNo) -> u64 {
+        self.header.offset(page_no)
+    }
+}
+
+impl DiskManager for DSMgr {
+    fn read_page(&self, page_no: PageNo, data: &mut [u8]) -> std::io::Result<()> {
+        DSMgr::read_page(self, page_no, data)
+    }
+
+    fn write_page(&self, page_no: PageNo, data: &[u8]) -> std::io::Result<()> {
+        DSMgr::write_page(self, page_no, data)
+    }
+
+    fn allocate_in(&self, segment: SegmentId) -> std::io::Result<PageNo> {
+        match self.extents.allocate_in(segment, |num_pages| self.extend(num_pages))? {
+            Allocation::Fresh(page_no) => Ok(page_no),
+            Allocation::Reused(page_no) => {
+                #[cfg(any(feature = "lz4", feature = "zstd"))]
+                if let Some(compressed) = &self.compressed {
+                    compressed.clear(page_no)?;
+                    return Ok(page_no);
+                }
+                #[cfg(feature = "encryption")]
+                if let Some(cipher) = &self.cipher {
+                    cipher.clear(page_no)?;
+                    return Ok(page_no);
+                }
+                self.file.write_all_at(&PageData::zeroed(self.get_page_size()), self.offset(page_no))?;
+                Ok(page_no)
+            }
+        }
+    }
+
+    fn allocate_pages(&self, n: PageNo) -> std::io::Result<PageNo> {
+        self.extents.allocate_pages(n, |num_pages| self.extend(num_pages))
+    }
+
+    fn deallocate(&self, page_no: PageNo) -> std::io::Result<()> {
+        self.extents.deallocate(page_no)
+    }
+
+    fn sync(&self) -> std::io::Result<()> {
+        DSMgr::sync(self)
+    }
+
+    fn num_pages(&self) -> PageNo {
+        self.get_num_pages()
+    }
+
+    fn page_size(&self) -> usize {
+        self.get_page_size()
+    }
+
+    fn read_pages(&self, reads: &mut [(PageNo, &mut [u8])]) -> Vec<std::io::Result<()>> {
+        DSMgr::read_pages(self, reads)
+    }
+
+    fn write_pages(&self, writes: &[(PageNo, &[u8])]) -> Vec<std::io::Result<()>> {
+        DSMgr::write_pages(self, writes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn direct_io_reads_and_writes_aligned_pages() {
+        let path = std::env::temp_dir().join(format!("adbs-direct-{}.dbf", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        let options = DSMgrOptions {
+            direct_io: true,
+            sync_policy: SyncPolicy::DataSync,
+            ..DSMgrOptions::default()
+        };
+
+        let dsmgr = DSMgr::open_with(path, options).unwrap();
+        assert_eq!(dsmgr.get_sync_policy(), SyncPolicy::DataSync);
+        let page_no = dsmgr.new_page().unwrap();
+        let mut written = PageData::zeroed(PAGE_SIZE);
+        assert!((written.as_ptr() as usize).is_multiple_of(4096));
+        written.iter_mut().enumerate().for_each(|(i, byte)| *byte = i as u8);
+        dsmgr.write_page(page_no, &written).unwrap();
+        assert_eq!(dsmgr.get_write_num(), 1);
+
+        // O_DIRECT refuses buffers that are not aligned.
+        let buffer = PageData::zeroed(2 * PAGE_SIZE);
+        assert!(dsmgr.write_page(page_no, &buffer[1..PAGE_SIZE + 1]).is_err());
+        drop(dsmgr);
+
+        let dsmgr = DSMgr::open_with(path, options).unwrap();
+        let mut data = PageData::zeroed(PAGE_SIZE);
+        dsmgr.read_page(page_no, &mut data).unwrap();
+        assert_eq!(&data[..], &written[..]);
+        drop(dsmgr);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn legacy_files_without_a_header_keep_their_pages_in_place() {
+        let path = std::env::temp_dir().join(format!("adbs-legacy-{}.dbf", std::process::id()));
+        let path = path.to_str().unwrap();
+        // Like the test.dbf older versions initialized: zeroed pages only.
+        std::fs::write(path, vec![0; 3 * PAGE_SIZE]).unwrap();
+
+        let dsmgr = DSMgr::open_file(path).unwrap();
+        assert_eq!((dsmgr.get_num_pages(), dsmgr.get_page_size()), (3, PAGE_SIZE));
+        let written: Vec<u8> = (0..PAGE_SIZE).map(|i| i as u8).collect();
+        dsmgr.write_page(1, &written).unwrap();
+        drop(dsmgr);
+        assert_eq!(std::fs::read(path).unwrap()[PAGE_SIZE..2 * PAGE_SIZE], written[..]);
+
+        let dsmgr = DSMgr::open_file(path).unwrap();
+        let mut data = vec![0; PAGE_SIZE];
+        dsmgr.read_page(1, &mut data).unwrap();
+        assert_eq!(data, written);
+        dsmgr.read_page(2, &mut data).unwrap();
+        assert!(data.iter().all(|&byte| byte == 0));
+        drop(dsmgr);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    #[test]
+    fn uring_pages_round_trip_one_by_one_and_in_batches() {
+        let path = std::env::temp_dir().join(format!("adbs-uring-{}.dbf", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        let page = |seed: usize| -> Vec<u8> { (0..PAGE_SIZE).map(|i| (i * 7 + seed) as u8).collect() };
+
+        // More pages than the queue is deep, so the batches go in chunks.
+        let dsmgr = DSMgr::open_file_with_uring(path, 4).unwrap();
+        let page_nos: Vec<PageNo> = (0..10).map(|_| dsmgr.new_page().unwrap()).collect();
+        dsmgr.write_page(page_nos[0], &page(100)).unwrap();
+        let mut data = vec![0; PAGE_SIZE];
+        dsmgr.read_page(page_nos[0], &mut data).unwrap();
+        assert_eq!(data, page(100));
+
+        let pages: Vec<Vec<u8>> = page_nos.iter().map(|&page_no| page(page_no as usize)).collect();
+        let writes: Vec<(PageNo, &[u8])> =
+            page_nos.iter().zip(&pages).map(|(&page_no, data)| (page_no, &data[..])).collect();
+        assert!(dsmgr.write_pages(&writes).iter().all(Result::is_ok));
+        assert_eq!(dsmgr.get_write_num(), 11);
+
+        // A page past the end of the file fails alone.
+        let mut buffers = vec![vec![0; PAGE_SIZE]; page_nos.len() + 1];
+        let mut reads: Vec<(PageNo, &mut [u8])> = page_nos
+            .iter()
+            .copied()
+            .chain([1 << 20])
+            .zip(buffers.iter_mut())
+            .map(|(page_no, data)| (page_no, &mut data[..]))
+            .collect();
+        let results = dsmgr.read_pages(&mut reads);
+        assert!(results[..page_nos.len()].iter().all(Result::is_ok));
+        assert_eq!(results[page_nos.len()].as_ref().unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(&buffers[..page_nos.len()], &pages[..]);
+        dsmgr.close_file().unwrap();
+        drop(dsmgr);
+
+        // The pages are where plain I/O expects them.
+        let dsmgr = DSMgr::open_file(path).unwrap();
+        for (&page_no, expected) in page_nos.iter().zip(&pages) {
+            dsmgr.read_page(page_no, &mut data).unwrap();
+            assert_eq!(&data, expected, "page {}", page_no);
+        }
+        drop(dsmgr);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn encrypted_pages_round_trip_and_detect_tampering() {
+        let path = std::env::temp_dir().join(format!("adbs-encrypted-{}.dbf", std::process::id()));
+        let path = path.to_str().unwrap();
+        let remove = || {
+            let _ = std::fs::remove_file(path);
+            let _ = std::fs::remove_file(format!("{}.tags", path));
+        };
+        remove();
+        let open = |key: u8| {
+            let options = DSMgrOptions {
+                encryption_key: Some(EncryptionKey([key; 32])),
+                ..DSMgrOptions::default()
+            };
+            DSMgr::open_with(path, options)
+        };
+
+        let dsmgr = open(1).unwrap();
+        let page_no = dsmgr.new_page().unwrap();
+        let written: Vec<u8> = (0..PAGE_SIZE).map(|i| i as u8).collect();
+        dsmgr.write_page(page_no, &written).unwrap();
+        dsmgr.write_page(page_no + 1, &written).unwrap();
+        dsmgr.close_file().unwrap();
+        drop(dsmgr);
+
+        // Nothing of the plaintext is on disk.
+        let raw = std::fs::read(path).unwrap();
+        assert!(!raw.windows(64).any(|window| window == &written[..64]));
+        assert_eq!(open(2).err().unwrap().kind(), std::io::ErrorKind::InvalidInput);
+
+        let dsmgr = open(1).unwrap();
+        let mut data = vec![0; PAGE_SIZE];
+        dsmgr.read_page(page_no, &mut data).unwrap();
+        assert_eq!(data, written);
+        dsmgr.file.write_all_at(&[0x5a], dsmgr.offset(page_no) + 10).unwrap();
+        let error = dsmgr.read_page(page_no, &mut data).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        drop(dsmgr);
+        remove();
     }
 }
@@ -1,4 +1,35 @@
-pub type PageId = i32;
-pub type FrameId = i32;
+/// Identifies a data file within a tablespace.
+pub type FileId = u32;
+/// Position of a page within its data file.
+pub type PageNo = u64;
+/// Index of a frame in the buffer pool.
+pub type FrameId = usize;
 
+/// Address of a page: which file it lives in and where in that file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PageId {
+    pub file_id: FileId,
+    pub page_no: PageNo,
+}
+
+impl PageId {
+    pub const fn new(file_id: FileId, page_no: PageNo) -> Self {
+        Self { file_id, page_no }
+    }
+}
+
+impl std::fmt::Display for PageId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.file_id, self.page_no)
+    }
+}
+
+/// Page size of newly created database files unless asked otherwise.
 pub const PAGE_SIZE: usize = 4096;
+pub const MIN_PAGE_SIZE: usize = 4096;
+pub const MAX_PAGE_SIZE: usize = 65536;
+
+/// Page sizes are powers of two from `MIN_PAGE_SIZE` to `MAX_PAGE_SIZE`.
+pub fn is_valid_page_size(page_size: usize) -> bool {
+    page_size.is_power_of_two() && (MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&page_size)
+}
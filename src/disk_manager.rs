@@ -0,0 +1,48 @@
+use crate::define::PageNo;
+use crate::extent::{SegmentId, DEFAULT_SEGMENT};
+
+/// Page storage underneath a `BufferPoolManager`.
+///
+/// Pages are `page_size()` bytes and addressed by number; storage grows in
+/// whole extents of `EXTENT_SIZE` pages. Implementations must be safe to call
+/// from many threads at once; the buffer pool never reads and writes the same
+/// page concurrently.
+pub trait DiskManager: Send + Sync + 'static {
+    fn read_page(&self, page_no: PageNo, data: &mut [u8]) -> std::io::Result<()>;
+    fn write_page(&self, page_no: PageNo, data: &[u8]) -> std::io::Result<()>;
+
+    /// Returns a zeroed page.
+    fn allocate(&self) -> std::io::Result<PageNo> {
+        self.allocate_in(DEFAULT_SEGMENT)
+    }
+    /// Returns a zeroed page close to the others allocated for `segment`.
+    fn allocate_in(&self, segment: SegmentId) -> std::io::Result<PageNo>;
+    /// Returns the first of `n` contiguous zeroed pages.
+    fn allocate_pages(&self, n: PageNo) -> std::io::Result<PageNo>;
+    /// Marks a page as free for a later allocation.
+    fn deallocate(&self, page_no: PageNo) -> std::io::Result<()>;
+
+    /// Forces everything written so far to stable storage.
+    fn sync(&self) -> std::io::Result<()>;
+    /// Size of the storage in pages, including deallocated pages and ones
+    /// pre-extended but not handed out yet.
+    fn num_pages(&self) -> PageNo;
+    /// Size of every page, fixed for the lifetime of the storage.
+    fn page_size(&self) -> usize;
+
+    /// Reads a batch of pages. Results are returned per page, in order.
+    fn read_pages(&self, reads: &mut [(PageNo, &mut [u8])]) -> Vec<std::io::Result<()>> {
+        reads
+            .iter_mut()
+            .map(|(page_no, data)| self.read_page(*page_no, data))
+            .collect()
+    }
+
+    /// Writes a batch of pages. Results are returned per page, in order.
+    fn write_pages(&self, writes: &[(PageNo, &[u8])]) -> Vec<std::io::Result<()>> {
+        writes
+            .iter()
+            .map(|(page_no, data)| self.write_page(*page_no, data))
+            .collect()
+    }
+}
@@ -0,0 +1,502 @@
+use crate::define::PageNo;
+use crate::page::PageData;
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{Tag, XChaCha20Poly1305, XNonce};
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind};
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Size of one entry of the tag file: the current version (u64 write
+/// counter, 16-byte tag, u8 flags), a u8 saying whether a previous version
+/// follows, padding, then the previous version at `VERSION_SIZE * 2`.
+const TAG_ENTRY_SIZE: u64 = 64;
+
+const VERSION_SIZE: usize = 32;
+
+/// Version flag: the page holds data written since it was allocated.
+const ENTRY_WRITTEN: u8 = 1;
+
+/// Counter of the key-check nonce. Page writes never get this far.
+const KEY_CHECK: u64 = u64::MAX;
+
+/// Counters reserved at a time, so the high-water mark is only rewritten
+/// once every this many page writes.
+const COUNTER_BLOCK: u64 = 1 << 16;
+
+/// 256-bit key pages are encrypted with. Its `Debug` output leaves the key
+/// out.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct EncryptionKey(pub [u8; 32]);
+
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+/// Counter and tag one version of a page was sealed with.
+#[derive(Clone, Copy, Default)]
+struct TagVersion {
+    counter: u64,
+    tag: [u8; 16],
+    flags: u8,
+}
+
+/// The tags of one page. `previous` is set while a write of `current` may
+/// not have reached the data file, so a read can still authenticate the
+/// version it replaces.
+#[derive(Clone, Copy, Default)]
+struct TagEntry {
+    current: TagVersion,
+    previous: Option<TagVersion>,
+}
+
+impl TagEntry {
+    fn encode(&self) -> [u8; TAG_ENTRY_SIZE as usize] {
+        let mut entry = [0; TAG_ENTRY_SIZE as usize];
+        let mut put = |at: usize, version: &TagVersion| {
+            entry[at..at + 8].copy_from_slice(&version.counter.to_le_bytes());
+            entry[at + 8..at + 24].copy_from_slice(&version.tag);
+            entry[at + 24] = version.flags;
+        };
+        put(0, &self.current);
+        if let Some(previous) = &self.previous {
+            put(VERSION_SIZE, previous);
+            entry[25] = 1;
+        }
+        entry
+    }
+
+    fn decode(entry: &[u8]) -> Self {
+        let get = |at: usize| TagVersion {
+            counter: u64::from_le_bytes(entry[at..at + 8].try_into().unwrap()),
+            tag: entry[at + 8..at + 24].try_into().unwrap(),
+            flags: entry[at + 24],
+        };
+        Self {
+            current: get(0),
+            previous: (entry[25] != 0).then(|| get(VERSION_SIZE)),
+        }
+    }
+}
+
+/// A page encrypted by `PageCipher::seal`. It goes through `stage` before
+/// the ciphertext is written and `commit` after.
+pub(crate) struct Sealed {
+    pub(crate) data: PageData,
+    version: TagVersion,
+}
+
+/// Authenticated encryption of the pages of one `DSMgr` file.
+///
+/// Pages are encrypted with XChaCha20-Poly1305. The nonce is the file's
+/// salt, the page number and a write counter that grows with every page
+/// write, so no nonce is used twice under one key, even across files. Each
+/// page's counter and tag live in a side file, `<data file>.tags`, keeping
+/// pages their normal size on disk. Its first entry is a key check, so a
+/// wrong key is reported at open rather than as corruption on every read,
+/// and holds the counter high-water mark: counters are reserved in blocks
+/// made durable before any of them reaches the data file, so a crash never
+/// hands out a used counter again.
+///
+/// A page's new tag is made durable next to the old one before its
+/// ciphertext is written, and the old one is dropped once the write is
+/// done. After a crash in between, the version actually on disk is found
+/// at open. A torn page write is not repaired and fails authentication.
+///
+/// A page moved to another page number or file, or modified in place,
+/// fails authentication on read. Rolling a page back to an older version
+/// together with its tag entry is not detected.
+pub(crate) struct PageCipher {
+    cipher: XChaCha20Poly1305,
+    salt: u64,
+    page_size: usize,
+    tags: File,
+    entries: Mutex<Vec<TagEntry>>,
+    next_counter: AtomicU64,
+    /// Counters below this are reserved in the tag file. Only raised with
+    /// the mutex held, after the new mark is written.
+    reserved: Mutex<u64>,
+}
+
+impl PageCipher {
+    /// Opens the tag file of `filename`, whose pages are `page_size` bytes.
+    /// Pages whose last write may not have completed are read through
+    /// `read_raw` to find the version on disk.
+    pub(crate) fn open<F>(
+        filename: &str,
+        key: &EncryptionKey,
+        salt: u64,
+        page_size: usize,
+        read_raw: F,
+    ) -> std::io::Result<Self>
+    where
+        F: Fn(PageNo, &mut [u8]) -> std::io::Result<()>,
+    {
+        let tags = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(format!("{}.tags", filename))?;
+        let len = tags.metadata()?.len();
+        let mut raw = vec![0; (len - len % TAG_ENTRY_SIZE) as usize];
+        tags.read_exact_at(&mut raw, 0)?;
+        let mut entries: Vec<TagEntry> = raw
+            .chunks_exact(TAG_ENTRY_SIZE as usize)
+            .map(TagEntry::decode)
+            .collect();
+
+        let cipher = Self {
+            cipher: XChaCha20Poly1305::new(&key.0.into()),
+            salt,
+            page_size,
+            tags,
+            entries: Mutex::new(Vec::new()),
+            next_counter: AtomicU64::new(0),
+            reserved: Mutex::new(0),
+        };
+        let reserved = if entries.is_empty() {
+            cipher.write_mark(1)?;
+            cipher.tags.sync_all()?;
+            1
+        } else {
+            let check = entries.remove(0).current;
+            if check.tag != cipher.key_check() {
+                return Err(Error::new(ErrorKind::InvalidInput, "wrong encryption key"));
+            }
+            check.counter
+        };
+        // Counters below the mark may have been used before a crash.
+        cipher.next_counter.store(reserved, Ordering::SeqCst);
+        *cipher.reserved.lock().unwrap() = reserved;
+        *cipher.entries.lock().unwrap() = entries;
+
+        let pending: Vec<PageNo> = cipher
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.previous.is_some())
+            .map(|(page_no, _)| page_no as PageNo)
+            .collect();
+        for &page_no in &pending {
+            cipher.resolve(page_no, &read_raw)?;
+        }
+        if !pending.is_empty() {
+            cipher.tags.sync_all()?;
+        }
+        Ok(cipher)
+    }
+
+    /// Encrypts `data` for `page_no` under a fresh counter. The result goes
+    /// to disk in place of the plaintext.
+    pub(crate) fn seal(&self, page_no: PageNo, data: &[u8]) -> Sealed {
+        let counter = self.next_counter.fetch_add(1, Ordering::SeqCst);
+        let mut sealed = PageData::from(data);
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(&self.nonce(page_no, counter), &[], &mut sealed)
+            .expect("pages are far below the cipher's message size limit");
+        Sealed {
+            data: sealed,
+            version: TagVersion {
+                counter,
+                tag: tag.into(),
+                flags: ENTRY_WRITTEN,
+            },
+        }
+    }
+
+    /// Makes the tags of sealed pages durable next to the tags of the
+    /// versions they replace, and their counters reserved. Must return
+    /// before the ciphertexts are written. A page whose previous write
+    /// never completed is first read through `read_raw` to find which
+    /// version it holds.
+    pub(crate) fn stage<F>(&self, sealed: &[(PageNo, &Sealed)], read_raw: F) -> std::io::Result<()>
+    where
+        F: Fn(PageNo, &mut [u8]) -> std::io::Result<()>,
+    {
+        for (page_no, sealed) in sealed {
+            if self.entry(*page_no).previous.is_some() {
+                self.resolve(*page_no, &read_raw)?;
+            }
+            let entry = TagEntry {
+                current: sealed.version,
+                previous: Some(self.entry(*page_no).current),
+            };
+            self.set_entry(*page_no, entry)?;
+        }
+        if let Some(counter) = sealed.iter().map(|(_, sealed)| sealed.version.counter).max() {
+            let mut reserved = self.reserved.lock().unwrap();
+            if counter >= *reserved {
+                self.write_mark(counter + COUNTER_BLOCK)?;
+                *reserved = counter + COUNTER_BLOCK;
+            }
+        }
+        // Also covers a mark written by a concurrent batch whose counters
+        // this one shares a block with.
+        self.tags.sync_all()
+    }
+
+    /// Drops the previous tag of a staged page whose ciphertext has been
+    /// written. Not synced: until it is, reads still try both tags.
+    pub(crate) fn commit(&self, page_no: PageNo, sealed: &Sealed) -> std::io::Result<()> {
+        self.set_entry(
+            page_no,
+            TagEntry {
+                current: sealed.version,
+                previous: None,
+            },
+        )
+    }
+
+    /// Decrypts `data` as read from disk for `page_no`, in place. A page
+    /// never written since allocation reads as zeros.
+    pub(crate) fn open_page(&self, page_no: PageNo, data: &mut [u8]) -> std::io::Result<()> {
+        let entry = self.entry(page_no);
+        let opened = match entry.previous {
+            None => self.open_version(page_no, &entry.current, data),
+            // A write that may not have completed: the page holds either
+            // version. Failed attempts leave `data` as it was.
+            Some(previous) => self
+                .open_version(page_no, &entry.current, data)
+                .or_else(|_| self.open_version(page_no, &previous, data)),
+        };
+        opened.map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("page {} failed authentication", page_no),
+            )
+        })
+    }
+
+    /// Makes a page read as zeros again. Its counter is kept so the next
+    /// write still gets a fresh nonce.
+    pub(crate) fn clear(&self, page_no: PageNo) -> std::io::Result<()> {
+        let entry = self.entry(page_no);
+        self.set_entry(
+            page_no,
+            TagEntry {
+                current: TagVersion {
+                    flags: 0,
+                    ..entry.current
+                },
+                previous: None,
+            },
+        )
+    }
+
+    pub(crate) fn sync(&self) -> std::io::Result<()> {
+        self.tags.sync_all()
+    }
+
+    fn entry(&self, page_no: PageNo) -> TagEntry {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(page_no as usize)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn set_entry(&self, page_no: PageNo, entry: TagEntry) -> std::io::Result<()> {
+        {
+            let mut entries = self.entries.lock().unwrap();
+            let index = page_no as usize;
+            if index >= entries.len() {
+                entries.resize(index + 1, TagEntry::default());
+            }
+            entries[index] = entry;
+        }
+        self.tags.write_all_at(&entry.encode(), (page_no + 1) * TAG_ENTRY_SIZE)
+    }
+
+    /// Keeps only the version of a page with an unfinished write that its
+    /// data on disk authenticates as. A page matching neither, torn by the
+    /// write, is left to fail authentication on read.
+    fn resolve<F>(&self, page_no: PageNo, read_raw: &F) -> std::io::Result<()>
+    where
+        F: Fn(PageNo, &mut [u8]) -> std::io::Result<()>,
+    {
+        let entry = self.entry(page_no);
+        let Some(previous) = entry.previous else {
+            return Ok(());
+        };
+        let mut data = PageData::zeroed(self.page_size);
+        read_raw(page_no, &mut data)?;
+        let opens = |version: &TagVersion| self.open_version(page_no, version, &mut data.clone()).is_ok();
+        let current = if opens(&entry.current) {
+            entry.current
+        } else if opens(&previous) {
+            previous
+        } else {
+            return Ok(());
+        };
+        self.set_entry(page_no, TagEntry { current, previous: None })
+    }
+
+    fn open_version(&self, page_no: PageNo, version: &TagVersion, data: &mut [u8]) -> Result<(), ()> {
+        if version.flags & ENTRY_WRITTEN == 0 {
+            data.fill(0);
+            return Ok(());
+        }
+        self.cipher
+            .decrypt_in_place_detached(
+                &self.nonce(page_no, version.counter),
+                &[],
+                data,
+                Tag::from_slice(&version.tag),
+            )
+            .map_err(|_| ())
+    }
+
+    /// Writes the key-check entry with `mark` as the counter high-water
+    /// mark.
+    fn write_mark(&self, mark: u64) -> std::io::Result<()> {
+        let entry = TagEntry {
+            current: TagVersion {
+                counter: mark,
+                tag: self.key_check(),
+                flags: 0,
+            },
+            previous: None,
+        };
+        self.tags.write_all_at(&entry.encode(), 0)
+    }
+
+    /// Tag of the empty message under the reserved key-check nonce.
+    fn key_check(&self) -> [u8; 16] {
+        self.cipher
+            .encrypt_in_place_detached(&self.nonce(KEY_CHECK, KEY_CHECK), &[], &mut [])
+            .expect("an empty message can always be encrypted")
+            .into()
+    }
+
+    fn nonce(&self, page_no: PageNo, counter: u64) -> XNonce {
+        let mut nonce = XNonce::default();
+        nonce[..8].copy_from_slice(&self.salt.to_le_bytes());
+        nonce[8..16].copy_from_slice(&page_no.to_le_bytes());
+        nonce[16..].copy_from_slice(&counter.to_le_bytes());
+        nonce
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::define::PAGE_SIZE;
+    use std::collections::HashMap;
+
+    const KEY: EncryptionKey = EncryptionKey([7; 32]);
+
+    /// A data file as a map from page number to the bytes on disk.
+    type Disk = Mutex<HashMap<PageNo, PageData>>;
+
+    fn temp_name(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("adbs-cipher-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(format!("{}.tags", path.display()));
+        path.to_str().unwrap().to_owned()
+    }
+
+    fn open(name: &str, disk: &Disk) -> std::io::Result<PageCipher> {
+        PageCipher::open(name, &KEY, 1, PAGE_SIZE, |page_no, data| {
+            data.copy_from_slice(&disk.lock().unwrap()[&page_no]);
+            Ok(())
+        })
+    }
+
+    /// Seals and stages `byte` for `page_no`, then writes the ciphertext
+    /// and commits only as far as asked.
+    fn write(cipher: &PageCipher, disk: &Disk, page_no: PageNo, byte: u8, land: bool, commit: bool) -> u64 {
+        let sealed = cipher.seal(page_no, &[byte; PAGE_SIZE]);
+        cipher
+            .stage(&[(page_no, &sealed)], |page_no, data| {
+                data.copy_from_slice(&disk.lock().unwrap()[&page_no]);
+                Ok(())
+            })
+            .unwrap();
+        if land {
+            disk.lock().unwrap().insert(page_no, sealed.data.clone());
+        }
+        if commit {
+            cipher.commit(page_no, &sealed).unwrap();
+        }
+        sealed.version.counter
+    }
+
+    fn read(cipher: &PageCipher, disk: &Disk, page_no: PageNo) -> std::io::Result<u8> {
+        let mut data = disk.lock().unwrap()[&page_no].clone();
+        cipher.open_page(page_no, &mut data)?;
+        assert!(data.iter().all(|&b| b == data[0]));
+        Ok(data[0])
+    }
+
+    #[test]
+    fn pages_round_trip_and_tampering_is_detected() {
+        let name = temp_name("tamper");
+        let disk = Disk::default();
+        let cipher = open(&name, &disk).unwrap();
+        disk.lock().unwrap().insert(0, PageData::zeroed(PAGE_SIZE));
+        assert_eq!(read(&cipher, &disk, 0).unwrap(), 0, "unwritten pages read as zeros");
+        write(&cipher, &disk, 0, 5, true, true);
+        write(&cipher, &disk, 1, 6, true, true);
+        assert_eq!(read(&cipher, &disk, 0).unwrap(), 5);
+
+        disk.lock().unwrap().get_mut(&0).unwrap()[100] ^= 1;
+        assert_eq!(read(&cipher, &disk, 0).unwrap_err().kind(), ErrorKind::InvalidData);
+        // A page copied over another does not authenticate there.
+        let copy = disk.lock().unwrap()[&1].clone();
+        disk.lock().unwrap().insert(0, copy);
+        assert_eq!(read(&cipher, &disk, 0).unwrap_err().kind(), ErrorKind::InvalidData);
+        drop(cipher);
+
+        let wrong = PageCipher::open(&name, &EncryptionKey([8; 32]), 1, PAGE_SIZE, |_, _| Ok(()));
+        assert_eq!(wrong.err().unwrap().kind(), ErrorKind::InvalidInput);
+        let _ = std::fs::remove_file(format!("{}.tags", name));
+    }
+
+    #[test]
+    fn interrupted_writes_read_as_whichever_version_is_on_disk() {
+        let name = temp_name("interrupted");
+        let disk = Disk::default();
+        let cipher = open(&name, &disk).unwrap();
+        write(&cipher, &disk, 0, 1, true, true);
+        write(&cipher, &disk, 1, 1, true, true);
+        // Crash with page 0's new tag staged but its ciphertext not written,
+        // and page 1's ciphertext written but not committed.
+        write(&cipher, &disk, 0, 2, false, false);
+        write(&cipher, &disk, 1, 2, true, false);
+        assert_eq!(read(&cipher, &disk, 0).unwrap(), 1);
+        assert_eq!(read(&cipher, &disk, 1).unwrap(), 2);
+        drop(cipher);
+
+        let cipher = open(&name, &disk).unwrap();
+        assert_eq!(read(&cipher, &disk, 0).unwrap(), 1);
+        assert_eq!(read(&cipher, &disk, 1).unwrap(), 2);
+        assert!(cipher.entries.lock().unwrap().iter().all(|entry| entry.previous.is_none()));
+        // The next write of page 0 replaces the version that is on disk.
+        write(&cipher, &disk, 0, 3, false, false);
+        assert_eq!(read(&cipher, &disk, 0).unwrap(), 1);
+        let _ = std::fs::remove_file(format!("{}.tags", name));
+    }
+
+    #[test]
+    fn counters_are_not_reused_after_a_crash() {
+        let name = temp_name("counters");
+        let disk = Disk::default();
+        let cipher = open(&name, &disk).unwrap();
+        write(&cipher, &disk, 0, 1, true, true);
+        // Its tag never reaches the tag file, so the counter is nowhere but
+        // in the reserved block.
+        let sealed = cipher.seal(1, &[2; PAGE_SIZE]);
+        drop(cipher);
+
+        let cipher = open(&name, &disk).unwrap();
+        assert!(write(&cipher, &disk, 1, 3, true, true) > sealed.version.counter);
+        let _ = std::fs::remove_file(format!("{}.tags", name));
+    }
+}
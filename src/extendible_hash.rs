@@ -0,0 +1,672 @@
+use crate::buffer_pool_manager::{BufferPoolManager, PinnedPage};
+use crate::checksum::crc32;
+use crate::define::{FileId, PageId, PageNo};
+use crate::disk_manager::DiskManager;
+use crate::heap_file::{RecordId, RECORD_ID_SIZE};
+use crate::page::{Page, PageType, PAGE_HEADER_SIZE};
+use std::io::{Error, ErrorKind};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, RwLockWriteGuard};
+
+/// The directory page, first page of the index's file.
+const DIRECTORY_PAGE_NO: PageNo = 0;
+
+/// In the directory page after the page header: u32 key size, u32 global
+/// depth, then the bucket page of every slot as u64. The local depth of
+/// every slot follows as u8, after room for as many slots as the page can
+/// take, so neither array moves when the directory doubles.
+const DIR_KEY_SIZE_OFFSET: usize = PAGE_HEADER_SIZE;
+const DIR_GLOBAL_DEPTH_OFFSET: usize = PAGE_HEADER_SIZE + 4;
+const DIR_SLOTS_OFFSET: usize = PAGE_HEADER_SIZE + 8;
+
+/// Bytes of a directory slot: its bucket page and local depth.
+const DIR_SLOT_SIZE: usize = 9;
+
+/// In a bucket after the page header: u32 number of entries, then the
+/// entries, each a key followed by a record id, in no particular order.
+const BUCKET_LEN_OFFSET: usize = PAGE_HEADER_SIZE;
+const BUCKET_ENTRIES_OFFSET: usize = PAGE_HEADER_SIZE + 4;
+
+/// Fewest entries a bucket must have room for.
+const MIN_BUCKET_CAPACITY: usize = 4;
+
+/// Extendible hash index mapping fixed-size keys to record ids, stored in
+/// one data file of the pool: its directory page first, then the buckets.
+/// Keys are unique and compared byte by byte; there is no order among
+/// them, so the index answers point lookups only.
+///
+/// A key goes to the bucket of the directory slot numbered by the lowest
+/// `global depth` bits of its hash. A bucket with local depth `d` holds
+/// every key whose hash ends in the same `d` bits, so it is shared by the
+/// `2^(global - d)` slots ending in them. A full bucket is split on the
+/// next bit, doubling the directory first if its local depth was the
+/// global one; an emptied bucket is merged back into its split image, and
+/// the directory halves once no bucket needs its last bit. Every lookup
+/// thus reads the directory and a single bucket, each a page fetched
+/// through `BufferPoolManager::fix_page`. The directory has to fit in its
+/// page, which bounds the global depth; inserting into a full bucket that
+/// cannot be split any more fails with `StorageFull`.
+///
+/// Any number of threads may use the index at once. Lookups latch the
+/// directory shared and then the bucket, releasing the directory before
+/// reading it; inserts and deletes do the same with the bucket latched
+/// exclusively. Splits and merges latch the directory exclusively, which
+/// keeps everyone else away from the buckets they touch.
+///
+/// Like `BPlusTree`, the index is not crash-safe: changes are not logged
+/// and only durable once `flush` returns.
+pub struct ExtendibleHashIndex<D: DiskManager> {
+    bmgr: Arc<BufferPoolManager<D>>,
+    file_id: FileId,
+    key_size: usize,
+    /// Largest global depth the directory page has room for.
+    max_depth: u32,
+}
+
+impl<D: DiskManager> ExtendibleHashIndex<D> {
+    /// Opens the index stored in `file_id`, which must already be in the
+    /// pool; an empty file becomes an empty index with keys of `key_size`
+    /// bytes. An existing index must have been created with the same key
+    /// size.
+    pub fn open(bmgr: Arc<BufferPoolManager<D>>, file_id: FileId, key_size: usize) -> std::io::Result<Self> {
+        let page_size = bmgr.get_page_size();
+        if key_size == 0 || (page_size - BUCKET_ENTRIES_OFFSET) / (key_size + RECORD_ID_SIZE) < MIN_BUCKET_CAPACITY {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("keys of {} bytes do not suit {}-byte pages", key_size, page_size),
+            ));
+        }
+        let num_pages = bmgr
+            .get_num_pages(file_id)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("no file {} in the pool", file_id)))?;
+        if num_pages == 0 {
+            bmgr.tablespace().allocate(file_id)?;
+        }
+        let index = Self {
+            bmgr,
+            file_id,
+            key_size,
+            max_depth: ((page_size - DIR_SLOTS_OFFSET) / DIR_SLOT_SIZE).ilog2(),
+        };
+
+        let dir_pin = index.fix(DIRECTORY_PAGE_NO)?;
+        let page_type = dir_pin.read().get_page_type();
+        match page_type {
+            Some(PageType::HashDirectory) => {
+                let dir = index.directory(dir_pin.read())?;
+                if dir.key_size() != key_size {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("the hash index in file {} has keys of {} bytes", file_id, dir.key_size()),
+                    ));
+                }
+            }
+            Some(PageType::Unformatted) => {
+                let bucket = index.allocate_bucket()?;
+                let mut page = dir_pin.write();
+                page.init(PageType::HashDirectory);
+                page.set_dirty(true);
+                page.get_data_mut()[DIR_KEY_SIZE_OFFSET..][..4].copy_from_slice(&(key_size as u32).to_le_bytes());
+                let mut dir = index.directory(page)?;
+                dir.set_slot(0, bucket.pin.page_id.page_no, 0);
+            }
+            page_type => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("page {} should be a hash directory page, found {:?}", dir_pin.page_id, page_type),
+                ))
+            }
+        }
+        drop(dir_pin);
+        Ok(index)
+    }
+
+    /// The record id stored under `key`, if any.
+    pub fn get(&self, key: &[u8]) -> std::io::Result<Option<RecordId>> {
+        self.check_key(key)?;
+        let bucket = self.lock_bucket(hash(key), PinnedPage::read)?;
+        Ok(bucket.bucket.find(key).map(|i| bucket.bucket.record_id(i)))
+    }
+
+    /// Stores `rid` under `key`. Fails with `AlreadyExists` if the key is
+    /// taken.
+    pub fn insert(&self, key: &[u8], rid: RecordId) -> std::io::Result<()> {
+        self.check_key(key)?;
+        let hash = hash(key);
+        let entry = [key, &rid.encode()].concat();
+        {
+            let mut bucket = self.lock_bucket(hash, PinnedPage::write)?;
+            self.check_absent(&bucket.bucket, key)?;
+            if bucket.bucket.len() < bucket.bucket.capacity() {
+                bucket.bucket.push(&entry);
+                return Ok(());
+            }
+        }
+
+        // The bucket has to be split: start over with the directory latched
+        // exclusively. Splitting may leave every entry on one side, so it
+        // goes on until the key's bucket has room.
+        let dir_pin = self.fix(DIRECTORY_PAGE_NO)?;
+        let mut dir = self.directory(dir_pin.write())?;
+        loop {
+            let slot = dir.slot(hash);
+            let pin = self.fix(dir.bucket(slot))?;
+            let mut bucket = Bucket::new(pin.write(), self.key_size)?;
+            self.check_absent(&bucket, key)?;
+            if bucket.len() < bucket.capacity() {
+                bucket.push(&entry);
+                return Ok(());
+            }
+            self.split(&mut dir, slot, &mut bucket)?;
+        }
+    }
+
+    /// Removes `key` and its record id. Fails with `NotFound` if there is
+    /// no such key.
+    pub fn delete(&self, key: &[u8]) -> std::io::Result<()> {
+        self.check_key(key)?;
+        let hash = hash(key);
+        {
+            let mut bucket = self.lock_bucket(hash, PinnedPage::write)?;
+            let i = bucket
+                .bucket
+                .find(key)
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, "the key is not in the index"))?;
+            bucket.bucket.remove(i);
+            if bucket.bucket.len() > 0 {
+                return Ok(());
+            }
+        }
+        self.merge(hash)
+    }
+
+    /// Writes back the directory and every changed bucket and syncs the
+    /// index's file.
+    pub fn flush(&self) -> std::io::Result<()> {
+        self.bmgr.flush_file(self.file_id).map(|_| ())
+    }
+
+    pub fn get_file_id(&self) -> FileId {
+        self.file_id
+    }
+
+    pub fn get_key_size(&self) -> usize {
+        self.key_size
+    }
+
+    /// Number of hash bits the directory currently tells buckets apart by.
+    pub fn get_global_depth(&self) -> std::io::Result<u32> {
+        let dir_pin = self.fix(DIRECTORY_PAGE_NO)?;
+        let global_depth = self.directory(dir_pin.read())?.global_depth();
+        Ok(global_depth)
+    }
+
+    fn check_key(&self, key: &[u8]) -> std::io::Result<()> {
+        if key.len() != self.key_size {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("keys of this index are {} bytes, got {}", self.key_size, key.len()),
+            ));
+        }
+        Ok(())
+    }
+
+    fn check_absent<P: Deref<Target = Page>>(&self, bucket: &Bucket<P>, key: &[u8]) -> std::io::Result<()> {
+        match bucket.find(key) {
+            Some(_) => Err(Error::new(ErrorKind::AlreadyExists, "the key is already in the index")),
+            None => Ok(()),
+        }
+    }
+
+    fn fix(&self, page_no: PageNo) -> std::io::Result<PinnedPage<'_, D>> {
+        PinnedPage::fix(&self.bmgr, PageId::new(self.file_id, page_no))
+    }
+
+    fn directory<P: Deref<Target = Page>>(&self, page: P) -> std::io::Result<Directory<P>> {
+        Directory::new(page, self.max_depth)
+    }
+
+    /// Latches the directory shared to find the bucket for `hash`, latches
+    /// that with `latch` and releases the directory.
+    fn lock_bucket<'a, G: Deref<Target = Page>>(
+        &'a self,
+        hash: u32,
+        latch: impl FnOnce(&PinnedPage<'a, D>) -> G,
+    ) -> std::io::Result<LatchedBucket<'a, D, G>> {
+        let dir_pin = self.fix(DIRECTORY_PAGE_NO)?;
+        let dir = self.directory(dir_pin.read())?;
+        let pin = self.fix(dir.bucket(dir.slot(hash)))?;
+        let bucket = Bucket::new(latch(&pin), self.key_size)?;
+        drop(dir);
+        Ok(LatchedBucket { bucket, pin })
+    }
+
+    /// Allocates a page and formats it as an empty bucket.
+    fn allocate_bucket(&self) -> std::io::Result<LatchedBucket<'_, D, RwLockWriteGuard<'_, Page>>> {
+        let pin = PinnedPage::fix_new(&self.bmgr, self.file_id)?;
+        Ok(LatchedBucket {
+            bucket: Bucket::init(pin.write(), self.key_size),
+            pin,
+        })
+    }
+
+    /// Splits the full `bucket` of directory slot `slot` on the next hash
+    /// bit, moving the entries with that bit set to a new bucket. Doubles
+    /// the directory first if the bucket already uses all its bits.
+    fn split<P: DerefMut<Target = Page>, B: DerefMut<Target = Page>>(
+        &self,
+        dir: &mut Directory<P>,
+        slot: usize,
+        bucket: &mut Bucket<B>,
+    ) -> std::io::Result<()> {
+        let local_depth = dir.local_depth(slot);
+        if local_depth == dir.global_depth() {
+            if local_depth == self.max_depth {
+                return Err(Error::new(
+                    ErrorKind::StorageFull,
+                    format!("the directory of the hash index in file {} cannot grow any more", self.file_id),
+                ));
+            }
+            dir.grow();
+        }
+
+        let mut image = self.allocate_bucket()?;
+        let bit = 1 << local_depth;
+        let mut i = 0;
+        while i < bucket.len() {
+            if hash(bucket.key(i)) & bit != 0 {
+                image.bucket.push(bucket.entry(i));
+                bucket.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+
+        let (old_page_no, image_page_no) = (dir.bucket(slot), image.pin.page_id.page_no);
+        let suffix = slot & (bit as usize - 1);
+        for s in (suffix..dir.num_slots()).step_by(bit as usize) {
+            let page_no = if s & bit as usize != 0 { image_page_no } else { old_page_no };
+            dir.set_slot(s, page_no, local_depth + 1);
+        }
+        Ok(())
+    }
+
+    /// Merges the bucket for `hash` with its split image as long as one of
+    /// them is empty and both use the same bits, then halves the directory
+    /// as far as it can.
+    fn merge(&self, hash: u32) -> std::io::Result<()> {
+        let dir_pin = self.fix(DIRECTORY_PAGE_NO)?;
+        let mut dir = self.directory(dir_pin.write())?;
+        loop {
+            let slot = dir.slot(hash);
+            let local_depth = dir.local_depth(slot);
+            if local_depth == 0 {
+                break;
+            }
+            let image_slot = slot ^ (1 << (local_depth - 1));
+            if dir.local_depth(image_slot) != local_depth {
+                break;
+            }
+
+            // No other thread can reach either bucket while the directory is
+            // latched exclusively, so their latches are only waited for by
+            // this one, and neither changes once released.
+            let (page_no, image_page_no) = (dir.bucket(slot), dir.bucket(image_slot));
+            let pin = self.fix(page_no)?;
+            let image_pin = self.fix(image_page_no)?;
+            let (is_empty, is_image_empty) = {
+                let bucket = Bucket::new(pin.read(), self.key_size)?;
+                let image = Bucket::new(image_pin.read(), self.key_size)?;
+                (bucket.len() == 0, image.len() == 0)
+            };
+            let (kept, freed) = match (is_empty, is_image_empty) {
+                (true, _) => (image_page_no, pin.page_id),
+                (false, true) => (page_no, image_pin.page_id),
+                (false, false) => break,
+            };
+            // Unpinned first, so the pool frees the page right away unless
+            // a lookup that just let go of its latch still pins it.
+            drop((pin, image_pin));
+
+            let suffix = slot & ((1 << (local_depth - 1)) - 1);
+            for s in (suffix..dir.num_slots()).step_by(1 << (local_depth - 1)) {
+                dir.set_slot(s, kept, local_depth - 1);
+            }
+            self.bmgr.deallocate_page(freed)?;
+        }
+
+        while dir.global_depth() > 0 && (0..dir.num_slots()).all(|s| dir.local_depth(s) < dir.global_depth()) {
+            dir.shrink();
+        }
+        Ok(())
+    }
+}
+
+/// The hash of a key. Stored nowhere, but it decides where keys live, so
+/// it must never change.
+fn hash(key: &[u8]) -> u32 {
+    crc32(key)
+}
+
+/// A pinned and latched bucket. The latch is released before the pin, as
+/// fields are dropped in order.
+struct LatchedBucket<'a, D: DiskManager, G> {
+    bucket: Bucket<G>,
+    pin: PinnedPage<'a, D>,
+}
+
+/// The directory in its page. Modifying it marks the page dirty.
+struct Directory<P> {
+    page: P,
+    max_depth: u32,
+}
+
+impl<P: Deref<Target = Page>> Directory<P> {
+    fn new(page: P, max_depth: u32) -> std::io::Result<Self> {
+        match page.get_page_type() {
+            Some(PageType::HashDirectory) => Ok(Self { page, max_depth }),
+            page_type => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("expected a hash directory, found {:?}", page_type),
+            )),
+        }
+    }
+
+    fn key_size(&self) -> usize {
+        self.u32_at(DIR_KEY_SIZE_OFFSET) as usize
+    }
+
+    fn global_depth(&self) -> u32 {
+        self.u32_at(DIR_GLOBAL_DEPTH_OFFSET)
+    }
+
+    fn num_slots(&self) -> usize {
+        1 << self.global_depth()
+    }
+
+    /// The slot of keys with `hash`.
+    fn slot(&self, hash: u32) -> usize {
+        hash as usize & (self.num_slots() - 1)
+    }
+
+    fn bucket(&self, slot: usize) -> PageNo {
+        PageNo::from_le_bytes(self.page.get_data()[DIR_SLOTS_OFFSET + slot * 8..][..8].try_into().unwrap())
+    }
+
+    fn local_depth(&self, slot: usize) -> u32 {
+        self.page.get_data()[self.depths_offset() + slot] as u32
+    }
+
+    fn depths_offset(&self) -> usize {
+        DIR_SLOTS_OFFSET + (8 << self.max_depth)
+    }
+
+    fn u32_at(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.page.get_data()[offset..][..4].try_into().unwrap())
+    }
+}
+
+impl<P: DerefMut<Target = Page>> Directory<P> {
+    fn set_global_depth(&mut self, global_depth: u32) {
+        self.page.set_dirty(true);
+        self.page.get_data_mut()[DIR_GLOBAL_DEPTH_OFFSET..][..4].copy_from_slice(&global_depth.to_le_bytes());
+    }
+
+    fn set_slot(&mut self, slot: usize, page_no: PageNo, local_depth: u32) {
+        let depths_offset = self.depths_offset();
+        self.page.set_dirty(true);
+        let data = self.page.get_data_mut();
+        data[DIR_SLOTS_OFFSET + slot * 8..][..8].copy_from_slice(&page_no.to_le_bytes());
+        data[depths_offset + slot] = local_depth as u8;
+    }
+
+    /// Doubles the directory, the new upper half pointing to the same
+    /// buckets as the lower one.
+    fn grow(&mut self) {
+        let num_slots = self.num_slots();
+        for slot in 0..num_slots {
+            self.set_slot(num_slots + slot, self.bucket(slot), self.local_depth(slot));
+        }
+        self.set_global_depth(self.global_depth() + 1);
+    }
+
+    /// Halves the directory; its upper half must mirror the lower one.
+    fn shrink(&mut self) {
+        self.set_global_depth(self.global_depth() - 1);
+    }
+}
+
+/// A bucket in its page. Modifying it marks the page dirty.
+struct Bucket<P> {
+    page: P,
+    key_size: usize,
+}
+
+impl<P: Deref<Target = Page>> Bucket<P> {
+    fn new(page: P, key_size: usize) -> std::io::Result<Self> {
+        match page.get_page_type() {
+            Some(PageType::HashBucket) => Ok(Self { page, key_size }),
+            page_type => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("expected a hash bucket, found {:?}", page_type),
+            )),
+        }
+    }
+
+    fn len(&self) -> usize {
+        u32::from_le_bytes(self.page.get_data()[BUCKET_LEN_OFFSET..][..4].try_into().unwrap()) as usize
+    }
+
+    fn entry_size(&self) -> usize {
+        self.key_size + RECORD_ID_SIZE
+    }
+
+    fn capacity(&self) -> usize {
+        (self.page.get_data().len() - BUCKET_ENTRIES_OFFSET) / self.entry_size()
+    }
+
+    fn entry(&self, i: usize) -> &[u8] {
+        let entry_size = self.entry_size();
+        &self.page.get_data()[BUCKET_ENTRIES_OFFSET + i * entry_size..][..entry_size]
+    }
+
+    fn key(&self, i: usize) -> &[u8] {
+        &self.entry(i)[..self.key_size]
+    }
+
+    fn record_id(&self, i: usize) -> RecordId {
+        RecordId::decode(&self.entry(i)[self.key_size..])
+    }
+
+    /// Index of the entry with `key`, if any.
+    fn find(&self, key: &[u8]) -> Option<usize> {
+        (0..self.len()).find(|&i| self.key(i) == key)
+    }
+}
+
+impl<P: DerefMut<Target = Page>> Bucket<P> {
+    fn init(mut page: P, key_size: usize) -> Self {
+        page.init(PageType::HashBucket);
+        page.set_dirty(true);
+        Self { page, key_size }
+    }
+
+    fn set_len(&mut self, len: usize) {
+        self.page.set_dirty(true);
+        self.page.get_data_mut()[BUCKET_LEN_OFFSET..][..4].copy_from_slice(&(len as u32).to_le_bytes());
+    }
+
+    /// Appends `entry`; the bucket must have room for it.
+    fn push(&mut self, entry: &[u8]) {
+        let len = self.len();
+        let at = BUCKET_ENTRIES_OFFSET + len * self.entry_size();
+        self.page.get_data_mut()[at..at + entry.len()].copy_from_slice(entry);
+        self.set_len(len + 1);
+    }
+
+    /// Removes entry `i`, moving the last entry into its place.
+    fn remove(&mut self, i: usize) {
+        let (len, entry_size) = (self.len(), self.entry_size());
+        let last = BUCKET_ENTRIES_OFFSET + (len - 1) * entry_size;
+        self.page
+            .get_data_mut()
+            .copy_within(last..last + entry_size, BUCKET_ENTRIES_OFFSET + i * entry_size);
+        self.set_len(len - 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer_pool_manager::ReplacePolicyType;
+    use crate::memory_disk_manager::MemDiskManager;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    /// Keys this large leave room for only a few entries per bucket, so
+    /// few keys make a deep directory.
+    const KEY_SIZE: usize = 200;
+
+    fn open(frame_num: usize) -> ExtendibleHashIndex<MemDiskManager> {
+        let bmgr = BufferPoolManager::with_disk_manager(MemDiskManager::new(), ReplacePolicyType::LRU, frame_num);
+        ExtendibleHashIndex::open(Arc::new(bmgr), 0, KEY_SIZE).unwrap()
+    }
+
+    fn key(k: u64) -> Vec<u8> {
+        let mut key = vec![0; KEY_SIZE];
+        key[..8].copy_from_slice(&k.to_le_bytes());
+        key
+    }
+
+    fn rid(k: u64) -> RecordId {
+        RecordId::new(PageId::new(1, k), (k % 1000) as u16)
+    }
+
+    #[test]
+    fn splits_deepen_the_directory_to_its_limit_and_merges_shrink_it() {
+        let index = open(16);
+        let mut inserted = Vec::new();
+        let mut depths = vec![index.get_global_depth().unwrap()];
+        for k in 0..100_000 {
+            match index.insert(&key(k), rid(k)) {
+                Ok(()) => inserted.push(k),
+                Err(e) if e.kind() == ErrorKind::StorageFull => break,
+                Err(e) => panic!("insert {}: {}", k, e),
+            }
+            let depth = index.get_global_depth().unwrap();
+            if depth != *depths.last().unwrap() {
+                depths.push(depth);
+            }
+        }
+        // The directory doubled one bit at a time until it filled its page.
+        assert_eq!(depths, (0..=index.max_depth).collect::<Vec<_>>());
+        assert_eq!(index.insert(&key(0), rid(0)).unwrap_err().kind(), ErrorKind::AlreadyExists);
+        for &k in &inserted {
+            assert_eq!(index.get(&key(k)).unwrap(), Some(rid(k)), "get {}", k);
+        }
+        let num_pages = index.bmgr.get_num_pages(0).unwrap();
+
+        for &k in &inserted {
+            index.delete(&key(k)).unwrap();
+        }
+        assert_eq!(index.get_global_depth().unwrap(), 0);
+        assert_eq!(index.delete(&key(0)).unwrap_err().kind(), ErrorKind::NotFound);
+
+        // The buckets merged away were freed and are used again.
+        for &k in &inserted {
+            index.insert(&key(k), rid(k)).unwrap();
+        }
+        assert_eq!(index.bmgr.get_num_pages(0).unwrap(), num_pages);
+    }
+
+    #[test]
+    fn concurrent_inserts_and_deletes_keep_every_key() {
+        const THREADS: u64 = 4;
+        // Few frames, so buckets keep getting evicted under the threads.
+        let index = Arc::new(open(24));
+        let threads: Vec<_> = (0..THREADS)
+            .map(|id| {
+                let index = Arc::clone(&index);
+                thread::spawn(move || {
+                    // Each thread owns the keys equal to its number modulo
+                    // the thread count, so it knows which of them exist.
+                    let mut stored = HashSet::new();
+                    for round in 0..3u64 {
+                        for i in 0..600 {
+                            let k = i * THREADS + id;
+                            if (i + round) % 3 == 0 {
+                                if stored.remove(&k) {
+                                    index.delete(&key(k)).unwrap();
+                                }
+                            } else if stored.insert(k) {
+                                index.insert(&key(k), rid(k)).unwrap();
+                            }
+                            let found = index.get(&key(k)).unwrap();
+                            assert_eq!(found, stored.contains(&k).then(|| rid(k)), "thread {}: get {}", id, k);
+                        }
+                    }
+                    stored
+                })
+            })
+            .collect();
+        let mut expected = HashSet::new();
+        for handle in threads {
+            expected.extend(handle.join().expect("worker panicked"));
+        }
+        for k in 0..600 * THREADS {
+            assert_eq!(index.get(&key(k)).unwrap(), expected.contains(&k).then(|| rid(k)), "get {}", k);
+        }
+        assert!(index.get_global_depth().unwrap() > 3);
+    }
+
+    #[test]
+    fn concurrent_merges_free_buckets_other_threads_pass_through() {
+        const WRITERS: u64 = 4;
+        const KEYS: u64 = 100;
+        let index = Arc::new(open(32));
+        let is_done = Arc::new(AtomicBool::new(false));
+        // Readers keep looking keys up, so buckets are merged away while
+        // a lookup still has them pinned.
+        let readers: Vec<_> = (0..4)
+            .map(|seed| {
+                let (index, is_done) = (Arc::clone(&index), Arc::clone(&is_done));
+                thread::spawn(move || {
+                    let mut rng = StdRng::seed_from_u64(seed);
+                    while !is_done.load(Ordering::SeqCst) {
+                        let k = rng.gen_range(0..KEYS * WRITERS);
+                        let found = index.get(&key(k)).unwrap();
+                        assert!(found.is_none_or(|found| found == rid(k)), "get {}", k);
+                    }
+                })
+            })
+            .collect();
+        // Every round fills the index and empties it again, so buckets
+        // split and merge all the time.
+        let writers: Vec<_> = (0..WRITERS)
+            .map(|id| {
+                let index = Arc::clone(&index);
+                thread::spawn(move || {
+                    for _ in 0..10 {
+                        for k in (0..KEYS).map(|i| i * WRITERS + id) {
+                            index.insert(&key(k), rid(k)).unwrap();
+                        }
+                        for k in (0..KEYS).map(|i| i * WRITERS + id) {
+                            index.delete(&key(k)).unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in writers {
+            handle.join().expect("writer panicked");
+        }
+        is_done.store(true, Ordering::SeqCst);
+        for handle in readers {
+            handle.join().expect("reader panicked");
+        }
+        assert_eq!(index.get_global_depth().unwrap(), 0);
+    }
+}
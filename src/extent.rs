@@ -0,0 +1,176 @@
+use crate::define::PageNo;
+use std::collections::{BTreeSet, HashMap};
+use std::io::{Error, ErrorKind};
+use std::ops::Range;
+use std::sync::Mutex;
+
+/// Number of pages storage grows by at a time, and the unit a segment
+/// claims pages in.
+pub const EXTENT_SIZE: PageNo = 64;
+
+/// Groups allocations that should be physically close, e.g. the nodes of one
+/// index. Each segment fills an extent of its own before claiming the next.
+pub type SegmentId = u32;
+
+/// Segment used by plain `allocate` calls.
+pub const DEFAULT_SEGMENT: SegmentId = 0;
+
+/// Page handed out by an `ExtentAllocator`.
+pub(crate) enum Allocation {
+    /// Never used since the storage was extended; reads as zeros.
+    Fresh(PageNo),
+    /// Deallocated earlier; the caller has to zero it.
+    Reused(PageNo),
+}
+
+/// Extent bookkeeping shared by the disk managers. It only decides which
+/// page numbers to hand out; growing the storage is left to the `extend`
+/// callback, which is called with the new size in pages and runs under the
+/// allocator's lock.
+///
+/// Segment ownership and the free list live in memory only. After a reopen
+/// every page up to the end of the storage counts as allocated.
+pub(crate) struct ExtentAllocator {
+    state: Mutex<ExtentState>,
+}
+
+struct ExtentState {
+    /// Pages handed out or reserved by a segment.
+    next_page: PageNo,
+    /// Size of the storage in pages; at least `next_page`.
+    num_pages: PageNo,
+    /// Unused rest of the extent each segment is currently filling.
+    segments: HashMap<SegmentId, Range<PageNo>>,
+    /// Deallocated pages, handed out again lowest first.
+    free_pages: BTreeSet<PageNo>,
+}
+
+impl ExtentAllocator {
+    pub(crate) fn new(num_pages: PageNo) -> Self {
+        Self {
+            state: Mutex::new(ExtentState {
+                next_page: num_pages,
+                num_pages,
+                segments: HashMap::new(),
+                free_pages: BTreeSet::new(),
+            }),
+        }
+    }
+
+    pub(crate) fn num_pages(&self) -> PageNo {
+        self.state.lock().unwrap().num_pages
+    }
+
+    /// Allocates one page for `segment`: from the extent it is filling, else
+    /// a deallocated page, else from a newly claimed extent.
+    pub(crate) fn allocate_in<F>(&self, segment: SegmentId, extend: F) -> std::io::Result<Allocation>
+    where
+        F: FnOnce(PageNo) -> std::io::Result<()>,
+    {
+        let mut state = self.state.lock().unwrap();
+        if let Some(page_no) = state.segments.get_mut(&segment).and_then(Iterator::next) {
+            return Ok(Allocation::Fresh(page_no));
+        }
+        if let Some(page_no) = state.free_pages.pop_first() {
+            return Ok(Allocation::Reused(page_no));
+        }
+        let start = state.claim(EXTENT_SIZE, extend)?;
+        state.segments.insert(segment, start + 1..start + EXTENT_SIZE);
+        Ok(Allocation::Fresh(start))
+    }
+
+    /// Allocates `n` fresh, physically contiguous pages and returns the
+    /// first one. The run is not owned by any segment.
+    pub(crate) fn allocate_pages<F>(&self, n: PageNo, extend: F) -> std::io::Result<PageNo>
+    where
+        F: FnOnce(PageNo) -> std::io::Result<()>,
+    {
+        if n == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "cannot allocate zero pages"));
+        }
+        self.state.lock().unwrap().claim(n, extend)
+    }
+
+    /// Puts an allocated page back for reuse. Fails for pages that are not
+    /// allocated: past the allocated space, still reserved by a segment or
+    /// already free.
+    pub(crate) fn deallocate(&self, page_no: PageNo) -> std::io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if page_no >= state.next_page || state.segments.values().any(|extent| extent.contains(&page_no)) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("page {} was never allocated", page_no),
+            ));
+        }
+        if !state.free_pages.insert(page_no) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("page {} is already free", page_no),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl ExtentState {
+    /// Reserves `n` pages at the end of the allocated space, growing the
+    /// storage to a whole number of extents past them if needed.
+    fn claim<F>(&mut self, n: PageNo, extend: F) -> std::io::Result<PageNo>
+    where
+        F: FnOnce(PageNo) -> std::io::Result<()>,
+    {
+        let start = self.next_page;
+        let end = start
+            .checked_add(n)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "allocation overflows the page space"))?;
+        if end > self.num_pages {
+            let num_pages = end.next_multiple_of(EXTENT_SIZE);
+            extend(num_pages)?;
+            self.num_pages = num_pages;
+        }
+        self.next_page = end;
+        Ok(start)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allocate(allocator: &ExtentAllocator, segment: SegmentId) -> PageNo {
+        match allocator.allocate_in(segment, |_| Ok(())).unwrap() {
+            Allocation::Fresh(page_no) | Allocation::Reused(page_no) => page_no,
+        }
+    }
+
+    #[test]
+    fn segments_fill_their_own_extents() {
+        let allocator = ExtentAllocator::new(0);
+        assert_eq!(allocate(&allocator, 1), 0);
+        assert_eq!(allocate(&allocator, 2), EXTENT_SIZE);
+        assert_eq!(allocate(&allocator, 1), 1);
+        assert_eq!(allocator.allocate_pages(3, |_| Ok(())).unwrap(), 2 * EXTENT_SIZE);
+        assert_eq!(allocator.num_pages(), 3 * EXTENT_SIZE);
+    }
+
+    #[test]
+    fn freed_pages_are_reused_once() {
+        let allocator = ExtentAllocator::new(EXTENT_SIZE);
+        allocator.deallocate(5).unwrap();
+        allocator.deallocate(3).unwrap();
+        assert_eq!(allocator.deallocate(5).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert!(matches!(allocator.allocate_in(DEFAULT_SEGMENT, |_| Ok(())).unwrap(), Allocation::Reused(3)));
+        assert!(matches!(allocator.allocate_in(DEFAULT_SEGMENT, |_| Ok(())).unwrap(), Allocation::Reused(5)));
+        assert!(matches!(allocator.allocate_in(DEFAULT_SEGMENT, |_| Ok(())).unwrap(), Allocation::Fresh(64)));
+    }
+
+    #[test]
+    fn unallocated_pages_cannot_be_freed() {
+        let allocator = ExtentAllocator::new(0);
+        assert_eq!(allocate(&allocator, DEFAULT_SEGMENT), 0);
+        // Reserved by the segment, but not handed out yet.
+        assert!(allocator.deallocate(1).is_err());
+        assert!(allocator.deallocate(EXTENT_SIZE).is_err());
+        allocator.deallocate(0).unwrap();
+    }
+}
@@ -0,0 +1,211 @@
+use crate::define::PageNo;
+use crate::disk_manager::DiskManager;
+use crate::extent::SegmentId;
+use crate::page::PageData;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// Size of the unit a torn write is split on.
+const SECTOR_SIZE: usize = 512;
+
+/// When a fault fires: always for the listed pages, and with `probability`
+/// for every other access.
+#[derive(Clone, Debug, Default)]
+pub struct FaultRule {
+    pub page_nos: HashSet<PageNo>,
+    pub probability: f64,
+}
+
+impl FaultRule {
+    pub fn never() -> Self {
+        Self::default()
+    }
+
+    pub fn always() -> Self {
+        Self::with_probability(1.0)
+    }
+
+    pub fn with_probability(probability: f64) -> Self {
+        Self {
+            page_nos: HashSet::new(),
+            probability,
+        }
+    }
+
+    pub fn on_pages(page_nos: impl IntoIterator<Item = PageNo>) -> Self {
+        Self {
+            page_nos: page_nos.into_iter().collect(),
+            probability: 0.0,
+        }
+    }
+}
+
+/// Which faults a `FaultyDiskManager` injects. Everything is off by default.
+#[derive(Clone, Debug, Default)]
+pub struct FaultConfig {
+    /// `read_page` fails without touching the buffer.
+    pub read_error: FaultRule,
+    /// `read_page` fills only part of the buffer and fails with
+    /// `UnexpectedEof`, like a file that ends mid-page.
+    pub short_read: FaultRule,
+    /// `read_page` succeeds but one bit of the returned data is flipped.
+    /// Storage itself is left intact.
+    pub bit_flip: FaultRule,
+    /// `write_page` fails without writing anything.
+    pub write_error: FaultRule,
+    /// `write_page` only persists a sector-aligned prefix of the new data,
+    /// keeping the old contents after it, yet reports success; the effect of
+    /// a crash in the middle of a page write.
+    pub torn_write: FaultRule,
+    /// Reads and writes sleep for `delay` before doing anything else.
+    pub latency: FaultRule,
+    pub delay: Duration,
+}
+
+/// Wraps another disk manager and injects faults into its page I/O.
+///
+/// All random decisions come from one generator seeded at construction, so a
+/// single-threaded run is reproducible. With several threads the sequence of
+/// faults depends on how their calls interleave.
+pub struct FaultyDiskManager<D: DiskManager> {
+    inner: D,
+    config: Mutex<FaultConfig>,
+    rng: Mutex<StdRng>,
+    num_injected: AtomicI32,
+    num_write_batches: AtomicI32,
+}
+
+impl<D: DiskManager> FaultyDiskManager<D> {
+    pub fn new(inner: D, config: FaultConfig, seed: u64) -> Self {
+        Self {
+            inner,
+            config: Mutex::new(config),
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+            num_injected: AtomicI32::new(0),
+            num_write_batches: AtomicI32::new(0),
+        }
+    }
+
+    /// Replaces the fault configuration, e.g. to arm faults once a test has
+    /// finished setting up its data.
+    pub fn set_config(&self, config: FaultConfig) {
+        *self.config.lock().unwrap() = config;
+    }
+
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// Number of faults injected so far, latency included.
+    pub fn get_injected_num(&self) -> i32 {
+        self.num_injected.load(Ordering::SeqCst)
+    }
+
+    /// Number of `write_pages` calls so far, each a batch of pages.
+    pub fn get_write_batch_num(&self) -> i32 {
+        self.num_write_batches.load(Ordering::SeqCst)
+    }
+
+    fn fires(&self, rule: &FaultRule, page_no: PageNo) -> bool {
+        let fired = rule.page_nos.contains(&page_no)
+            || (rule.probability > 0.0 && self.rng.lock().unwrap().gen_bool(rule.probability.min(1.0)));
+        if fired {
+            self.num_injected.fetch_add(1, Ordering::SeqCst);
+        }
+        fired
+    }
+
+    fn delay(&self, config: &FaultConfig, page_no: PageNo) {
+        if self.fires(&config.latency, page_no) {
+            thread::sleep(config.delay);
+        }
+    }
+
+    fn injected(kind: ErrorKind, what: &str, page_no: PageNo) -> Error {
+        Error::new(kind, format!("injected {} on page {}", what, page_no))
+    }
+}
+
+impl<D: DiskManager> DiskManager for FaultyDiskManager<D> {
+    fn read_page(&self, page_no: PageNo, data: &mut [u8]) -> std::io::Result<()> {
+        let config = self.config.lock().unwrap().clone();
+        self.delay(&config, page_no);
+        if self.fires(&config.read_error, page_no) {
+            return Err(Self::injected(ErrorKind::Other, "read error", page_no));
+        }
+        if self.fires(&config.short_read, page_no) {
+            let mut full = PageData::zeroed(data.len());
+            self.inner.read_page(page_no, &mut full)?;
+            let len = self.rng.lock().unwrap().gen_range(0..data.len());
+            data[..len].copy_from_slice(&full[..len]);
+            return Err(Self::injected(ErrorKind::UnexpectedEof, "short read", page_no));
+        }
+        self.inner.read_page(page_no, data)?;
+        if self.fires(&config.bit_flip, page_no) {
+            let bit = self.rng.lock().unwrap().gen_range(0..data.len() * 8);
+            data[bit / 8] ^= 1 << (bit % 8);
+        }
+        Ok(())
+    }
+
+    fn write_page(&self, page_no: PageNo, data: &[u8]) -> std::io::Result<()> {
+        let config = self.config.lock().unwrap().clone();
+        self.delay(&config, page_no);
+        if self.fires(&config.write_error, page_no) {
+            return Err(Self::injected(ErrorKind::Other, "write error", page_no));
+        }
+        if data.len() > SECTOR_SIZE && self.fires(&config.torn_write, page_no) {
+            let mut torn = PageData::zeroed(data.len());
+            // A page that was never written reads as zeros after the tear.
+            let _ = self.inner.read_page(page_no, &mut torn);
+            let sectors = data.len() / SECTOR_SIZE;
+            let len = self.rng.lock().unwrap().gen_range(1..sectors) * SECTOR_SIZE;
+            torn[..len].copy_from_slice(&data[..len]);
+            return self.inner.write_page(page_no, &torn);
+        }
+        self.inner.write_page(page_no, data)
+    }
+
+    /// Writes page by page, so each page gets its own faults.
+    fn write_pages(&self, writes: &[(PageNo, &[u8])]) -> Vec<std::io::Result<()>> {
+        self.num_write_batches.fetch_add(1, Ordering::SeqCst);
+        writes
+            .iter()
+            .map(|(page_no, data)| self.write_page(*page_no, data))
+            .collect()
+    }
+
+    fn allocate(&self) -> std::io::Result<PageNo> {
+        self.inner.allocate()
+    }
+
+    fn allocate_in(&self, segment: SegmentId) -> std::io::Result<PageNo> {
+        self.inner.allocate_in(segment)
+    }
+
+    fn allocate_pages(&self, n: PageNo) -> std::io::Result<PageNo> {
+        self.inner.allocate_pages(n)
+    }
+
+    fn deallocate(&self, page_no: PageNo) -> std::io::Result<()> {
+        self.inner.deallocate(page_no)
+    }
+
+    fn sync(&self) -> std::io::Result<()> {
+        self.inner.sync()
+    }
+
+    fn num_pages(&self) -> PageNo {
+        self.inner.num_pages()
+    }
+
+    fn page_size(&self) -> usize {
+        self.inner.page_size()
+    }
+}
@@ -0,0 +1,178 @@
+use crate::define::{is_valid_page_size, MIN_PAGE_SIZE};
+use crate::page::PageData;
+use std::fs::File;
+use std::io::{Error, ErrorKind};
+use std::os::unix::fs::FileExt;
+
+const MAGIC: &[u8; 8] = b"ADBSPAGE";
+const VERSION: u32 = 1;
+
+/// Page size of files from before headers existed, which are nothing but
+/// pages.
+const LEGACY_PAGE_SIZE: usize = 4096;
+
+/// Header flag: data pages are stored compressed, see `compression`.
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+pub(crate) const FLAG_COMPRESSED: u32 = 1;
+/// Header flag: data pages are stored encrypted, see `encryption`.
+#[cfg(feature = "encryption")]
+pub(crate) const FLAG_ENCRYPTED: u32 = 2;
+
+/// The first page of every database file. It records the format version and
+/// the page size the file was created with; page 0 starts right after it.
+///
+/// Layout (little endian): 8-byte magic, u32 version, u32 page size, u32
+/// flags, u64 salt, zeros up to the end of the page. Files written before
+/// flags existed read as having none. Files written before headers existed
+/// are bare 4096-byte pages starting at offset 0; they are used as they are,
+/// without flags, and never get a header.
+pub(crate) struct FileHeader {
+    pub(crate) page_size: usize,
+    pub(crate) flags: u32,
+    /// Random per file, chosen at creation; tells apart files that may share
+    /// an encryption key.
+    pub(crate) salt: u64,
+    /// Whether the file starts with this header, i.e. is not a legacy file.
+    has_header: bool,
+}
+
+impl FileHeader {
+    /// Reads the header of `file`, or writes one for `page_size` if the file
+    /// is empty. Returns the header actually in effect. Fails if an existing
+    /// file was created with other `flags`, as its pages would be misread.
+    pub(crate) fn init(file: &File, page_size: usize, flags: u32) -> std::io::Result<Self> {
+        if file.metadata()?.len() == 0 {
+            if !is_valid_page_size(page_size) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("unsupported page size {}", page_size),
+                ));
+            }
+            let header = Self {
+                page_size,
+                flags,
+                salt: rand::random(),
+                has_header: true,
+            };
+            header.write(file)?;
+            Ok(header)
+        } else {
+            let header = Self::read(file)?;
+            if header.flags != flags {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("file has header flags {:#x}, expected {:#x}", header.flags, flags),
+                ));
+            }
+            Ok(header)
+        }
+    }
+
+    fn read(file: &File) -> std::io::Result<Self> {
+        // The smallest page is enough to hold the header and keeps the read
+        // aligned for O_DIRECT.
+        let mut data = PageData::zeroed(MIN_PAGE_SIZE);
+        file.read_exact_at(&mut data, 0)?;
+        if &data[..8] != MAGIC {
+            if file.metadata()?.len().is_multiple_of(LEGACY_PAGE_SIZE as u64) {
+                return Ok(Self {
+                    page_size: LEGACY_PAGE_SIZE,
+                    flags: 0,
+                    salt: 0,
+                    has_header: false,
+                });
+            }
+            return Err(Error::new(ErrorKind::InvalidData, "not a database file: bad header magic"));
+        }
+        let version = u32::from_le_bytes(data[8..12].try_into().unwrap());
+        if version != VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported database file version {}", version),
+            ));
+        }
+        let page_size = u32::from_le_bytes(data[12..16].try_into().unwrap()) as usize;
+        if !is_valid_page_size(page_size) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("corrupt header: page size {}", page_size),
+            ));
+        }
+        let flags = u32::from_le_bytes(data[16..20].try_into().unwrap());
+        let salt = u64::from_le_bytes(data[20..28].try_into().unwrap());
+        Ok(Self {
+            page_size,
+            flags,
+            salt,
+            has_header: true,
+        })
+    }
+
+    fn write(&self, file: &File) -> std::io::Result<()> {
+        let mut data = PageData::zeroed(self.page_size);
+        data[..8].copy_from_slice(MAGIC);
+        data[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        data[12..16].copy_from_slice(&(self.page_size as u32).to_le_bytes());
+        data[16..20].copy_from_slice(&self.flags.to_le_bytes());
+        data[20..28].copy_from_slice(&self.salt.to_le_bytes());
+        file.write_all_at(&data, 0)?;
+        file.sync_all()
+    }
+
+    /// Byte offset of `page_no` in the file, past the header page if there
+    /// is one. Saturates for absurd page numbers, so I/O on them fails like
+    /// any read past the end of the file.
+    pub(crate) fn offset(&self, page_no: u64) -> u64 {
+        page_no
+            .saturating_add(self.has_header as u64)
+            .saturating_mul(self.page_size as u64)
+    }
+
+    /// Number of whole pages in a file of `len` bytes, not counting the
+    /// header page.
+    pub(crate) fn num_pages(&self, len: u64) -> u64 {
+        (len / self.page_size as u64).saturating_sub(self.has_header as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::define::PAGE_SIZE;
+    use std::fs::OpenOptions;
+
+    fn temp_file(name: &str, contents: &[u8]) -> (std::path::PathBuf, File) {
+        let path = std::env::temp_dir().join(format!("adbs-header-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        let file = OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        (path, file)
+    }
+
+    #[test]
+    fn header_round_trips() {
+        let (path, file) = temp_file("round-trip", &[]);
+        let created = FileHeader::init(&file, 8192, 0).unwrap();
+        let opened = FileHeader::init(&file, 4096, 0).unwrap();
+        assert_eq!((opened.page_size, opened.salt), (8192, created.salt));
+        assert!(FileHeader::init(&file, 8192, 1).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn headerless_file_is_read_as_bare_legacy_pages() {
+        let (path, file) = temp_file("legacy", &vec![0; 3 * LEGACY_PAGE_SIZE]);
+        let header = FileHeader::init(&file, 8192, 0).unwrap();
+        assert_eq!(header.page_size, LEGACY_PAGE_SIZE);
+        assert_eq!((header.offset(0), header.offset(2)), (0, 2 * LEGACY_PAGE_SIZE as u64));
+        assert_eq!(header.num_pages(file.metadata().unwrap().len()), 3);
+        // Legacy files have no flags to match, and no header is written.
+        assert!(FileHeader::init(&file, PAGE_SIZE, 1).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), vec![0; 3 * LEGACY_PAGE_SIZE]);
+        std::fs::remove_file(path).unwrap();
+
+        let (path, file) = temp_file("garbage", &vec![1; PAGE_SIZE + 1]);
+        let error = FileHeader::init(&file, PAGE_SIZE, 0).err().unwrap();
+        assert!(error.to_string().contains("bad header magic"), "{}", error);
+        std::fs::remove_file(path).unwrap();
+    }
+}
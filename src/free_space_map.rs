@@ -0,0 +1,247 @@
+use crate::buffer_pool_manager::BufferPoolManager;
+use crate::define::{FileId, PageId, PageNo};
+use crate::disk_manager::DiskManager;
+use crate::page::{Page, PageType, PAGE_HEADER_SIZE};
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex};
+
+/// Free space of the pages of one data file, one byte per page, kept in
+/// dedicated pages of the same file.
+///
+/// A map page stores the free space of the `entries_per_page` pages right
+/// after it, so map pages sit at fixed page numbers: 0, `entries_per_page +
+/// 1` and so on. Their owner must not use those pages for anything else;
+/// they are formatted when first written.
+///
+/// Each entry is a category, the free space in units of 1/256 of the page
+/// rounded down, so the map never promises more room than a page has. The
+/// category of an empty page is kept for empty pages alone: a record that
+/// fills a whole page rounds up past it, and searching for that category
+/// instead then finds only pages with room for the record. It
+/// is not logged: entries are durable once `flush` returns, and after a
+/// crash the map may lag behind the pages, promising room a page no longer
+/// has or missing room it gained. Callers finding less room than promised
+/// are expected to `set` the page right.
+/// The largest entry of every map page is cached in memory, so a search
+/// reads a single map page.
+pub struct FreeSpaceMap<D: DiskManager> {
+    bmgr: Arc<BufferPoolManager<D>>,
+    file_id: FileId,
+    entries_per_page: usize,
+    /// Free bytes one category stands for.
+    unit: usize,
+    /// Largest category in each map page.
+    maxima: Mutex<Vec<u8>>,
+}
+
+impl<D: DiskManager> FreeSpaceMap<D> {
+    /// Opens the map of `file_id`, which must already be in the pool,
+    /// reading each of its map pages once.
+    pub fn open(bmgr: Arc<BufferPoolManager<D>>, file_id: FileId) -> std::io::Result<Self> {
+        let num_pages = bmgr
+            .get_num_pages(file_id)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("no file {} in the pool", file_id)))?;
+        let page_size = bmgr.get_page_size();
+        let fsm = Self {
+            bmgr,
+            file_id,
+            entries_per_page: page_size - PAGE_HEADER_SIZE,
+            unit: page_size / 256,
+            maxima: Mutex::new(Vec::new()),
+        };
+        let mut maxima = Vec::new();
+        let mut map_page_no = 0;
+        while map_page_no < num_pages {
+            maxima.push(fsm.with_map_page(map_page_no, false, |entries| Ok(Self::max(entries)))?);
+            map_page_no += fsm.entries_per_page as PageNo + 1;
+        }
+        *fsm.maxima.lock().unwrap() = maxima;
+        Ok(fsm)
+    }
+
+    /// Whether `page_no` is one of the map's own pages.
+    pub fn is_map_page(&self, page_no: PageNo) -> bool {
+        page_no.is_multiple_of(self.entries_per_page as PageNo + 1)
+    }
+
+    /// Records that `page_no` has `free_space` bytes free.
+    pub fn set(&self, page_no: PageNo, free_space: usize) -> std::io::Result<()> {
+        let (map_index, entry) = self.locate(page_no)?;
+        let empty = self.empty_category();
+        let category = if free_space >= self.bmgr.get_page_size() - PAGE_HEADER_SIZE {
+            empty
+        } else {
+            (free_space / self.unit).min(empty as usize - 1) as u8
+        };
+        self.with_map_page(self.map_page_no(map_index), true, |entries| {
+            let old = entries[entry];
+            entries[entry] = category;
+            let mut maxima = self.maxima.lock().unwrap();
+            if maxima.len() <= map_index {
+                maxima.resize(map_index + 1, 0);
+            }
+            if category >= maxima[map_index] {
+                maxima[map_index] = category;
+            } else if old == maxima[map_index] {
+                maxima[map_index] = Self::max(entries);
+            }
+            Ok(())
+        })
+    }
+
+    /// Free bytes recorded for `page_no`; at most what it really has,
+    /// unless the map lags behind.
+    pub fn get(&self, page_no: PageNo) -> std::io::Result<usize> {
+        let (map_index, entry) = self.locate(page_no)?;
+        let category = self.with_map_page(self.map_page_no(map_index), false, |entries| Ok(entries[entry]))?;
+        Ok(category as usize * self.unit)
+    }
+
+    /// The first page recorded with at least `needed` bytes free, if any.
+    pub fn search(&self, needed: usize) -> std::io::Result<Option<PageNo>> {
+        if needed > self.bmgr.get_page_size() - PAGE_HEADER_SIZE {
+            return Ok(None);
+        }
+        let category = needed.div_ceil(self.unit).min(self.empty_category() as usize) as u8;
+        let candidates: Vec<usize> = {
+            let maxima = self.maxima.lock().unwrap();
+            (0..maxima.len()).filter(|&i| maxima[i] >= category).collect()
+        };
+        for map_index in candidates {
+            let entry = self.with_map_page(self.map_page_no(map_index), false, |entries| {
+                Ok(entries.iter().position(|&entry| entry >= category))
+            })?;
+            if let Some(entry) = entry {
+                return Ok(Some(self.map_page_no(map_index) + 1 + entry as PageNo));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Writes back the map pages changed since they were last written and
+    /// syncs the file.
+    pub fn flush(&self) -> std::io::Result<()> {
+        let num_map_pages = self.maxima.lock().unwrap().len();
+        for map_index in 0..num_map_pages {
+            self.bmgr.flush_page(PageId::new(self.file_id, self.map_page_no(map_index)))?;
+        }
+        self.bmgr.tablespace().file(self.file_id)?.sync()
+    }
+
+    /// Runs `f` on the entries of the map page `map_page_no`. An unformatted
+    /// map page reads as all zeros and is formatted if `f` may modify it.
+    fn with_map_page<T>(
+        &self,
+        map_page_no: PageNo,
+        is_dirty: bool,
+        f: impl FnOnce(&mut [u8]) -> std::io::Result<T>,
+    ) -> std::io::Result<T> {
+        let page_id = PageId::new(self.file_id, map_page_no);
+        let frame_id = self.bmgr.fix_page(page_id, is_dirty)?;
+        let result = {
+            let mut page = self.bmgr.get_page(frame_id);
+            Self::check_map_page(&mut page, page_id, is_dirty)
+                .and_then(|_| f(&mut page.get_data_mut()[PAGE_HEADER_SIZE..]))
+        };
+        self.bmgr.unfix_page(page_id);
+        result
+    }
+
+    fn check_map_page(page: &mut Page, page_id: PageId, is_dirty: bool) -> std::io::Result<()> {
+        match page.get_page_type() {
+            Some(PageType::FreeSpaceMap) => Ok(()),
+            Some(PageType::Unformatted) => {
+                if is_dirty {
+                    page.init(PageType::FreeSpaceMap);
+                }
+                Ok(())
+            }
+            page_type => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("page {} should be a free space map page, found {:?}", page_id, page_type),
+            )),
+        }
+    }
+
+    /// Which map page holds the entry of `page_no`, and where.
+    fn locate(&self, page_no: PageNo) -> std::io::Result<(usize, usize)> {
+        if self.is_map_page(page_no) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("page {} belongs to the free space map", page_no),
+            ));
+        }
+        let group = self.entries_per_page as PageNo + 1;
+        Ok(((page_no / group) as usize, (page_no % group) as usize - 1))
+    }
+
+    fn map_page_no(&self, map_index: usize) -> PageNo {
+        map_index as PageNo * (self.entries_per_page as PageNo + 1)
+    }
+
+    /// Category recorded for an empty page, the most room a page has.
+    fn empty_category(&self) -> u8 {
+        ((self.bmgr.get_page_size() - PAGE_HEADER_SIZE) / self.unit).min(u8::MAX as usize) as u8
+    }
+
+    fn max(entries: &[u8]) -> u8 {
+        entries.iter().copied().max().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer_pool_manager::ReplacePolicyType;
+    use crate::data_storage_manager::DSMgr;
+    use crate::define::PAGE_SIZE;
+
+    fn open(path: &str) -> FreeSpaceMap<DSMgr> {
+        let bmgr = BufferPoolManager::with_disk_manager(DSMgr::open_file(path).unwrap(), ReplacePolicyType::LRU, 4);
+        bmgr.tablespace().file(0).unwrap().allocate_pages(6000).unwrap();
+        FreeSpaceMap::open(Arc::new(bmgr), 0).unwrap()
+    }
+
+    #[test]
+    fn search_spans_map_pages_and_flushed_entries_persist() {
+        let path = std::env::temp_dir().join(format!("adbs-fsm-{}.dbf", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+
+        let fsm = open(path);
+        let second_map_page = (PAGE_SIZE - PAGE_HEADER_SIZE) as PageNo + 1;
+        assert!(fsm.is_map_page(0) && fsm.is_map_page(second_map_page));
+        assert_eq!(fsm.set(second_map_page, 100).unwrap_err().kind(), ErrorKind::InvalidInput);
+
+        fsm.set(3, 1000).unwrap();
+        fsm.set(second_map_page + 2, 3000).unwrap();
+        assert_eq!(fsm.get(3).unwrap(), 1000 / fsm.unit * fsm.unit, "categories round down");
+        assert_eq!(fsm.search(500).unwrap(), Some(3));
+        assert_eq!(fsm.search(2000).unwrap(), Some(second_map_page + 2));
+        assert_eq!(fsm.search(PAGE_SIZE).unwrap(), None);
+        // Only an empty page takes a record filling a whole page.
+        let empty = PAGE_SIZE - PAGE_HEADER_SIZE;
+        fsm.set(5, empty - 1).unwrap();
+        assert_eq!(fsm.search(empty).unwrap(), None);
+        fsm.set(6, empty).unwrap();
+        assert_eq!(fsm.search(empty).unwrap(), Some(6));
+        assert!(fsm.get(6).unwrap() <= empty);
+        fsm.set(5, 0).unwrap();
+        fsm.set(6, 0).unwrap();
+        // Lowering the largest entry of a map page lowers its cached maximum.
+        fsm.set(second_map_page + 2, 0).unwrap();
+        assert_eq!(fsm.maxima.lock().unwrap()[1], 0);
+        assert_eq!(fsm.search(2000).unwrap(), None);
+
+        fsm.set(second_map_page + 5, 2500).unwrap();
+        fsm.flush().unwrap();
+        fsm.set(4, 2000).unwrap();
+        // A crash loses the entry set after the flush, nothing before.
+        drop(fsm);
+        let fsm = open(path);
+        assert_eq!(fsm.search(2000).unwrap(), Some(second_map_page + 5));
+        assert_eq!(fsm.search(500).unwrap(), Some(3));
+        assert_eq!(fsm.get(4).unwrap(), 0);
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,401 @@
+use crate::buffer_pool_manager::BufferPoolManager;
+use crate::define::{FileId, FrameId, PageId, PageNo};
+use crate::disk_manager::DiskManager;
+use crate::free_space_map::FreeSpaceMap;
+use crate::page::{Page, PageType};
+use crate::slotted_page::{SlotNo, SlottedPage, SLOT_SIZE};
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+
+/// Address of a record in a heap file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RecordId {
+    pub page_id: PageId,
+    pub slot: SlotNo,
+}
+
+/// Size of an encoded `RecordId`: u32 file id, u64 page number, u16 slot.
+pub(crate) const RECORD_ID_SIZE: usize = 14;
+
+impl RecordId {
+    pub const fn new(page_id: PageId, slot: SlotNo) -> Self {
+        Self { page_id, slot }
+    }
+
+    /// The id as stored in index pages.
+    pub(crate) fn encode(&self) -> [u8; RECORD_ID_SIZE] {
+        let mut bytes = [0; RECORD_ID_SIZE];
+        bytes[..4].copy_from_slice(&self.page_id.file_id.to_le_bytes());
+        bytes[4..12].copy_from_slice(&self.page_id.page_no.to_le_bytes());
+        bytes[12..].copy_from_slice(&self.slot.to_le_bytes());
+        bytes
+    }
+
+    pub(crate) fn decode(bytes: &[u8]) -> Self {
+        let file_id = FileId::from_le_bytes(bytes[..4].try_into().unwrap());
+        let page_no = PageNo::from_le_bytes(bytes[4..12].try_into().unwrap());
+        let slot = SlotNo::from_le_bytes(bytes[12..14].try_into().unwrap());
+        Self::new(PageId::new(file_id, page_no), slot)
+    }
+}
+
+impl std::fmt::Display for RecordId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.page_id, self.slot)
+    }
+}
+
+/// Unordered collection of variable-length records, stored in the slotted
+/// pages of one data file of the pool. Every page of the file belongs to
+/// the heap: its free space map, and slotted pages for everything else.
+///
+/// Inserts find a page with room through the free space map, which is
+/// updated with every change.
+///
+/// Changes are not logged. They are durable once `flush` returns; until
+/// then they live in the pool, and pages are written back one at a time as
+/// they are evicted. A crash thus keeps an arbitrary subset of the page
+/// changes since the last flush: records may be lost, and a record moved
+/// by `update` may show up twice or, if only its deletion reached the disk,
+/// not at all. Nothing repairs a page whose write the crash tore, as there
+/// is no logged image of it: the page fails its checksum, so every read of
+/// it fails with `InvalidData` and a scan stops with that error when it
+/// gets there.
+pub struct HeapFile<D: DiskManager> {
+    bmgr: Arc<BufferPoolManager<D>>,
+    file_id: FileId,
+    fsm: FreeSpaceMap<D>,
+}
+
+impl<D: DiskManager> HeapFile<D> {
+    /// Opens the heap stored in `file_id`, which must already be in the
+    /// pool; an empty file is an empty heap.
+    pub fn open(bmgr: Arc<BufferPoolManager<D>>, file_id: FileId) -> std::io::Result<Self> {
+        let fsm = FreeSpaceMap::open(Arc::clone(&bmgr), file_id)?;
+        Ok(Self { bmgr, file_id, fsm })
+    }
+
+    /// Stores `record` in the first page the free space map knows to have
+    /// room for it, allocating a new page if there is none.
+    pub fn insert(&self, record: &[u8]) -> std::io::Result<RecordId> {
+        let page_size = self.bmgr.get_page_size();
+        if record.len() > SlottedPage::<&Page>::max_record_len(page_size) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("a record of {} bytes does not fit in a page", record.len()),
+            ));
+        }
+        let needed = record.len() + SLOT_SIZE;
+        loop {
+            let page_no = match self.fsm.search(needed)? {
+                Some(page_no) => page_no,
+                None => self.allocate_page()?,
+            };
+            // The map may promise more than the page has after a crash, or
+            // another thread filled the page meanwhile; the page's entry is
+            // then corrected and the search goes on.
+            match self.with_page(page_no, true, |page| page.insert(record)) {
+                Ok(slot) => return Ok(RecordId::new(self.page_id(page_no), slot)),
+                Err(e) if e.kind() == ErrorKind::StorageFull => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// The record at `rid`, or `None` if there is none.
+    pub fn get(&self, rid: RecordId) -> std::io::Result<Option<Vec<u8>>> {
+        let page_no = self.check_rid(rid)?;
+        self.with_page(page_no, false, |page| Ok(page.get(rid.slot).map(<[u8]>::to_vec)))
+    }
+
+    /// Replaces the record at `rid` and returns where it is now: at `rid` if
+    /// it still fits in its page, otherwise moved to another page.
+    pub fn update(&self, rid: RecordId, record: &[u8]) -> std::io::Result<RecordId> {
+        let page_no = self.check_rid(rid)?;
+        match self.with_page(page_no, true, |page| page.update(rid.slot, record)) {
+            Ok(()) => Ok(rid),
+            Err(e) if e.kind() == ErrorKind::StorageFull => {
+                let new_rid = self.insert(record)?;
+                self.delete(rid)?;
+                Ok(new_rid)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Deletes the record at `rid`. Fails with `NotFound` if there is none.
+    pub fn delete(&self, rid: RecordId) -> std::io::Result<()> {
+        let page_no = self.check_rid(rid)?;
+        self.with_page(page_no, true, |page| page.delete(rid.slot))
+    }
+
+    /// Iterates over all records in page and slot order. Only the page
+    /// being read is pinned; records inserted behind the scan are missed.
+    pub fn scan(&self) -> HeapScan<'_, D> {
+        HeapScan {
+            heap: self,
+            page_no: 0,
+            slot: 0,
+            pinned: None,
+        }
+    }
+
+    /// Writes back every changed page of the heap, free space map
+    /// included, and syncs its file.
+    pub fn flush(&self) -> std::io::Result<()> {
+        self.bmgr.flush_file(self.file_id).map(|_| ())
+    }
+
+    pub fn get_file_id(&self) -> FileId {
+        self.file_id
+    }
+
+    /// Number of pages in the heap's file, free space map included.
+    pub fn get_page_num(&self) -> PageNo {
+        self.bmgr.get_num_pages(self.file_id).unwrap_or(0)
+    }
+
+    /// Allocates and formats a page for records. Pages reserved for the free
+    /// space map are passed over; the map formats them itself.
+    fn allocate_page(&self) -> std::io::Result<PageNo> {
+        loop {
+            let mut page_id = self.page_id(0);
+            let frame_id = self.bmgr.fix_new_page(self.file_id, &mut page_id)?;
+            let free_space = if self.fsm.is_map_page(page_id.page_no) {
+                None
+            } else {
+                let mut page = self.bmgr.get_page(frame_id);
+                page.set_dirty(true);
+                Some(SlottedPage::init(&mut *page).free_space())
+            };
+            self.bmgr.unfix_page(page_id);
+            if let Some(free_space) = free_space {
+                self.fsm.set(page_id.page_no, free_space)?;
+                return Ok(page_id.page_no);
+            }
+        }
+    }
+
+    /// Runs `f` on page `page_no` of the heap, fixed for the duration, and
+    /// records the page's free space in the map afterwards if it was
+    /// modified. A page never formatted, such as one the file was extended
+    /// by in advance, reads as empty and is formatted once it is modified.
+    fn with_page<T>(
+        &self,
+        page_no: PageNo,
+        is_dirty: bool,
+        f: impl FnOnce(&mut SlottedPage<&mut Page>) -> std::io::Result<T>,
+    ) -> std::io::Result<T> {
+        let page_id = self.page_id(page_no);
+        let frame_id = self.bmgr.fix_page(page_id, is_dirty)?;
+        let result = self.on_frame(frame_id, is_dirty, f);
+        self.bmgr.unfix_page(page_id);
+        let (result, free_space) = result?;
+        if is_dirty {
+            self.fsm.set(page_no, free_space)?;
+        }
+        result
+    }
+
+    /// Runs `f` on the pinned page in `frame_id`, returning its result and
+    /// the page's free space afterwards.
+    fn on_frame<T>(
+        &self,
+        frame_id: FrameId,
+        is_dirty: bool,
+        f: impl FnOnce(&mut SlottedPage<&mut Page>) -> std::io::Result<T>,
+    ) -> std::io::Result<(std::io::Result<T>, usize)> {
+        let mut guard = self.bmgr.get_page(frame_id);
+        let mut empty_page;
+        let mut page = match guard.get_page_type() {
+            Some(PageType::Unformatted) if is_dirty => SlottedPage::init(&mut *guard),
+            Some(PageType::Unformatted) => {
+                empty_page = Page::new(self.bmgr.get_page_size());
+                SlottedPage::init(&mut empty_page)
+            }
+            _ => SlottedPage::new(&mut *guard)?,
+        };
+        let result = f(&mut page);
+        Ok((result, page.free_space()))
+    }
+
+    fn check_rid(&self, rid: RecordId) -> std::io::Result<PageNo> {
+        if rid.page_id.file_id != self.file_id
+            || rid.page_id.page_no >= self.get_page_num()
+            || self.fsm.is_map_page(rid.page_id.page_no)
+        {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("record {} is not in this heap file", rid),
+            ));
+        }
+        Ok(rid.page_id.page_no)
+    }
+
+    fn page_id(&self, page_no: PageNo) -> PageId {
+        PageId::new(self.file_id, page_no)
+    }
+}
+
+/// Records of a heap file, see `HeapFile::scan`. Yields each record with
+/// its id; unpins the current page when dropped.
+pub struct HeapScan<'a, D: DiskManager> {
+    heap: &'a HeapFile<D>,
+    page_no: PageNo,
+    /// Next slot to look at in page `page_no`.
+    slot: SlotNo,
+    /// Page `page_no` and its frame, while pinned.
+    pinned: Option<(PageId, FrameId)>,
+}
+
+impl<D: DiskManager> HeapScan<'_, D> {
+    /// The next record in the pinned page, pinning the next page when one
+    /// is exhausted; `None` at the end of the heap.
+    fn next_record(&mut self) -> std::io::Result<Option<(RecordId, Vec<u8>)>> {
+        let bmgr = &self.heap.bmgr;
+        loop {
+            let (page_id, frame_id) = match self.pinned {
+                Some(pinned) => pinned,
+                None => {
+                    if self.page_no >= self.heap.get_page_num() {
+                        return Ok(None);
+                    }
+                    if self.heap.fsm.is_map_page(self.page_no) {
+                        self.page_no += 1;
+                        continue;
+                    }
+                    let page_id = self.heap.page_id(self.page_no);
+                    let frame_id = bmgr.fix_page(page_id, false)?;
+                    self.pinned = Some((page_id, frame_id));
+                    (page_id, frame_id)
+                }
+            };
+            let found = {
+                let page = bmgr.get_page(frame_id);
+                match page.get_page_type() {
+                    Some(PageType::Unformatted) => None,
+                    _ => {
+                        let page = SlottedPage::new(&*page)?;
+                        (self.slot as usize..page.num_slots())
+                            .find_map(|slot| page.get(slot as SlotNo).map(|record| (slot as SlotNo, record.to_vec())))
+                    }
+                }
+            };
+            match found {
+                Some((slot, record)) => {
+                    self.slot = slot + 1;
+                    return Ok(Some((RecordId::new(page_id, slot), record)));
+                }
+                None => {
+                    bmgr.unfix_page(page_id);
+                    self.pinned = None;
+                    self.page_no += 1;
+                    self.slot = 0;
+                }
+            }
+        }
+    }
+}
+
+impl<D: DiskManager> Iterator for HeapScan<'_, D> {
+    type Item = std::io::Result<(RecordId, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_record() {
+            Ok(Some(item)) => Some(Ok(item)),
+            Ok(None) => None,
+            Err(e) => {
+                // Give up on the rest of the heap.
+                if let Some((page_id, _)) = self.pinned.take() {
+                    self.heap.bmgr.unfix_page(page_id);
+                }
+                self.page_no = PageNo::MAX;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl<D: DiskManager> Drop for HeapScan<'_, D> {
+    fn drop(&mut self) {
+        if let Some((page_id, _)) = self.pinned.take() {
+            self.heap.bmgr.unfix_page(page_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer_pool_manager::ReplacePolicyType;
+    use crate::data_storage_manager::DSMgr;
+
+    fn open(path: &str) -> (Arc<BufferPoolManager<DSMgr>>, HeapFile<DSMgr>) {
+        let bmgr = Arc::new(BufferPoolManager::with_disk_manager(
+            DSMgr::open_file(path).unwrap(),
+            ReplacePolicyType::LRU,
+            8,
+        ));
+        let heap = HeapFile::open(Arc::clone(&bmgr), 0).unwrap();
+        (bmgr, heap)
+    }
+
+    #[test]
+    fn flushed_records_survive_a_restart() {
+        let path = std::env::temp_dir().join(format!("adbs-heap-{}.dbf", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+
+        let (bmgr, heap) = open(path);
+        let mut rids: Vec<(RecordId, Vec<u8>)> = (0..500u32)
+            .map(|i| {
+                let record = vec![i as u8; 50 + i as usize % 200];
+                (heap.insert(&record).unwrap(), record)
+            })
+            .collect();
+        for (rid, record) in rids.iter_mut().step_by(7) {
+            record.resize(record.len() * 3, 0xee);
+            *rid = heap.update(*rid, record).unwrap();
+        }
+        for (rid, _) in rids.drain(..50) {
+            heap.delete(rid).unwrap();
+        }
+        heap.flush().unwrap();
+        assert_eq!(bmgr.get_dirty_ratio(), 0.0);
+        // No close: whatever was not flushed is gone.
+        drop((heap, bmgr));
+
+        let (_bmgr, heap) = open(path);
+        let mut scanned: Vec<(RecordId, Vec<u8>)> = heap.scan().collect::<std::io::Result<_>>().unwrap();
+        scanned.sort();
+        rids.sort();
+        assert_eq!(scanned, rids);
+        // The free space map came back too, so a small record goes to a
+        // page that has room rather than to a new one.
+        let rid = heap.insert(&[1; 10]).unwrap();
+        assert!(rids.iter().any(|(other, _)| other.page_id == rid.page_id));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn page_sized_records_reuse_empty_pages() {
+        let path = std::env::temp_dir().join(format!("adbs-heap-full-{}.dbf", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+
+        let (bmgr, heap) = open(path);
+        let record = vec![7; SlottedPage::<&Page>::max_record_len(bmgr.get_page_size())];
+        let first = heap.insert(&record).unwrap();
+        heap.delete(first).unwrap();
+        for _ in 0..3 {
+            let rid = heap.insert(&record).unwrap();
+            assert_eq!(rid.page_id, first.page_id, "the emptied page is reused");
+            heap.delete(rid).unwrap();
+        }
+        // A page that is not empty has no room for it.
+        assert_eq!(heap.insert(&[1]).unwrap().page_id, first.page_id);
+        assert_ne!(heap.insert(&record).unwrap().page_id, first.page_id);
+        drop((heap, bmgr));
+        let _ = std::fs::remove_file(path);
+    }
+}
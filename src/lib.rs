@@ -1,9 +1,35 @@
 // src/lib.rs
 
 pub mod define;
+mod checksum;
 pub mod page;
+pub mod slotted_page;
 pub mod replacer;
 pub mod lru_replacer;
 pub mod clock_replacer;
+pub mod disk_manager;
+pub mod extent;
+mod file_header;
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+pub mod compression;
+#[cfg(feature = "encryption")]
+pub mod encryption;
 pub mod data_storage_manager;
+pub mod memory_disk_manager;
+pub mod mmap_disk_manager;
+pub mod fault_injection;
+pub mod tablespace;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
 pub mod buffer_pool_manager;
+pub mod async_io;
+pub mod page_cleaner;
+pub mod read_ahead;
+pub mod wal;
+pub mod transaction;
+pub mod recovery;
+pub mod checkpoint;
+pub mod free_space_map;
+pub mod heap_file;
+pub mod btree;
+pub mod extendible_hash;
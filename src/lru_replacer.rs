@@ -62,6 +62,11 @@ impl Replacer for LRUReplacer {
         }
     }
 
+    fn peek_victims(&self, n: usize) -> Vec<FrameId> {
+        let inner = self.inner.lock().unwrap();
+        inner.list.iter().take(n).copied().collect()
+    }
+
     fn size(&self) -> usize {
         let inner = self.inner.lock().unwrap();
         inner.list.len()
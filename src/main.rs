@@ -1,27 +1,23 @@
-mod define;
-mod page;
-mod replacer;
-mod lru_replacer;
-mod clock_replacer;
-mod data_storage_manager;
-mod buffer_pool_manager;
-
 use clap::{Arg, ArgAction, Command};
-use buffer_pool_manager::{BufferPoolManager, ReplacePolicyType};
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+use adbs_lab::compression::Compression;
+#[cfg(feature = "encryption")]
+use adbs_lab::encryption::EncryptionKey;
+use adbs_lab::buffer_pool_manager::{BufferPoolManager, ReplacePolicyType};
+use adbs_lab::data_storage_manager::{DSMgr, DSMgrOptions, SyncPolicy};
+use adbs_lab::define::{PageId, PageNo, PAGE_SIZE};
+use adbs_lab::disk_manager::DiskManager;
+use adbs_lab::page_cleaner::{PageCleaner, PageCleanerConfig};
 use std::thread;
 use std::sync::Arc;
 use std::io::{BufReader, BufRead};
 use std::time::Instant;
-use std::fs::{File, OpenOptions};
-use std::io::{Write, Seek, SeekFrom};
-use std::path::Path;
 
-const PAGE_SIZE: usize = 4096; 
-const INITIAL_PAGES: usize = 50000; 
+const INITIAL_PAGES: PageNo = 50000; 
 
 fn main() -> std::io::Result<()> {
     
-    let matches = Command::new("Storage and Buffer Manager")
+    let command = Command::new("Storage and Buffer Manager")
         .version("1.0")
         .author("Your Name")
         .about("Implements Storage and Buffer Manager in Rust")
@@ -46,6 +42,35 @@ fn main() -> std::io::Result<()> {
                 .help("Use multi-threading")
                 .action(ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("cleaner")
+                .short('w')
+                .long("cleaner")
+                .help("Run a background page cleaner")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("direct")
+                .short('d')
+                .long("direct")
+                .help("Bypass the OS page cache with O_DIRECT")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("sync")
+                .long("sync")
+                .help("Sync policy for page writes")
+                .value_parser(["none", "data", "full"])
+                .default_value("none"),
+        )
+        .arg(
+            Arg::new("page-size")
+                .short('p')
+                .long("page-size")
+                .help("Page size in bytes when creating the database file")
+                .value_parser(["4096", "8192", "16384", "32768", "65536"])
+                .default_value("4096"),
+        )
         .arg(
             Arg::new("threads")
                 .short('t')
@@ -59,8 +84,28 @@ fn main() -> std::io::Result<()> {
                 .help("Input trace file")
                 .required(true)
                 .index(1),
-        )
-        .get_matches();
+        );
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    let command = command.arg(
+        Arg::new("compress")
+            .short('z')
+            .long("compress")
+            .help("Store pages compressed (uses a separate database file)")
+            .value_parser([
+                #[cfg(feature = "lz4")]
+                "lz4",
+                #[cfg(feature = "zstd")]
+                "zstd",
+            ]),
+    );
+    #[cfg(feature = "encryption")]
+    let command = command.arg(
+        Arg::new("key-file")
+            .short('k')
+            .long("key-file")
+            .help("Encrypt pages with the 32-byte key in this file (uses a separate database file)"),
+    );
+    let matches = command.get_matches();
 
     
     let policy = if matches.get_flag("lru") {
@@ -84,27 +129,65 @@ fn main() -> std::io::Result<()> {
         .expect("FILE argument is required.");
 
     
-    let db_filename = "test.dbf";
-    if !Path::new(db_filename).exists() {
-        println!("Creating and initializing {}", db_filename);
-        let mut db_file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .read(true)
-            .open(db_filename)?;
-
-        
-        let buffer = vec![0u8; PAGE_SIZE];
-        for _ in 0..INITIAL_PAGES {
-            db_file.write_all(&buffer)?;
+    // The io-uring feature adds fields that stay at their defaults.
+    #[allow(clippy::needless_update, unused_mut)]
+    let mut options = DSMgrOptions {
+        direct_io: matches.get_flag("direct"),
+        sync_policy: match matches.get_one::<String>("sync").map(String::as_str) {
+            Some("data") => SyncPolicy::DataSync,
+            Some("full") => SyncPolicy::FullSync,
+            _ => SyncPolicy::None,
+        },
+        page_size: matches
+            .get_one::<String>("page-size")
+            .and_then(|page_size| page_size.parse().ok())
+            .unwrap_or(PAGE_SIZE),
+        ..DSMgrOptions::default()
+    };
+    #[allow(unused_mut)]
+    let mut db_filename = "test.dbf";
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    {
+        options.compression = matches
+            .get_one::<String>("compress")
+            .and_then(|codec| match codec.as_str() {
+                #[cfg(feature = "lz4")]
+                "lz4" => Some(Compression::Lz4),
+                #[cfg(feature = "zstd")]
+                "zstd" => Some(Compression::Zstd(3)),
+                _ => None,
+            });
+        if options.compression.is_some() {
+            db_filename = "test_compressed.dbf";
         }
-        db_file.sync_all()?; 
+    }
+    #[cfg(feature = "encryption")]
+    if let Some(key_file) = matches.get_one::<String>("key-file") {
+        let key = std::fs::read(key_file)?.try_into().map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} does not hold a 32-byte key", key_file),
+            )
+        })?;
+        options.encryption_key = Some(EncryptionKey(key));
+        db_filename = "test_encrypted.dbf";
+    }
+    let disk_manager = DSMgr::open_with(db_filename, options)?;
+    if disk_manager.get_num_pages() == 0 {
+        println!("Creating and initializing {}", db_filename);
+        disk_manager.allocate_pages(INITIAL_PAGES)?;
+        disk_manager.sync()?;
     } else {
         println!("{} already exists.", db_filename);
     }
+    println!("Page size: {} bytes", disk_manager.get_page_size());
+    let bmgr = Arc::new(BufferPoolManager::with_disk_manager(disk_manager, policy, 1024));
 
-    
-    let bmgr = Arc::new(BufferPoolManager::new(db_filename, policy, 1024)?);
+    let mut cleaner = if matches.get_flag("cleaner") {
+        Some(PageCleaner::start(Arc::clone(&bmgr), PageCleanerConfig::default()))
+    } else {
+        None
+    };
 
     
     let start_time = Instant::now();
@@ -123,19 +206,19 @@ fn main() -> std::io::Result<()> {
                 thread::spawn(move || {
                     if let Ok(file) = std::fs::File::open(&fname) {
                         let reader = BufReader::new(file);
-                        for line in reader.lines() {
-                            if let Ok(l) = line {
-                                let parts: Vec<&str> = l.split(',').collect();
-                                if parts.len() != 2 {
-                                    continue;
-                                }
-                                let is_dirty: bool = parts[0].parse::<i32>().unwrap_or(0) != 0;
-                                let page_id: i32 = parts[1].parse().unwrap_or(-1);
-                                if page_id < 0 {
-                                    continue;
-                                }
-                                
-                                let _ = bmgr.fix_page(page_id, is_dirty);
+                        for l in reader.lines().map_while(Result::ok) {
+                            let parts: Vec<&str> = l.split(',').collect();
+                            if parts.len() != 2 {
+                                continue;
+                            }
+                            let is_dirty: bool = parts[0].parse::<i32>().unwrap_or(0) != 0;
+                            let page_no: PageNo = match parts[1].parse() {
+                                Ok(page_no) => page_no,
+                                Err(_) => continue,
+                            };
+                            let page_id = PageId::new(0, page_no);
+                             
+                            if bmgr.fix_page(page_id, is_dirty).is_ok() {
                                 bmgr.unfix_page(page_id);
                             }
                         }
@@ -152,18 +235,18 @@ fn main() -> std::io::Result<()> {
         
         if let Ok(file) = std::fs::File::open(filename) {
             let reader = BufReader::new(file);
-            for line in reader.lines() {
-                if let Ok(l) = line {
-                    let parts: Vec<&str> = l.split(',').collect();
-                    if parts.len() != 2 {
-                        continue;
-                    }
-                    let is_dirty: bool = parts[0].parse::<i32>().unwrap_or(0) != 0;
-                    let page_id: i32 = parts[1].parse().unwrap_or(-1);
-                    if page_id < 0 {
-                        continue;
-                    }
-                    let _ = bmgr.fix_page(page_id, is_dirty);
+            for l in reader.lines().map_while(Result::ok) {
+                let parts: Vec<&str> = l.split(',').collect();
+                if parts.len() != 2 {
+                    continue;
+                }
+                let is_dirty: bool = parts[0].parse::<i32>().unwrap_or(0) != 0;
+                let page_no: PageNo = match parts[1].parse() {
+                    Ok(page_no) => page_no,
+                    Err(_) => continue,
+                };
+                let page_id = PageId::new(0, page_no);
+                if bmgr.fix_page(page_id, is_dirty).is_ok() {
                     bmgr.unfix_page(page_id);
                 }
             }
@@ -175,6 +258,11 @@ fn main() -> std::io::Result<()> {
 
     
     let duration = start_time.elapsed();
+    if let Some(cleaner) = cleaner.as_mut() {
+        if let Err(e) = cleaner.stop() {
+            eprintln!("Page cleaner: {}", e);
+        }
+    }
 
     
     println!("Hit number: {}", bmgr.get_hit_num());
@@ -192,6 +280,22 @@ fn main() -> std::io::Result<()> {
         bmgr.get_hit_num() as f64 * 100.0 / total_requests as f64
     );
     println!("IO number: {}", bmgr.get_io_num());
+    println!(
+        "Evictions: {} clean, {} dirty ({} pages written by cleaner)",
+        bmgr.get_clean_eviction_num(),
+        bmgr.get_dirty_eviction_num(),
+        bmgr.get_cleaner_write_num()
+    );
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    {
+        let disk_manager = bmgr.tablespace().file(0)?;
+        if let (Some(ratio), Some(stored)) = (
+            disk_manager.get_compression_ratio(),
+            disk_manager.get_stored_bytes(),
+        ) {
+            println!("Compression ratio: {:.2} ({} bytes stored)", ratio, stored);
+        }
+    }
     println!("Time taken: {:.2?}", duration);
 
     Ok(())
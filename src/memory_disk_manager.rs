@@ -0,0 +1,133 @@
+use crate::define::{is_valid_page_size, PageNo, PAGE_SIZE};
+use crate::disk_manager::DiskManager;
+use crate::extent::{Allocation, ExtentAllocator, SegmentId};
+use crate::page::PageData;
+use std::io::{Error, ErrorKind};
+use std::sync::RwLock;
+
+/// Disk manager that keeps every page in memory, for tests and simulations
+/// that should not touch the filesystem. Contents are lost when dropped.
+pub struct MemDiskManager {
+    page_size: usize,
+    pages: RwLock<Vec<PageData>>,
+    extents: ExtentAllocator,
+}
+
+impl MemDiskManager {
+    pub fn new() -> Self {
+        Self::with_pages(0)
+    }
+
+    /// Starts out with `num_pages` zeroed pages, like a freshly initialized
+    /// database file.
+    pub fn with_pages(num_pages: usize) -> Self {
+        Self::with_page_size(PAGE_SIZE, num_pages)
+    }
+
+    pub fn with_page_size(page_size: usize, num_pages: usize) -> Self {
+        assert!(is_valid_page_size(page_size), "unsupported page size {}", page_size);
+        Self {
+            page_size,
+            pages: RwLock::new((0..num_pages).map(|_| PageData::zeroed(page_size)).collect()),
+            extents: ExtentAllocator::new(num_pages as PageNo),
+        }
+    }
+
+    fn extend(&self, num_pages: PageNo) -> std::io::Result<()> {
+        self.pages
+            .write()
+            .unwrap()
+            .resize_with(num_pages as usize, || PageData::zeroed(self.page_size));
+        Ok(())
+    }
+
+    fn out_of_range(page_no: PageNo) -> Error {
+        Error::new(ErrorKind::UnexpectedEof, format!("page {} is past the end of storage", page_no))
+    }
+
+    fn check_len(&self, len: usize) -> std::io::Result<()> {
+        if len > self.page_size {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("buffer of {} bytes exceeds the page size {}", len, self.page_size),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for MemDiskManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DiskManager for MemDiskManager {
+    fn read_page(&self, page_no: PageNo, data: &mut [u8]) -> std::io::Result<()> {
+        self.check_len(data.len())?;
+        let pages = self.pages.read().unwrap();
+        let page = usize::try_from(page_no)
+            .ok()
+            .and_then(|index| pages.get(index))
+            .ok_or_else(|| Self::out_of_range(page_no))?;
+        data.copy_from_slice(&page[..data.len()]);
+        Ok(())
+    }
+
+    fn write_page(&self, page_no: PageNo, data: &[u8]) -> std::io::Result<()> {
+        self.check_len(data.len())?;
+        let mut pages = self.pages.write().unwrap();
+        let page = usize::try_from(page_no)
+            .ok()
+            .and_then(|index| pages.get_mut(index))
+            .ok_or_else(|| Self::out_of_range(page_no))?;
+        page[..data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn allocate_in(&self, segment: SegmentId) -> std::io::Result<PageNo> {
+        match self.extents.allocate_in(segment, |num_pages| self.extend(num_pages))? {
+            Allocation::Fresh(page_no) => Ok(page_no),
+            Allocation::Reused(page_no) => {
+                self.pages.write().unwrap()[page_no as usize].fill(0);
+                Ok(page_no)
+            }
+        }
+    }
+
+    fn allocate_pages(&self, n: PageNo) -> std::io::Result<PageNo> {
+        self.extents.allocate_pages(n, |num_pages| self.extend(num_pages))
+    }
+
+    fn deallocate(&self, page_no: PageNo) -> std::io::Result<()> {
+        self.extents.deallocate(page_no)
+    }
+
+    fn sync(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn num_pages(&self) -> PageNo {
+        self.pages.read().unwrap().len() as PageNo
+    }
+
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffers_larger_than_a_page_are_invalid_input() {
+        let disk_manager = MemDiskManager::with_pages(1);
+        let mut data = vec![0; PAGE_SIZE + 1];
+        assert_eq!(disk_manager.read_page(0, &mut data).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(disk_manager.write_page(0, &data).unwrap_err().kind(), ErrorKind::InvalidInput);
+        disk_manager.write_page(0, &[7; 16]).unwrap();
+        disk_manager.read_page(0, &mut data[..PAGE_SIZE]).unwrap();
+        assert_eq!((data[15], data[16]), (7, 0));
+    }
+}
@@ -0,0 +1,210 @@
+use crate::define::{PageNo, PAGE_SIZE};
+use crate::disk_manager::DiskManager;
+use crate::extent::{Allocation, ExtentAllocator, SegmentId};
+use crate::file_header::FileHeader;
+use memmap2::MmapRaw;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind};
+use std::sync::RwLock;
+
+/// Number of page latches; pages share them round robin.
+const PAGE_LATCH_STRIPES: usize = 64;
+
+/// Disk manager that maps the database file into memory.
+///
+/// Reads and writes are plain copies to and from the mapping; the OS decides
+/// when dirty pages reach the file, so `sync` is the only durability point.
+/// Copies share the mapping and only exclude copies of the same page, so
+/// they run in parallel; growing the file remaps it, which waits for all
+/// in-flight page copies. The file format, header included, is the same as
+/// `DSMgr`'s.
+pub struct MmapDiskManager {
+    file: File,
+    header: FileHeader,
+    /// The mapping, latched exclusively only to replace it.
+    map: RwLock<MmapRaw>,
+    /// Latches of the pages, shared by every page number with the same
+    /// remainder; a copy holds the one of its page.
+    page_latches: Vec<RwLock<()>>,
+    extents: ExtentAllocator,
+}
+
+impl MmapDiskManager {
+    pub fn open(filename: &str) -> std::io::Result<Self> {
+        Self::open_with_page_size(filename, PAGE_SIZE)
+    }
+
+    /// Like `open`; `page_size` only matters if the file is created.
+    pub fn open_with_page_size(filename: &str, page_size: usize) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(filename)?;
+        let header = FileHeader::init(&file, page_size, 0)?;
+        // The file only ever grows by whole pages, so a trailing partial
+        // page means it was damaged or is not ours; it is left alone.
+        let len = file.metadata()?.len();
+        if len % header.page_size as u64 != 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("file size {} is not a multiple of the page size {}", len, header.page_size),
+            ));
+        }
+        let map = Self::map(&file)?;
+        let num_pages = header.num_pages(map.len() as u64) as PageNo;
+        Ok(Self {
+            file,
+            header,
+            map: RwLock::new(map),
+            page_latches: (0..PAGE_LATCH_STRIPES).map(|_| RwLock::new(())).collect(),
+            extents: ExtentAllocator::new(num_pages),
+        })
+    }
+
+    fn map(file: &File) -> std::io::Result<MmapRaw> {
+        // The file is owned by this manager and only modified through the
+        // mapping or `set_len` under the mapping's write lock. Other processes
+        // truncating it behind our back is outside what we support.
+        MmapRaw::map_raw(file)
+    }
+
+    fn page_latch(&self, page_no: PageNo) -> &RwLock<()> {
+        &self.page_latches[(page_no % PAGE_LATCH_STRIPES as PageNo) as usize]
+    }
+
+    fn range(&self, map: &MmapRaw, page_no: PageNo, len: usize) -> std::io::Result<std::ops::Range<usize>> {
+        let start = usize::try_from(self.header.offset(page_no)).ok();
+        match start {
+            Some(start) if start + len <= map.len() && len <= self.header.page_size => Ok(start..start + len),
+            _ => Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("page {} is past the end of the mapping", page_no),
+            )),
+        }
+    }
+
+    /// Grows the file to `num_pages` pages and remaps it.
+    fn extend(&self, num_pages: PageNo) -> std::io::Result<()> {
+        let mut map = self.map.write().unwrap();
+        // `set_len` zero-fills the new pages.
+        self.file.set_len(self.header.offset(num_pages))?;
+        *map = Self::map(&self.file)?;
+        Ok(())
+    }
+}
+
+impl DiskManager for MmapDiskManager {
+    fn read_page(&self, page_no: PageNo, data: &mut [u8]) -> std::io::Result<()> {
+        let map = self.map.read().unwrap();
+        let range = self.range(&map, page_no, data.len())?;
+        let _page = self.page_latch(page_no).read().unwrap();
+        // SAFETY: `range` lies within the mapping, which stays in place while
+        // `map` is held, and nobody writes the page while `_page` is held.
+        unsafe { std::ptr::copy_nonoverlapping(map.as_ptr().add(range.start), data.as_mut_ptr(), data.len()) };
+        Ok(())
+    }
+
+    fn write_page(&self, page_no: PageNo, data: &[u8]) -> std::io::Result<()> {
+        let map = self.map.read().unwrap();
+        let range = self.range(&map, page_no, data.len())?;
+        let _page = self.page_latch(page_no).write().unwrap();
+        // SAFETY: as in `read_page`, and nobody else touches the page while
+        // `_page` is held exclusively.
+        unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), map.as_mut_ptr().add(range.start), data.len()) };
+        Ok(())
+    }
+
+    fn allocate_in(&self, segment: SegmentId) -> std::io::Result<PageNo> {
+        match self.extents.allocate_in(segment, |num_pages| self.extend(num_pages))? {
+            Allocation::Fresh(page_no) => Ok(page_no),
+            Allocation::Reused(page_no) => {
+                let map = self.map.read().unwrap();
+                let range = self.range(&map, page_no, self.header.page_size)?;
+                let _page = self.page_latch(page_no).write().unwrap();
+                // SAFETY: as in `write_page`.
+                unsafe { std::ptr::write_bytes(map.as_mut_ptr().add(range.start), 0, range.len()) };
+                Ok(page_no)
+            }
+        }
+    }
+
+    fn allocate_pages(&self, n: PageNo) -> std::io::Result<PageNo> {
+        self.extents.allocate_pages(n, |num_pages| self.extend(num_pages))
+    }
+
+    fn deallocate(&self, page_no: PageNo) -> std::io::Result<()> {
+        self.extents.deallocate(page_no)
+    }
+
+    fn sync(&self) -> std::io::Result<()> {
+        self.map.read().unwrap().flush()?;
+        self.file.sync_all()
+    }
+
+    fn num_pages(&self) -> PageNo {
+        self.extents.num_pages()
+    }
+
+    fn page_size(&self) -> usize {
+        self.header.page_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn concurrent_writes_and_growth_keep_every_page() {
+        let path = std::env::temp_dir().join(format!("adbs-mmap-{}.dbf", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let disk_manager = Arc::new(MmapDiskManager::open(path.to_str().unwrap()).unwrap());
+        let threads: Vec<_> = (0..4u8)
+            .map(|t| {
+                let disk_manager = Arc::clone(&disk_manager);
+                thread::spawn(move || {
+                    let mut written = Vec::new();
+                    for i in 0..200u8 {
+                        // Allocations remap the file under the other threads' copies.
+                        let page_no = disk_manager.allocate().unwrap();
+                        disk_manager.write_page(page_no, &[t ^ i; PAGE_SIZE]).unwrap();
+                        written.push((page_no, t ^ i));
+                    }
+                    written
+                })
+            })
+            .collect();
+        let mut data = vec![0; PAGE_SIZE];
+        for handle in threads {
+            for (page_no, byte) in handle.join().unwrap() {
+                disk_manager.read_page(page_no, &mut data).unwrap();
+                assert!(data.iter().all(|&b| b == byte), "page {} lost its contents", page_no);
+            }
+        }
+        drop(disk_manager);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn trailing_partial_page_is_invalid_data() {
+        let path = std::env::temp_dir().join(format!("adbs-mmap-partial-{}.dbf", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let disk_manager = MmapDiskManager::open(path.to_str().unwrap()).unwrap();
+        let page_no = disk_manager.allocate().unwrap();
+        disk_manager.write_page(page_no, &[3; PAGE_SIZE]).unwrap();
+        disk_manager.sync().unwrap();
+        drop(disk_manager);
+        let len = std::fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(len + 100).unwrap();
+
+        let error = MmapDiskManager::open(path.to_str().unwrap()).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        // Nothing was cut off.
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len + 100);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,68 +1,338 @@
 
 
+use crate::checksum::{crc32, crc32_update};
 use crate::define::{PageId, PAGE_SIZE};
-use std::sync::atomic::{AtomicI32, Ordering};
+use crate::wal::{Lsn, INVALID_LSN};
+use std::alloc::{self, Layout};
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+
+/// Alignment of every `PageData`, as required for `O_DIRECT` I/O.
+const PAGE_ALIGN: usize = 4096;
+
+/// Heap buffer of one page, aligned to 4096 bytes as required for `O_DIRECT`
+/// I/O. Every buffer handed to `DSMgr` should be one of these.
+pub struct PageData {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// SAFETY: `PageData` owns its allocation exclusively, like a `Box<[u8]>`.
+unsafe impl Send for PageData {}
+unsafe impl Sync for PageData {}
+
+impl PageData {
+    pub fn zeroed(page_size: usize) -> Self {
+        assert!(page_size > 0, "page size must be positive");
+        let layout = Self::layout(page_size);
+        // SAFETY: the layout has a non-zero size.
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        match NonNull::new(ptr) {
+            Some(ptr) => Self { ptr, len: page_size },
+            None => alloc::handle_alloc_error(layout),
+        }
+    }
+
+    fn layout(page_size: usize) -> Layout {
+        Layout::from_size_align(page_size, PAGE_ALIGN).expect("page size fits in a layout")
+    }
+}
+
+impl Default for PageData {
+    fn default() -> Self {
+        Self::zeroed(PAGE_SIZE)
+    }
+}
+
+impl Clone for PageData {
+    fn clone(&self) -> Self {
+        Self::from(&self[..])
+    }
+}
+
+impl Drop for PageData {
+    fn drop(&mut self) {
+        // SAFETY: allocated in `zeroed` with this very layout.
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), Self::layout(self.len)) };
+    }
+}
+
+impl From<&[u8]> for PageData {
+    fn from(data: &[u8]) -> Self {
+        let mut page = Self::zeroed(data.len());
+        page.copy_from_slice(data);
+        page
+    }
+}
+
+impl Deref for PageData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: `ptr` points to `len` initialized bytes owned by `self`.
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for PageData {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: as in `deref`, and `&mut self` guarantees exclusive access.
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+/// Every page starts with a header of this size:
+///
+/// | bytes  | field                                  |
+/// |--------|----------------------------------------|
+/// | 0..8   | u64 page LSN                           |
+/// | 8..12  | u32 CRC-32 of all other bytes          |
+/// | 12     | u8 page type                           |
+/// | 13     | u8 flags, defined by the page type     |
+/// | 14..16 | reserved, zero                         |
+/// | 16..20 | u32 start of the free space            |
+/// | 20..24 | u32 end of the free space              |
+pub const PAGE_HEADER_SIZE: usize = 24;
+
+/// The page LSN and checksum, which the pool maintains itself; logged
+/// updates cannot touch them.
+pub(crate) const MAINTAINED_HEADER_SIZE: usize = 12;
+
+const CHECKSUM_OFFSET: usize = 8;
+const PAGE_TYPE_OFFSET: usize = 12;
+const FLAGS_OFFSET: usize = 13;
+const FREE_START_OFFSET: usize = 16;
+const FREE_END_OFFSET: usize = 20;
+
+/// What a page holds, telling how to interpret everything after its
+/// header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PageType {
+    /// Never formatted; all zeros on disk.
+    Unformatted,
+    /// Formatted, with contents only its owner knows how to read.
+    Raw,
+    /// Variable-length records behind a slot directory, see `SlottedPage`.
+    Slotted,
+    /// Free space of the pages that follow it, see `FreeSpaceMap`.
+    FreeSpaceMap,
+    /// Key size and root of a B+ tree, see `BPlusTree`.
+    BTreeMeta,
+    /// Inner node or leaf of a B+ tree, told apart by their level.
+    BTreeNode,
+    /// Global depth and bucket of every slot of an extendible hash index,
+    /// see `ExtendibleHashIndex`.
+    HashDirectory,
+    /// Entries of an extendible hash index whose hashes share a suffix.
+    HashBucket,
+}
+
+impl PageType {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            PageType::Unformatted => 0,
+            PageType::Raw => 1,
+            PageType::Slotted => 2,
+            PageType::FreeSpaceMap => 3,
+            PageType::BTreeMeta => 4,
+            PageType::BTreeNode => 5,
+            PageType::HashDirectory => 6,
+            PageType::HashBucket => 7,
+        }
+    }
+
+    /// The page type stored as `value`, or `None` if there is none.
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(PageType::Unformatted),
+            1 => Some(PageType::Raw),
+            2 => Some(PageType::Slotted),
+            3 => Some(PageType::FreeSpaceMap),
+            4 => Some(PageType::BTreeMeta),
+            5 => Some(PageType::BTreeNode),
+            6 => Some(PageType::HashDirectory),
+            7 => Some(PageType::HashBucket),
+            _ => None,
+        }
+    }
+}
+
+/// The `page_lsn` stored in a page image.
+pub fn page_lsn(data: &[u8]) -> Lsn {
+    Lsn::from_le_bytes(data[..8].try_into().unwrap())
+}
+
+/// The checksum a page image should carry: the CRC-32 of the whole image
+/// except the checksum field.
+pub fn compute_checksum(data: &[u8]) -> u32 {
+    let crc = crc32(&data[..CHECKSUM_OFFSET]);
+    crc32_update(crc, &data[CHECKSUM_OFFSET + 4..])
+}
+
+/// Whether a page image read from disk is intact: its checksum matches, or
+/// it is all zeros because it was never written.
+pub fn verify_checksum(data: &[u8]) -> bool {
+    let stored = u32::from_le_bytes(data[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4].try_into().unwrap());
+    stored == compute_checksum(data) || data.iter().all(|&byte| byte == 0)
+}
+
+/// Stores the checksum of a page image about to be written.
+pub(crate) fn stamp_checksum(data: &mut [u8]) {
+    let checksum = compute_checksum(data);
+    data[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4].copy_from_slice(&checksum.to_le_bytes());
+}
 
 pub struct Page {
-    page_id: PageId,
+    /// `None` while the frame holds no page.
+    page_id: Option<PageId>,
     is_dirty: bool,
-    data: [u8; PAGE_SIZE],
-    pin_count: AtomicI32,
+    /// LSN of the oldest logged change not yet written back, or
+    /// `INVALID_LSN`.
+    rec_lsn: Lsn,
+    data: PageData,
+}
+
+impl Default for Page {
+    fn default() -> Self {
+        Self::new(PAGE_SIZE)
+    }
 }
 
 impl Page {
-    pub fn new() -> Self {
+    pub fn new(page_size: usize) -> Self {
         Self {
-            page_id: -1,
+            page_id: None,
             is_dirty: false,
-            data: [0; PAGE_SIZE],
-            pin_count: AtomicI32::new(0),
+            rec_lsn: INVALID_LSN,
+            data: PageData::zeroed(page_size),
         }
     }
 
-    pub fn with_page_id(page_id: PageId) -> Self {
+    pub fn with_page_id(page_id: PageId, page_size: usize) -> Self {
         Self {
-            page_id,
+            page_id: Some(page_id),
             is_dirty: false,
-            data: [0; PAGE_SIZE],
-            pin_count: AtomicI32::new(0),
+            rec_lsn: INVALID_LSN,
+            data: PageData::zeroed(page_size),
         }
     }
 
-    pub fn get_page_id(&self) -> PageId {
+    pub fn get_page_id(&self) -> Option<PageId> {
         self.page_id
     }
 
     pub fn set_page_id(&mut self, page_id: PageId) {
-        self.page_id = page_id;
+        self.page_id = Some(page_id);
     }
 
-    pub fn get_data(&self) -> &[u8; PAGE_SIZE] {
+    pub fn get_data(&self) -> &[u8] {
         &self.data
     }
 
-    
-    pub fn set_data(&mut self, data: &[u8; PAGE_SIZE]) {
+    pub fn get_data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    /// Overwrites the page contents; `data` must be exactly one page long.
+    pub fn set_data(&mut self, data: &[u8]) {
         self.data.copy_from_slice(data);
     }
 
-    pub fn is_dirty(&self) -> bool {
-        self.is_dirty
+    /// LSN of the last logged change to the page, kept in its first eight
+    /// bytes so it is written out with the page.
+    pub fn get_page_lsn(&self) -> Lsn {
+        page_lsn(&self.data)
     }
 
-    pub fn set_dirty(&mut self, is_dirty: bool) {
-        self.is_dirty = is_dirty;
+    /// Stamps the page with the LSN of a change just logged. The first such
+    /// change since the page was last written back also becomes its
+    /// `rec_lsn`.
+    pub fn set_page_lsn(&mut self, lsn: Lsn) {
+        self.data[..8].copy_from_slice(&lsn.to_le_bytes());
+        if self.rec_lsn == INVALID_LSN {
+            self.rec_lsn = lsn;
+        }
+    }
+
+    /// LSN of the oldest logged change that may be missing from disk, or
+    /// `INVALID_LSN` if every logged change has been written back.
+    pub fn get_rec_lsn(&self) -> Lsn {
+        self.rec_lsn
+    }
+
+    pub fn set_rec_lsn(&mut self, rec_lsn: Lsn) {
+        self.rec_lsn = rec_lsn;
+    }
+
+    /// Checksum as of the last time the page was read or written; the
+    /// in-memory copy does not keep it up to date.
+    pub fn get_checksum(&self) -> u32 {
+        self.u32_at(CHECKSUM_OFFSET)
+    }
+
+    /// Formats the page as an empty page of `page_type`: everything after
+    /// the page LSN is cleared and all space after the header is free.
+    pub fn init(&mut self, page_type: PageType) {
+        self.data[CHECKSUM_OFFSET..].fill(0);
+        self.set_page_type(page_type);
+        self.set_free_start(PAGE_HEADER_SIZE);
+        self.set_free_end(self.data.len());
+    }
+
+    /// The page's type, or `None` if its type byte is not one this version
+    /// knows.
+    pub fn get_page_type(&self) -> Option<PageType> {
+        PageType::from_u8(self.data[PAGE_TYPE_OFFSET])
     }
 
-    pub fn get_pin_count(&self) -> i32 {
-        self.pin_count.load(Ordering::SeqCst)
+    pub fn set_page_type(&mut self, page_type: PageType) {
+        self.data[PAGE_TYPE_OFFSET] = page_type.to_u8();
     }
 
-    pub fn inc_pin_count(&self) {
-        self.pin_count.fetch_add(1, Ordering::SeqCst);
+    pub fn get_flags(&self) -> u8 {
+        self.data[FLAGS_OFFSET]
     }
 
-    pub fn dec_pin_count(&self) {
-        self.pin_count.fetch_sub(1, Ordering::SeqCst);
+    pub fn set_flags(&mut self, flags: u8) {
+        self.data[FLAGS_OFFSET] = flags;
+    }
+
+    /// Offset of the first free byte.
+    pub fn get_free_start(&self) -> usize {
+        self.u32_at(FREE_START_OFFSET) as usize
+    }
+
+    pub fn set_free_start(&mut self, free_start: usize) {
+        self.set_u32_at(FREE_START_OFFSET, free_start as u32);
+    }
+
+    /// Offset just past the last free byte.
+    pub fn get_free_end(&self) -> usize {
+        self.u32_at(FREE_END_OFFSET) as usize
+    }
+
+    pub fn set_free_end(&mut self, free_end: usize) {
+        self.set_u32_at(FREE_END_OFFSET, free_end as u32);
+    }
+
+    /// Bytes between the free space pointers.
+    pub fn get_free_space(&self) -> usize {
+        self.get_free_end().saturating_sub(self.get_free_start())
+    }
+
+    fn u32_at(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.data[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn set_u32_at(&mut self, offset: usize, value: u32) {
+        self.data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.is_dirty
+    }
+
+    pub fn set_dirty(&mut self, is_dirty: bool) {
+        self.is_dirty = is_dirty;
     }
 }
@@ -0,0 +1,217 @@
+use crate::buffer_pool_manager::BufferPoolManager;
+use crate::disk_manager::DiskManager;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+pub struct PageCleanerConfig {
+    /// How long the cleaner sleeps between passes.
+    pub interval: Duration,
+    /// Maximum number of pages written per pass.
+    pub batch_size: usize,
+    /// Below this fraction of dirty frames a pass does nothing.
+    pub min_dirty_ratio: f64,
+    /// Above this fraction of dirty frames a pass scans the whole replacer
+    /// instead of only the next `batch_size` victims.
+    pub max_dirty_ratio: f64,
+}
+
+impl Default for PageCleanerConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(10),
+            batch_size: 32,
+            min_dirty_ratio: 0.1,
+            max_dirty_ratio: 0.5,
+        }
+    }
+}
+
+/// Background thread that writes back dirty, unpinned pages close to the
+/// eviction end of the replacer. A failed pass does not stop it; the last
+/// error is kept and returned by `stop`. Stops when dropped.
+pub struct PageCleaner {
+    stop: Option<Sender<()>>,
+    /// Returns the last error a pass failed with.
+    handle: Option<JoinHandle<Option<std::io::Error>>>,
+}
+
+impl PageCleaner {
+    pub fn start<D: DiskManager>(bmgr: Arc<BufferPoolManager<D>>, config: PageCleanerConfig) -> Self {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            let mut last_error = None;
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(config.interval) {
+                if let Err(e) = clean_pass(&bmgr, &config) {
+                    last_error = Some(e);
+                }
+            }
+            last_error
+        });
+
+        Self {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+
+    /// Stops the thread and returns the last error a pass failed with, even
+    /// if later passes succeeded. Only the first call can fail.
+    pub fn stop(&mut self) -> std::io::Result<()> {
+        self.stop.take();
+        match self.handle.take().map(|handle| handle.join().expect("Page cleaner panicked")) {
+            Some(Some(e)) => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for PageCleaner {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+/// One pass of the cleaner, sized by how dirty the pool is. Returns how many
+/// pages were written.
+fn clean_pass<D: DiskManager>(bmgr: &BufferPoolManager<D>, config: &PageCleanerConfig) -> std::io::Result<usize> {
+    let dirty_ratio = bmgr.get_dirty_ratio();
+    if dirty_ratio < config.min_dirty_ratio {
+        return Ok(0);
+    }
+    let scan_depth = if dirty_ratio > config.max_dirty_ratio {
+        bmgr.get_frame_num()
+    } else {
+        config.batch_size
+    };
+    bmgr.clean_pages(scan_depth, config.batch_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer_pool_manager::ReplacePolicyType;
+    use crate::define::PageId;
+    use crate::fault_injection::{FaultConfig, FaultRule, FaultyDiskManager};
+    use crate::memory_disk_manager::MemDiskManager;
+    use std::time::Instant;
+
+    type FaultyPool = BufferPoolManager<FaultyDiskManager<MemDiskManager>>;
+
+    fn pool(num_pages: usize, frame_num: usize) -> Arc<FaultyPool> {
+        let disk_manager = FaultyDiskManager::new(MemDiskManager::with_pages(num_pages), FaultConfig::default(), 0);
+        Arc::new(BufferPoolManager::with_disk_manager(disk_manager, ReplacePolicyType::LRU, frame_num))
+    }
+
+    fn config() -> PageCleanerConfig {
+        PageCleanerConfig {
+            interval: Duration::from_millis(1),
+            batch_size: 8,
+            min_dirty_ratio: 0.0,
+            max_dirty_ratio: 1.0,
+        }
+    }
+
+    fn is_dirty(bmgr: &FaultyPool, page_id: PageId) -> bool {
+        let frame_id = bmgr.fix_page(page_id, false).unwrap();
+        let is_dirty = bmgr.get_page_shared(frame_id).is_dirty();
+        bmgr.unfix_page(page_id);
+        is_dirty
+    }
+
+    fn wait_for_writes(bmgr: &FaultyPool, num_writes: i32) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while bmgr.get_cleaner_write_num() < num_writes {
+            assert!(Instant::now() < deadline, "the cleaner wrote {} pages", bmgr.get_cleaner_write_num());
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn cleaner_writes_unpinned_pages_and_stops() {
+        let bmgr = pool(8, 8);
+        for page_no in 0..5 {
+            bmgr.fix_page(PageId::new(0, page_no), true).unwrap();
+        }
+        for page_no in 0..4 {
+            bmgr.unfix_page(PageId::new(0, page_no));
+        }
+
+        // Page 4 is still pinned, so it is left dirty, pass after pass.
+        let pinned = PageId::new(0, 4);
+        assert_eq!(clean_pass(&bmgr, &config()).unwrap(), 4);
+        assert_eq!(clean_pass(&bmgr, &config()).unwrap(), 0);
+        assert!((0..4).all(|page_no| !is_dirty(&bmgr, PageId::new(0, page_no))));
+        assert!(is_dirty(&bmgr, pinned));
+
+        // The thread picks it up once it is unpinned.
+        bmgr.unfix_page(pinned);
+        let mut cleaner = PageCleaner::start(Arc::clone(&bmgr), config());
+        wait_for_writes(&bmgr, 5);
+        assert!(!is_dirty(&bmgr, pinned));
+
+        // Nothing is written once the cleaner is stopped.
+        cleaner.stop().unwrap();
+        bmgr.fix_page(pinned, true).unwrap();
+        bmgr.unfix_page(pinned);
+        assert_eq!(bmgr.get_cleaner_write_num(), 5);
+        assert!(is_dirty(&bmgr, pinned));
+        assert_eq!(bmgr.get_dirty_eviction_num(), 0);
+    }
+
+    #[test]
+    fn stopping_returns_the_last_failed_pass() {
+        let bmgr = pool(2, 2);
+        let page_id = PageId::new(0, 0);
+        bmgr.fix_page(page_id, true).unwrap();
+        bmgr.unfix_page(page_id);
+        bmgr.tablespace().file(0).unwrap().set_config(FaultConfig {
+            write_error: FaultRule::always(),
+            ..FaultConfig::default()
+        });
+
+        let mut cleaner = PageCleaner::start(Arc::clone(&bmgr), config());
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while bmgr.tablespace().file(0).unwrap().get_injected_num() == 0 {
+            assert!(Instant::now() < deadline, "the cleaner never tried to write");
+            thread::yield_now();
+        }
+        let error = cleaner.stop().unwrap_err();
+        assert!(error.to_string().contains("write error"), "{}", error);
+        assert!(cleaner.stop().is_ok());
+        assert!(is_dirty(&bmgr, page_id));
+    }
+
+    #[test]
+    fn cleaner_leaves_a_page_being_evicted_alone() {
+        let bmgr = pool(2, 1);
+        let (victim, page_id) = (PageId::new(0, 0), PageId::new(0, 1));
+        bmgr.fix_page(victim, true).unwrap();
+        bmgr.unfix_page(victim);
+        bmgr.tablespace().file(0).unwrap().set_config(FaultConfig {
+            latency: FaultRule::on_pages([0]),
+            delay: Duration::from_millis(200),
+            ..FaultConfig::default()
+        });
+
+        // The eviction writes the dirty victim slowly; a pass run meanwhile
+        // must not touch its frame.
+        let fix = {
+            let bmgr = Arc::clone(&bmgr);
+            thread::spawn(move || {
+                bmgr.fix_page(page_id, false).unwrap();
+                bmgr.unfix_page(page_id);
+            })
+        };
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while bmgr.get_dirty_eviction_num() == 0 {
+            assert!(Instant::now() < deadline, "the eviction never started");
+            thread::yield_now();
+        }
+        assert_eq!(clean_pass(&bmgr, &config()).unwrap(), 0);
+        fix.join().unwrap();
+        assert_eq!(bmgr.get_cleaner_write_num(), 0);
+        assert_eq!(bmgr.get_dirty_eviction_num(), 1);
+    }
+}
@@ -0,0 +1,222 @@
+use crate::buffer_pool_manager::BufferPoolManager;
+use crate::data_storage_manager::DSMgr;
+use crate::disk_manager::DiskManager;
+use crate::define::{FileId, PageId, PageNo};
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+#[derive(Clone, Copy)]
+pub struct ReadAheadConfig {
+    /// Number of consecutive ascending pages of one file that counts as a
+    /// sequential scan.
+    pub trigger: usize,
+    /// How many pages past the current one to keep loaded during a scan.
+    /// Zero disables read-ahead.
+    pub window: usize,
+}
+
+impl Default for ReadAheadConfig {
+    fn default() -> Self {
+        Self {
+            trigger: 4,
+            window: 32,
+        }
+    }
+}
+
+/// Watches the stream of fixed page ids and decides when and what to read
+/// ahead. Runs are tracked per file, so scans of different files do not
+/// break each other's runs.
+pub(crate) struct SequentialDetector {
+    config: ReadAheadConfig,
+    runs: HashMap<FileId, Run>,
+}
+
+/// The sequential run of one file.
+struct Run {
+    last_page_no: PageNo,
+    length: usize,
+    /// First page not yet requested for read-ahead in this run.
+    next_prefetch: PageNo,
+}
+
+impl SequentialDetector {
+    pub(crate) fn new(config: ReadAheadConfig) -> Self {
+        Self {
+            config,
+            runs: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.config.window > 0
+    }
+
+    /// Records an access and returns the pages to read ahead, if any. Pages
+    /// are requested half a window at a time, so a scan never catches up with
+    /// the read-ahead.
+    /// `num_pages` is the size of the file `page_id` belongs to.
+    pub(crate) fn on_access(&mut self, page_id: PageId, num_pages: PageNo) -> Option<Vec<PageId>> {
+        if !self.is_enabled() {
+            return None;
+        }
+
+        let page_no = page_id.page_no;
+        let run = self.runs.entry(page_id.file_id).or_insert(Run {
+            last_page_no: page_no,
+            length: 0,
+            next_prefetch: page_no + 1,
+        });
+        if run.length > 0 && page_no.checked_sub(1) == Some(run.last_page_no) {
+            run.length += 1;
+        } else {
+            run.length = 1;
+            run.next_prefetch = page_no + 1;
+        }
+        run.last_page_no = page_no;
+
+        let window = self.config.window as PageNo;
+        if run.length < self.config.trigger || run.next_prefetch > page_no + window / 2 {
+            return None;
+        }
+        let start = run.next_prefetch.max(page_no + 1);
+        let end = (page_no + 1 + window).min(num_pages);
+        if start >= end {
+            return None;
+        }
+        run.next_prefetch = end;
+        Some((start..end).map(|page_no| PageId::new(page_id.file_id, page_no)).collect())
+    }
+}
+
+/// Background thread that performs the read-ahead requested by the pool's
+/// sequential detector, so the scanning thread never waits for it. Without
+/// one, read-ahead runs inline in `fix_page`. Stops when dropped.
+pub struct ReadAhead<D: DiskManager = DSMgr> {
+    bmgr: Arc<BufferPoolManager<D>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl<D: DiskManager> ReadAhead<D> {
+    pub fn start(bmgr: Arc<BufferPoolManager<D>>, config: ReadAheadConfig) -> Self {
+        let (sender, receiver) = mpsc::channel::<Vec<PageId>>();
+        bmgr.set_read_ahead(config);
+        bmgr.set_read_ahead_worker(Some(sender));
+
+        let worker = Arc::clone(&bmgr);
+        let handle = thread::spawn(move || {
+            while let Ok(page_ids) = receiver.recv() {
+                // A scan that has moved on makes older requests stale; only
+                // the most recent one of each file is worth reading.
+                let mut latest = HashMap::new();
+                for page_ids in [page_ids].into_iter().chain(receiver.try_iter()) {
+                    if let Some(page_id) = page_ids.first() {
+                        latest.insert(page_id.file_id, page_ids);
+                    }
+                }
+                for page_ids in latest.into_values() {
+                    // Read-ahead is best effort; a full pool simply loads less.
+                    let _ = worker.prefetch(&page_ids);
+                }
+            }
+        });
+
+        Self {
+            bmgr,
+            handle: Some(handle),
+        }
+    }
+
+    pub fn stop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            self.bmgr.set_read_ahead_worker(None);
+            handle.join().expect("Read-ahead worker panicked");
+        }
+    }
+}
+
+impl<D: DiskManager> Drop for ReadAhead<D> {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer_pool_manager::ReplacePolicyType;
+    use crate::memory_disk_manager::MemDiskManager;
+    use std::time::{Duration, Instant};
+
+    fn config() -> ReadAheadConfig {
+        ReadAheadConfig { trigger: 2, window: 8 }
+    }
+
+    fn pages(file_id: FileId, page_nos: std::ops::Range<PageNo>) -> Option<Vec<PageId>> {
+        Some(page_nos.map(|page_no| PageId::new(file_id, page_no)).collect())
+    }
+
+    #[test]
+    fn detector_reads_half_a_window_ahead_up_to_the_end_of_the_file() {
+        let mut detector = SequentialDetector::new(config());
+        assert_eq!(detector.on_access(PageId::new(0, 0), 12), None);
+        assert_eq!(detector.on_access(PageId::new(0, 1), 12), pages(0, 2..10));
+        for page_no in 2..6 {
+            assert_eq!(detector.on_access(PageId::new(0, page_no), 12), None);
+        }
+        assert_eq!(detector.on_access(PageId::new(0, 6), 12), pages(0, 10..12));
+        assert_eq!(detector.on_access(PageId::new(0, 10), 12), None);
+
+        // A jump starts a new run.
+        assert_eq!(detector.on_access(PageId::new(0, 3), 12), None);
+        assert_eq!(detector.on_access(PageId::new(0, 4), 12), pages(0, 5..12));
+
+        let mut detector = SequentialDetector::new(ReadAheadConfig { trigger: 2, window: 0 });
+        assert!((0..4).all(|page_no| detector.on_access(PageId::new(0, page_no), 12).is_none()));
+    }
+
+    #[test]
+    fn detector_keeps_a_run_per_file() {
+        let mut detector = SequentialDetector::new(config());
+        assert_eq!(detector.on_access(PageId::new(0, 0), 100), None);
+        assert_eq!(detector.on_access(PageId::new(1, 50), 100), None);
+        assert_eq!(detector.on_access(PageId::new(0, 1), 100), pages(0, 2..10));
+        assert_eq!(detector.on_access(PageId::new(1, 51), 100), pages(1, 52..60));
+        // The same page number in another file does not continue a run.
+        assert_eq!(detector.on_access(PageId::new(1, 2), 100), None);
+        assert_eq!(detector.on_access(PageId::new(0, 2), 100), None);
+    }
+
+    #[test]
+    fn worker_loads_the_pages_ahead_of_a_scan() {
+        let bmgr = Arc::new(BufferPoolManager::with_disk_manager(
+            MemDiskManager::with_pages(16),
+            ReplacePolicyType::LRU,
+            16,
+        ));
+        let mut read_ahead = ReadAhead::start(Arc::clone(&bmgr), config());
+        for page_no in 0..2 {
+            bmgr.fix_page(PageId::new(0, page_no), false).unwrap();
+            bmgr.unfix_page(PageId::new(0, page_no));
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while bmgr.get_prefetch_num() < 8 {
+            assert!(Instant::now() < deadline, "read ahead {} pages", bmgr.get_prefetch_num());
+            thread::yield_now();
+        }
+        read_ahead.stop();
+        assert_eq!(bmgr.get_prefetch_num(), 8);
+
+        // Without the worker, the rest of the file is read ahead inline.
+        let num_hits = bmgr.get_hit_num();
+        for page_no in 2..10 {
+            bmgr.fix_page(PageId::new(0, page_no), false).unwrap();
+            bmgr.unfix_page(PageId::new(0, page_no));
+        }
+        assert_eq!(bmgr.get_hit_num(), num_hits + 8);
+        assert_eq!(bmgr.get_prefetch_num(), 13);
+    }
+}
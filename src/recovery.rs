@@ -0,0 +1,358 @@
+use crate::buffer_pool_manager::BufferPoolManager;
+use crate::checkpoint::Checkpoint;
+use crate::define::PageId;
+use crate::disk_manager::DiskManager;
+use crate::wal::{LogBody, Lsn, TxnId, TxnState, INVALID_LSN};
+use std::collections::{BinaryHeap, HashMap};
+use std::io::{Error, ErrorKind};
+
+/// What `BufferPoolManager::recover` found and did.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// Log records read by the analysis pass.
+    pub analyzed_num: usize,
+    /// Updates and CLRs reapplied because the page predated them.
+    pub redone_num: usize,
+    /// Pages rebuilt from an image in the log rather than read from disk.
+    pub restored_num: usize,
+    /// Transactions rolled back, and the updates undone for them.
+    pub rolled_back_num: usize,
+    pub undone_num: usize,
+}
+
+impl<D: DiskManager> BufferPoolManager<D> {
+    /// Brings the pool's files back to the state the log describes after a
+    /// crash, in the three ARIES passes:
+    ///
+    /// - analysis scans the log from the last checkpoint, if any, and
+    ///   rebuilds the transaction table and the dirty page table, i.e. each
+    ///   page's first LSN that may be missing from disk;
+    /// - redo repeats history from the oldest of those LSNs, reapplying every
+    ///   update and CLR newer than the page it touches, losers included.
+    ///   A page with an image in the log is rebuilt from it without reading
+    ///   the copy on disk, so a page torn by the crash is repaired; a torn
+    ///   page without one fails recovery with `InvalidData`;
+    /// - undo rolls back the transactions that never committed, newest
+    ///   record first across all of them, logging a CLR per update so that a
+    ///   crash during recovery never undoes anything twice.
+    ///
+    /// Committed transactions without an end record get one. Must run before
+    /// the pool is used otherwise; all files the log refers to have to be in
+    /// the pool.
+    pub fn recover(&self) -> std::io::Result<RecoveryReport> {
+        let log_manager = self.require_log()?;
+        let mut report = RecoveryReport::default();
+
+        let checkpoint = Checkpoint::read(&log_manager.checkpoint_path())?;
+        let start_lsn = checkpoint
+            .as_ref()
+            .map_or(log_manager.get_first_lsn(), |checkpoint| checkpoint.begin_lsn);
+        let mut transactions: HashMap<TxnId, (TxnState, Lsn)> = HashMap::new();
+        let mut dirty_pages: HashMap<PageId, Lsn> = HashMap::new();
+        if let Some(checkpoint) = checkpoint {
+            transactions.extend(
+                checkpoint
+                    .transactions
+                    .into_iter()
+                    .map(|(txn_id, txn_state, last_lsn)| (txn_id, (txn_state, last_lsn))),
+            );
+            dirty_pages.extend(checkpoint.dirty_pages);
+        }
+        for record in log_manager.iter(start_lsn)? {
+            let record = record?;
+            report.analyzed_num += 1;
+            let txn_state = match &record.body {
+                LogBody::End => {
+                    transactions.remove(&record.txn_id);
+                    continue;
+                }
+                LogBody::Commit => TxnState::Committed,
+                LogBody::Abort | LogBody::Clr { .. } => TxnState::Aborting,
+                LogBody::Begin | LogBody::Update { .. } | LogBody::PageImage { .. } => transactions
+                    .get(&record.txn_id)
+                    .map_or(TxnState::Running, |(txn_state, _)| *txn_state),
+            };
+            transactions.insert(record.txn_id, (txn_state, record.lsn));
+            if let LogBody::Update { page_id, .. } | LogBody::Clr { page_id, .. } | LogBody::PageImage { page_id, .. } =
+                &record.body
+            {
+                dirty_pages.entry(*page_id).or_insert(record.lsn);
+            }
+        }
+
+        // Pages that failed their checksum, left torn by the crash. Their
+        // changes are skipped until an image of the page turns up, which
+        // already holds them.
+        let mut torn_pages = HashMap::new();
+        if let Some(&redo_lsn) = dirty_pages.values().min() {
+            for record in log_manager.iter(redo_lsn)? {
+                let record = record?;
+                let (page_id, offset, image) = match &record.body {
+                    LogBody::Update {
+                        page_id, offset, after, ..
+                    }
+                    | LogBody::Clr {
+                        page_id, offset, after, ..
+                    } => (*page_id, *offset as usize, after),
+                    LogBody::PageImage { page_id, image } => (*page_id, 0, image),
+                    _ => continue,
+                };
+                if dirty_pages.get(&page_id).is_none_or(|&rec_lsn| record.lsn < rec_lsn) {
+                    continue;
+                }
+                if let LogBody::PageImage { .. } = record.body {
+                    self.restore_page(page_id, image, record.lsn)?;
+                    torn_pages.remove(&page_id);
+                    report.restored_num += 1;
+                    continue;
+                }
+                if torn_pages.contains_key(&page_id) {
+                    continue;
+                }
+                let redone = self.with_page(page_id, |frame_id| {
+                    let mut page = self.get_page(frame_id);
+                    if page.get_page_lsn() >= record.lsn {
+                        return Ok(false);
+                    }
+                    page.get_data_mut()[offset..offset + image.len()].copy_from_slice(image);
+                    page.set_page_lsn(record.lsn);
+                    Ok(true)
+                });
+                match redone {
+                    Ok(true) => report.redone_num += 1,
+                    Ok(false) => {}
+                    Err(e) if e.kind() == ErrorKind::InvalidData => {
+                        torn_pages.insert(page_id, e);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        if let Some((page_id, e)) = torn_pages.into_iter().next() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("{}, and the log holds no image of page {} to rebuild it from", e, page_id),
+            ));
+        }
+
+        // Losers are undone together, always continuing with the newest
+        // record any of them still has to undo.
+        let mut last_lsns = HashMap::new();
+        let mut to_undo = BinaryHeap::new();
+        for (txn_id, (txn_state, last_lsn)) in transactions {
+            if txn_state == TxnState::Committed {
+                log_manager.append(txn_id, last_lsn, LogBody::End)?;
+            } else {
+                last_lsns.insert(txn_id, last_lsn);
+                to_undo.push((last_lsn, txn_id));
+            }
+        }
+        while let Some((lsn, txn_id)) = to_undo.pop() {
+            let record = log_manager.read(lsn)?;
+            let last_lsn = last_lsns[&txn_id];
+            let (undo_next, last_lsn) = self.undo(&record, last_lsn)?;
+            if matches!(record.body, LogBody::Update { .. }) {
+                report.undone_num += 1;
+            }
+            if undo_next == INVALID_LSN {
+                log_manager.append(txn_id, last_lsn, LogBody::End)?;
+                report.rolled_back_num += 1;
+            } else {
+                last_lsns.insert(txn_id, last_lsn);
+                to_undo.push((undo_next, txn_id));
+            }
+        }
+        log_manager.flush(Lsn::MAX)?;
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::buffer_pool_manager::{BufferPoolManager, ReplacePolicyType};
+    use crate::data_storage_manager::DSMgr;
+    use crate::define::{PageId, PAGE_SIZE};
+    use crate::fault_injection::{FaultConfig, FaultRule, FaultyDiskManager};
+    use crate::page::PAGE_HEADER_SIZE;
+    use crate::wal::{LogBody, LogManager, Lsn};
+    use std::sync::Arc;
+
+    /// A fresh directory for one test's database.
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("adbs-recovery-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    const OFFSET: usize = PAGE_HEADER_SIZE + 8;
+
+    /// Opens the database in `path` on a pool of two frames, so that the
+    /// pages touched below keep getting evicted, dirty ones included. Log
+    /// segments are small, but still fit a page image.
+    fn open(path: &str) -> BufferPoolManager<DSMgr> {
+        let bmgr = BufferPoolManager::with_disk_manager(DSMgr::open_file(path).unwrap(), ReplacePolicyType::LRU, 2);
+        let log_manager = LogManager::with_segment_size(&format!("{}.wal", path), 16384).unwrap();
+        bmgr.set_log_manager(Arc::new(log_manager)).unwrap();
+        bmgr
+    }
+
+    fn read(bmgr: &BufferPoolManager<DSMgr>, page_no: u64) -> Vec<u8> {
+        let page_id = PageId::new(0, page_no);
+        bmgr.with_page(page_id, |frame_id| Ok(bmgr.get_page(frame_id).get_data()[OFFSET..OFFSET + 4].to_vec()))
+            .unwrap()
+    }
+
+    #[test]
+    fn losers_are_rolled_back_and_clrs_are_not_undone_again() {
+        let dir = temp_dir("losers");
+        let path = dir.join("db").to_str().unwrap().to_owned();
+
+        let bmgr = open(&path);
+        for _ in 0..4 {
+            let mut page_id = PageId::new(0, 0);
+            bmgr.fix_new_page(0, &mut page_id).unwrap();
+            bmgr.unfix_page(page_id);
+        }
+        let mut winner = bmgr.begin().unwrap();
+        bmgr.update(&mut winner, PageId::new(0, 0), OFFSET, &[1; 4]).unwrap();
+        bmgr.commit(winner).unwrap();
+
+        let mut loser = bmgr.begin().unwrap();
+        bmgr.update(&mut loser, PageId::new(0, 1), OFFSET, &[2; 4]).unwrap();
+        bmgr.update(&mut loser, PageId::new(0, 2), OFFSET, &[2; 4]).unwrap();
+        bmgr.update(&mut loser, PageId::new(0, 1), OFFSET, &[3; 4]).unwrap();
+
+        // Crashes halfway through its rollback: the newest update is undone
+        // and compensated, the one before it is not.
+        let mut aborting = bmgr.begin().unwrap();
+        bmgr.update(&mut aborting, PageId::new(0, 3), OFFSET, &[4; 4]).unwrap();
+        bmgr.update(&mut aborting, PageId::new(0, 3), OFFSET, &[5; 4]).unwrap();
+        let log_manager = Arc::clone(bmgr.log_manager().unwrap());
+        let abort_lsn = log_manager.append(aborting.id(), aborting.last_lsn(), LogBody::Abort).unwrap();
+        let record = log_manager.read(aborting.last_lsn()).unwrap();
+        bmgr.undo(&record, abort_lsn).unwrap();
+        assert_eq!(read(&bmgr, 3), [4; 4]);
+
+        log_manager.flush(Lsn::MAX).unwrap();
+        // Whatever was not evicted by now is lost.
+        drop((bmgr, log_manager));
+
+        let bmgr = open(&path);
+        let report = bmgr.recover().unwrap();
+        assert_eq!(report.rolled_back_num, 2);
+        assert_eq!(report.undone_num, 4, "the compensated update is not undone twice");
+        assert_eq!(read(&bmgr, 0), [1; 4]);
+        assert_eq!(read(&bmgr, 1), [0; 4]);
+        assert_eq!(read(&bmgr, 2), [0; 4]);
+        assert_eq!(read(&bmgr, 3), [0; 4]);
+        assert!(bmgr.log_manager().unwrap().active_transactions().is_empty());
+        drop(bmgr);
+
+        // Recovery logged end records, so a second run has nothing to undo.
+        let bmgr = open(&path);
+        let report = bmgr.recover().unwrap();
+        assert_eq!((report.rolled_back_num, report.undone_num), (0, 0));
+        assert_eq!(read(&bmgr, 0), [1; 4]);
+        assert_eq!(read(&bmgr, 1), [0; 4]);
+        drop(bmgr);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn every_data_file_is_recovered() {
+        let dir = temp_dir("files");
+        let paths = [dir.join("a"), dir.join("b")];
+        let paths: Vec<&str> = paths.iter().map(|path| path.to_str().unwrap()).collect();
+
+        let bmgr = BufferPoolManager::open(&paths, ReplacePolicyType::LRU, 16).unwrap();
+        let mut page_id = PageId::new(1, 0);
+        bmgr.fix_new_page(1, &mut page_id).unwrap();
+        bmgr.unfix_page(page_id);
+        let mut txn = bmgr.begin().unwrap();
+        bmgr.update(&mut txn, page_id, OFFSET, &[6; 4]).unwrap();
+        bmgr.commit(txn).unwrap();
+        drop(bmgr);
+
+        // The log refers to file 1, which a pool of file 0 alone lacks.
+        assert!(BufferPoolManager::new(paths[0], ReplacePolicyType::LRU, 16).is_err());
+        let bmgr = BufferPoolManager::open(&paths, ReplacePolicyType::LRU, 16).unwrap();
+        let data = bmgr.with_page(page_id, |frame_id| Ok(bmgr.get_page(frame_id).get_data()[OFFSET]));
+        assert_eq!(data.unwrap(), 6);
+        drop(bmgr);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn close_leaves_nothing_to_recover() {
+        let dir = temp_dir("close");
+        let path = dir.join("db").to_str().unwrap().to_owned();
+        let bmgr = BufferPoolManager::new(&path, ReplacePolicyType::LRU, 16).unwrap();
+        let mut page_id = PageId::new(0, 0);
+        bmgr.fix_new_page(0, &mut page_id).unwrap();
+        bmgr.unfix_page(page_id);
+        let mut txn = bmgr.begin().unwrap();
+        bmgr.update(&mut txn, page_id, OFFSET, &[7; 4]).unwrap();
+        bmgr.commit(txn).unwrap();
+        bmgr.close().unwrap();
+        assert!(bmgr.dirty_page_table().is_empty());
+        drop(bmgr);
+
+        // The page is on disk, not only in the log.
+        let mut data = vec![0; crate::define::PAGE_SIZE];
+        DSMgr::open_file(&path).unwrap().read_page(page_id.page_no, &mut data).unwrap();
+        assert_eq!(data[OFFSET..OFFSET + 4], [7; 4]);
+
+        let bmgr = BufferPoolManager::new(&path, ReplacePolicyType::LRU, 16).unwrap();
+        let report = bmgr.recover().unwrap();
+        assert_eq!((report.redone_num, report.undone_num), (0, 0));
+        drop(bmgr);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn a_torn_page_is_rebuilt_from_its_logged_image() {
+        let dir = temp_dir("torn");
+        let path = dir.join("db").to_str().unwrap().to_owned();
+        let disk_manager = FaultyDiskManager::new(DSMgr::open_file(&path).unwrap(), FaultConfig::default(), 42);
+        let bmgr = BufferPoolManager::with_disk_manager(disk_manager, ReplacePolicyType::LRU, 2);
+        let log_manager = LogManager::with_segment_size(&format!("{}.wal", path), 16384).unwrap();
+        bmgr.set_log_manager(Arc::new(log_manager)).unwrap();
+        let mut page_id = PageId::new(0, 0);
+        bmgr.fix_new_page(0, &mut page_id).unwrap();
+        bmgr.unfix_page(page_id);
+
+        // The change sits in the last sector, which a torn write never gets
+        // to, so the page on disk fails its checksum.
+        let offset = PAGE_SIZE - 8;
+        let read_end = |bmgr: &BufferPoolManager<DSMgr>| {
+            bmgr.with_page(page_id, |frame_id| Ok(bmgr.get_page(frame_id).get_data()[offset..offset + 4].to_vec()))
+                .unwrap()
+        };
+        let mut txn = bmgr.begin().unwrap();
+        bmgr.update(&mut txn, page_id, offset, &[8; 4]).unwrap();
+        bmgr.commit(txn).unwrap();
+        bmgr.tablespace().file(0).unwrap().set_config(FaultConfig {
+            torn_write: FaultRule::on_pages([page_id.page_no]),
+            ..FaultConfig::default()
+        });
+        assert!(bmgr.flush_page(page_id).unwrap());
+        // The crash that tore the write.
+        drop(bmgr);
+
+        let bmgr = open(&path);
+        let torn = bmgr.fix_page(page_id, false).unwrap_err();
+        assert_eq!(torn.kind(), std::io::ErrorKind::InvalidData);
+        let report = bmgr.recover().unwrap();
+        assert_eq!(report.restored_num, 1);
+        assert_eq!(read_end(&bmgr), [8; 4]);
+        bmgr.close().unwrap();
+        drop(bmgr);
+
+        // Written back whole this time.
+        let bmgr = open(&path);
+        assert_eq!(read_end(&bmgr), [8; 4]);
+        drop(bmgr);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -4,6 +4,9 @@ pub trait Replacer: Send + Sync {
     fn victim(&self) -> Option<FrameId>;
     fn insert(&self, frame_id: FrameId);
     fn remove(&self, frame_id: FrameId);
+    /// Returns up to `n` frames in the order they would be victimized,
+    /// without removing them.
+    fn peek_victims(&self, n: usize) -> Vec<FrameId>;
     fn size(&self) -> usize;
     fn print(&self);
 }
@@ -0,0 +1,312 @@
+use crate::page::{Page, PageType, PAGE_HEADER_SIZE};
+use std::io::{Error, ErrorKind};
+use std::ops::{Deref, DerefMut};
+
+/// Number of a slot in a page's slot directory. Stays the same for the
+/// lifetime of its record, however the record moves within the page.
+pub type SlotNo = u16;
+
+/// Size of a slot directory entry: u16 offset of the record in the page and
+/// u16 length.
+pub const SLOT_SIZE: usize = 4;
+
+/// A page storing variable-length records.
+///
+/// The slot directory grows from the page header towards the end of the
+/// page, the records from the end of the page towards the header; the
+/// header's free space pointers mark the gap between them. A deleted
+/// record leaves a tombstone, an entry with offset 0, so the other slots
+/// keep their numbers; `insert` reuses tombstones before growing the
+/// directory. Space freed by deleting or shrinking records is reclaimed by
+/// `compact`, which `insert` and `update` run when the gap is too small.
+///
+/// Works on anything dereferencing to a `Page`, such as the guard returned
+/// by `BufferPoolManager::get_page`; modifying needs mutable access.
+pub struct SlottedPage<P> {
+    page: P,
+}
+
+impl<P: Deref<Target = Page>> SlottedPage<P> {
+    /// Wraps a page formatted by `SlottedPage::init`.
+    pub fn new(page: P) -> std::io::Result<Self> {
+        match page.get_page_type() {
+            Some(PageType::Slotted) => Ok(Self { page }),
+            page_type => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("expected a slotted page, found {:?}", page_type),
+            )),
+        }
+    }
+
+    /// Slots in the directory, tombstones included.
+    pub fn num_slots(&self) -> usize {
+        (self.page.get_free_start() - PAGE_HEADER_SIZE) / SLOT_SIZE
+    }
+
+    /// The record in `slot`, or `None` if the slot is a tombstone or does
+    /// not exist.
+    pub fn get(&self, slot: SlotNo) -> Option<&[u8]> {
+        match self.slot(slot) {
+            Some((offset, len)) if offset != 0 => Some(&self.page.get_data()[offset..offset + len]),
+            _ => None,
+        }
+    }
+
+    /// The records in slot order.
+    pub fn iter(&self) -> impl Iterator<Item = (SlotNo, &[u8])> {
+        (0..self.num_slots() as SlotNo).filter_map(|slot| self.get(slot).map(|record| (slot, record)))
+    }
+
+    /// Bytes available to new records, counting the space `compact` would
+    /// reclaim. A record that needs a new slot also takes `SLOT_SIZE` of it.
+    pub fn free_space(&self) -> usize {
+        let used: usize = (0..self.num_slots() as SlotNo)
+            .filter_map(|slot| self.slot(slot))
+            .filter(|&(offset, _)| offset != 0)
+            .map(|(_, len)| len)
+            .sum();
+        self.page.get_data().len() - self.page.get_free_start() - used
+    }
+
+    /// Longest record that fits in an empty page of `page_size` bytes.
+    pub fn max_record_len(page_size: usize) -> usize {
+        page_size - PAGE_HEADER_SIZE - SLOT_SIZE
+    }
+
+    pub fn into_inner(self) -> P {
+        self.page
+    }
+
+    /// Offset and length of the record in `slot`, or `None` if the slot
+    /// does not exist.
+    fn slot(&self, slot: SlotNo) -> Option<(usize, usize)> {
+        if slot as usize >= self.num_slots() {
+            return None;
+        }
+        let at = PAGE_HEADER_SIZE + slot as usize * SLOT_SIZE;
+        let data = self.page.get_data();
+        let offset = u16::from_le_bytes([data[at], data[at + 1]]) as usize;
+        let len = u16::from_le_bytes([data[at + 2], data[at + 3]]) as usize;
+        Some((offset, len))
+    }
+
+    fn first_tombstone(&self) -> Option<SlotNo> {
+        (0..self.num_slots() as SlotNo).find(|&slot| self.slot(slot).is_some_and(|(offset, _)| offset == 0))
+    }
+}
+
+impl<P: DerefMut<Target = Page>> SlottedPage<P> {
+    /// Formats `page` as an empty slotted page.
+    pub fn init(mut page: P) -> Self {
+        page.init(PageType::Slotted);
+        Self { page }
+    }
+
+    /// Stores `record` and returns its slot. Fails with `StorageFull` if the
+    /// page has no room for it, even after compaction.
+    pub fn insert(&mut self, record: &[u8]) -> std::io::Result<SlotNo> {
+        self.check_len(record)?;
+        let tombstone = self.first_tombstone();
+        let needed = record.len() + if tombstone.is_none() { SLOT_SIZE } else { 0 };
+        if self.free_space() < needed {
+            return Err(Self::full());
+        }
+        // The directory can only grow into the gap.
+        if self.page.get_free_space() < needed {
+            self.compact();
+        }
+        let slot = match tombstone {
+            Some(slot) => slot,
+            None => {
+                let slot = self.num_slots() as SlotNo;
+                let free_start = self.page.get_free_start();
+                self.page.set_free_start(free_start + SLOT_SIZE);
+                self.set_slot(slot, 0, 0);
+                slot
+            }
+        };
+        let offset = self.allocate(record.len());
+        self.page.get_data_mut()[offset..offset + record.len()].copy_from_slice(record);
+        self.set_slot(slot, offset, record.len());
+        Ok(slot)
+    }
+
+    /// Replaces the record in `slot`, in place if it does not grow. Fails
+    /// with `NotFound` if there is no such record and with `StorageFull` if
+    /// the new one does not fit, leaving the old one as it was.
+    pub fn update(&mut self, slot: SlotNo, record: &[u8]) -> std::io::Result<()> {
+        self.check_len(record)?;
+        let (offset, len) = self.live_slot(slot)?;
+        if record.len() <= len {
+            self.page.get_data_mut()[offset..offset + record.len()].copy_from_slice(record);
+            self.set_slot(slot, offset, record.len());
+            return Ok(());
+        }
+        if self.free_space() + len < record.len() {
+            return Err(Self::full());
+        }
+        // Free the old copy first, so compaction can reclaim it.
+        self.set_slot(slot, 0, 0);
+        let offset = self.allocate(record.len());
+        self.page.get_data_mut()[offset..offset + record.len()].copy_from_slice(record);
+        self.set_slot(slot, offset, record.len());
+        Ok(())
+    }
+
+    /// Deletes the record in `slot`, leaving a tombstone. Trailing
+    /// tombstones are dropped from the directory.
+    pub fn delete(&mut self, slot: SlotNo) -> std::io::Result<()> {
+        self.live_slot(slot)?;
+        self.set_slot(slot, 0, 0);
+        let mut num_slots = self.num_slots();
+        while num_slots > 0 && self.slot(num_slots as SlotNo - 1).is_some_and(|(offset, _)| offset == 0) {
+            num_slots -= 1;
+        }
+        self.page.set_free_start(PAGE_HEADER_SIZE + num_slots * SLOT_SIZE);
+        Ok(())
+    }
+
+    /// Moves the records together at the end of the page, in place, so all
+    /// free space is in one gap. Slot numbers do not change.
+    pub fn compact(&mut self) {
+        let mut records: Vec<(SlotNo, usize, usize)> = (0..self.num_slots() as SlotNo)
+            .filter_map(|slot| self.slot(slot).map(|(offset, len)| (slot, offset, len)))
+            .filter(|&(_, offset, len)| offset != 0 && len != 0)
+            .collect();
+        // Highest first: every record then moves towards the end of the
+        // page, never over one not moved yet.
+        records.sort_unstable_by_key(|&(_, offset, _)| std::cmp::Reverse(offset));
+        let mut free_end = self.page.get_data().len();
+        for (slot, offset, len) in records {
+            free_end -= len;
+            self.page.get_data_mut().copy_within(offset..offset + len, free_end);
+            self.set_slot(slot, free_end, len);
+        }
+        self.page.set_free_end(free_end);
+    }
+
+    /// Takes `len` bytes from the end of the gap, compacting first if the
+    /// gap is too small. The caller has checked that there is room.
+    fn allocate(&mut self, len: usize) -> usize {
+        // An empty record takes no space, but needs an offset other than the
+        // tombstone's 0; the end of a 64 KiB page does not fit in a u16.
+        if len == 0 {
+            return PAGE_HEADER_SIZE;
+        }
+        if self.page.get_free_space() < len {
+            self.compact();
+        }
+        let offset = self.page.get_free_end() - len;
+        self.page.set_free_end(offset);
+        offset
+    }
+
+    fn live_slot(&self, slot: SlotNo) -> std::io::Result<(usize, usize)> {
+        match self.slot(slot) {
+            Some((offset, len)) if offset != 0 => Ok((offset, len)),
+            _ => Err(Error::new(
+                ErrorKind::NotFound,
+                format!("no record in slot {}", slot),
+            )),
+        }
+    }
+
+    fn set_slot(&mut self, slot: SlotNo, offset: usize, len: usize) {
+        let at = PAGE_HEADER_SIZE + slot as usize * SLOT_SIZE;
+        let data = self.page.get_data_mut();
+        data[at..at + 2].copy_from_slice(&(offset as u16).to_le_bytes());
+        data[at + 2..at + 4].copy_from_slice(&(len as u16).to_le_bytes());
+    }
+
+    fn check_len(&self, record: &[u8]) -> std::io::Result<()> {
+        if record.len() > Self::max_record_len(self.page.get_data().len()) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("a record of {} bytes does not fit in a page", record.len()),
+            ));
+        }
+        Ok(())
+    }
+
+    fn full() -> Error {
+        Error::new(ErrorKind::StorageFull, "not enough free space in the page")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::define::PAGE_SIZE;
+
+    #[test]
+    fn deleted_slots_become_tombstones_and_are_reused() {
+        let mut page = Page::new(PAGE_SIZE);
+        let mut slotted = SlottedPage::init(&mut page);
+        let slots: Vec<SlotNo> = (0..4u8).map(|i| slotted.insert(&[i; 10]).unwrap()).collect();
+        assert_eq!(slots, [0, 1, 2, 3]);
+
+        slotted.delete(1).unwrap();
+        assert_eq!(slotted.get(1), None);
+        assert_eq!(slotted.delete(1).unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(slotted.num_slots(), 4);
+        assert_eq!(slotted.insert(&[9; 3]).unwrap(), 1);
+        assert_eq!(slotted.get(1), Some(&[9; 3][..]));
+
+        // Trailing tombstones leave the directory.
+        slotted.delete(3).unwrap();
+        slotted.delete(2).unwrap();
+        assert_eq!(slotted.num_slots(), 2);
+        let records: Vec<(SlotNo, Vec<u8>)> = slotted.iter().map(|(slot, record)| (slot, record.to_vec())).collect();
+        assert_eq!(records, [(0, vec![0; 10]), (1, vec![9; 3])]);
+        assert_eq!(SlottedPage::new(&page).unwrap().num_slots(), 2);
+    }
+
+    #[test]
+    fn freed_space_is_compacted_on_demand() {
+        let mut page = Page::new(PAGE_SIZE);
+        let mut slotted = SlottedPage::init(&mut page);
+        let record_len = 1000;
+        let mut slots = Vec::new();
+        loop {
+            match slotted.insert(&vec![slots.len() as u8; record_len]) {
+                Ok(slot) => slots.push(slot),
+                Err(e) => {
+                    assert_eq!(e.kind(), ErrorKind::StorageFull);
+                    break;
+                }
+            }
+        }
+        assert_eq!(slots.len(), (PAGE_SIZE - PAGE_HEADER_SIZE) / (record_len + SLOT_SIZE));
+
+        // Two non-adjacent holes only fit a record twice their size once
+        // compacted into one gap.
+        slotted.delete(0).unwrap();
+        slotted.delete(2).unwrap();
+        let big = vec![0xaa; 2 * record_len];
+        assert!(slotted.page.get_free_space() < big.len());
+        assert_eq!(slotted.insert(&big).unwrap(), 0);
+        for &slot in &slots[3..] {
+            assert_eq!(slotted.get(slot), Some(&vec![slot as u8; record_len][..]));
+        }
+        assert_eq!(slotted.get(1), Some(&vec![1; record_len][..]));
+        assert_eq!(slotted.get(2), None);
+
+        // Growing a record in place of a shrunk one reuses its space.
+        slotted.update(1, &[1; 10]).unwrap();
+        slotted.update(1, &vec![1; record_len + 40]).unwrap();
+        assert_eq!(slotted.get(1).unwrap().len(), record_len + 40);
+        let error = slotted.update(1, &vec![1; 4 * record_len]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::StorageFull);
+        assert_eq!(slotted.get(1).unwrap().len(), record_len + 40, "a failed update keeps the old record");
+    }
+
+    #[test]
+    fn other_page_types_are_rejected() {
+        let page = Page::new(PAGE_SIZE);
+        assert_eq!(SlottedPage::new(&page).err().unwrap().kind(), ErrorKind::InvalidData);
+        let mut page = Page::new(PAGE_SIZE);
+        let mut slotted = SlottedPage::init(&mut page);
+        let error = slotted.insert(&vec![0; SlottedPage::<&Page>::max_record_len(PAGE_SIZE) + 1]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    }
+}
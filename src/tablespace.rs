@@ -0,0 +1,249 @@
+use crate::data_storage_manager::DSMgr;
+use crate::define::{FileId, PageId, PageNo};
+use crate::disk_manager::DiskManager;
+use crate::extent::SegmentId;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// A set of data files sharing one page size, each behind its own disk
+/// manager. Pages are addressed by `PageId`, i.e. file id plus page number.
+///
+/// Files can be added and dropped while I/O is running: every call looks its
+/// file up first and keeps it alive until the call returns.
+pub struct Tablespace<D: DiskManager = DSMgr> {
+    page_size: usize,
+    files: RwLock<HashMap<FileId, Arc<D>>>,
+    next_file_id: AtomicU32,
+}
+
+impl<D: DiskManager> Tablespace<D> {
+    pub fn new(page_size: usize) -> Self {
+        Self {
+            page_size,
+            files: RwLock::new(HashMap::new()),
+            next_file_id: AtomicU32::new(0),
+        }
+    }
+
+    /// Adds a data file and returns its id. Ids are never reused.
+    pub fn add_file(&self, disk_manager: D) -> std::io::Result<FileId> {
+        if disk_manager.page_size() != self.page_size {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "file has {}-byte pages, tablespace uses {}",
+                    disk_manager.page_size(),
+                    self.page_size
+                ),
+            ));
+        }
+        let file_id = self.next_file_id.fetch_add(1, Ordering::SeqCst);
+        self.files.write().unwrap().insert(file_id, Arc::new(disk_manager));
+        Ok(file_id)
+    }
+
+    /// Removes a data file and hands back its disk manager. I/O already
+    /// running against it completes; later calls fail with `NotFound`.
+    pub fn drop_file(&self, file_id: FileId) -> std::io::Result<Arc<D>> {
+        self.files
+            .write()
+            .unwrap()
+            .remove(&file_id)
+            .ok_or_else(|| Self::no_such_file(file_id))
+    }
+
+    pub fn file(&self, file_id: FileId) -> std::io::Result<Arc<D>> {
+        self.files
+            .read()
+            .unwrap()
+            .get(&file_id)
+            .cloned()
+            .ok_or_else(|| Self::no_such_file(file_id))
+    }
+
+    pub fn contains_file(&self, file_id: FileId) -> bool {
+        self.files.read().unwrap().contains_key(&file_id)
+    }
+
+    pub fn file_ids(&self) -> Vec<FileId> {
+        let mut file_ids: Vec<FileId> = self.files.read().unwrap().keys().copied().collect();
+        file_ids.sort_unstable();
+        file_ids
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// Number of pages in `file_id`, or `None` if there is no such file.
+    pub fn num_pages(&self, file_id: FileId) -> Option<PageNo> {
+        self.files.read().unwrap().get(&file_id).map(|file| file.num_pages())
+    }
+
+    pub fn read_page(&self, page_id: PageId, data: &mut [u8]) -> std::io::Result<()> {
+        self.file(page_id.file_id)?.read_page(page_id.page_no, data)
+    }
+
+    pub fn write_page(&self, page_id: PageId, data: &[u8]) -> std::io::Result<()> {
+        self.file(page_id.file_id)?.write_page(page_id.page_no, data)
+    }
+
+    /// Reads a batch of pages, one `read_pages` call per file involved.
+    /// Results are returned per page, in order.
+    pub fn read_pages(&self, reads: &mut [(PageId, &mut [u8])]) -> Vec<std::io::Result<()>> {
+        let len = reads.len();
+        let mut by_file: HashMap<FileId, Vec<_>> = HashMap::new();
+        for (index, (page_id, data)) in reads.iter_mut().enumerate() {
+            by_file
+                .entry(page_id.file_id)
+                .or_default()
+                .push((index, page_id.page_no, &mut **data));
+        }
+
+        let mut results: Vec<Option<std::io::Result<()>>> = (0..len).map(|_| None).collect();
+        for (file_id, group) in by_file {
+            let (indexes, mut batch): (Vec<usize>, Vec<(PageNo, &mut [u8])>) = group
+                .into_iter()
+                .map(|(index, page_no, data)| (index, (page_no, data)))
+                .unzip();
+            match self.file(file_id) {
+                Ok(file) => {
+                    for (index, result) in indexes.into_iter().zip(file.read_pages(&mut batch)) {
+                        results[index] = Some(result);
+                    }
+                }
+                Err(_) => {
+                    for index in indexes {
+                        results[index] = Some(Err(Self::no_such_file(file_id)));
+                    }
+                }
+            }
+        }
+        results
+            .into_iter()
+            .map(|result| result.expect("every page belongs to one group"))
+            .collect()
+    }
+
+    /// Writes a batch of pages, one `write_pages` call per file involved.
+    /// Results are returned per page, in order.
+    pub fn write_pages(&self, writes: &[(PageId, &[u8])]) -> Vec<std::io::Result<()>> {
+        let mut by_file: HashMap<FileId, Vec<_>> = HashMap::new();
+        for (index, (page_id, data)) in writes.iter().enumerate() {
+            by_file
+                .entry(page_id.file_id)
+                .or_default()
+                .push((index, (page_id.page_no, *data)));
+        }
+
+        let mut results: Vec<Option<std::io::Result<()>>> = writes.iter().map(|_| None).collect();
+        for (file_id, group) in by_file {
+            let (indexes, batch): (Vec<usize>, Vec<(PageNo, &[u8])>) = group.into_iter().unzip();
+            match self.file(file_id) {
+                Ok(file) => {
+                    for (index, result) in indexes.into_iter().zip(file.write_pages(&batch)) {
+                        results[index] = Some(result);
+                    }
+                }
+                Err(_) => {
+                    for index in indexes {
+                        results[index] = Some(Err(Self::no_such_file(file_id)));
+                    }
+                }
+            }
+        }
+        results
+            .into_iter()
+            .map(|result| result.expect("every page belongs to one group"))
+            .collect()
+    }
+
+    /// Allocates a zeroed page in `file_id`.
+    pub fn allocate(&self, file_id: FileId) -> std::io::Result<PageId> {
+        let page_no = self.file(file_id)?.allocate()?;
+        Ok(PageId::new(file_id, page_no))
+    }
+
+    /// Allocates a zeroed page in `file_id`, next to the pages already
+    /// allocated there for `segment`.
+    pub fn allocate_in(&self, file_id: FileId, segment: SegmentId) -> std::io::Result<PageId> {
+        let page_no = self.file(file_id)?.allocate_in(segment)?;
+        Ok(PageId::new(file_id, page_no))
+    }
+
+    /// Allocates `n` contiguous zeroed pages in `file_id` and returns the
+    /// first.
+    pub fn allocate_pages(&self, file_id: FileId, n: PageNo) -> std::io::Result<PageId> {
+        let page_no = self.file(file_id)?.allocate_pages(n)?;
+        Ok(PageId::new(file_id, page_no))
+    }
+
+    /// Frees `page_id` in its file. A page that may be cached in a pool must
+    /// be freed through `BufferPoolManager::deallocate_page` instead, so the
+    /// pool forgets it too.
+    pub fn deallocate(&self, page_id: PageId) -> std::io::Result<()> {
+        self.file(page_id.file_id)?.deallocate(page_id.page_no)
+    }
+
+    /// Syncs every file, stopping at the first error.
+    pub fn sync(&self) -> std::io::Result<()> {
+        let files: Vec<Arc<D>> = self.files.read().unwrap().values().cloned().collect();
+        files.iter().try_for_each(|file| file.sync())
+    }
+
+    fn no_such_file(file_id: FileId) -> Error {
+        Error::new(ErrorKind::NotFound, format!("no data file with id {}", file_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::define::PAGE_SIZE;
+    use crate::fault_injection::{FaultConfig, FaultRule, FaultyDiskManager};
+    use crate::memory_disk_manager::MemDiskManager;
+    use std::thread;
+    use std::time::Duration;
+
+    fn file(page_size: usize) -> FaultyDiskManager<MemDiskManager> {
+        FaultyDiskManager::new(MemDiskManager::with_page_size(page_size, 4), FaultConfig::default(), 0)
+    }
+
+    #[test]
+    fn dropped_files_finish_their_io_and_keep_their_ids() {
+        let tablespace = Arc::new(Tablespace::new(PAGE_SIZE));
+        assert_eq!(tablespace.add_file(file(PAGE_SIZE)).unwrap(), 0);
+        assert_eq!(tablespace.add_file(file(PAGE_SIZE)).unwrap(), 1);
+        let error = tablespace.add_file(file(2 * PAGE_SIZE)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+
+        // A read still sleeping in file 0 when it is dropped completes.
+        tablespace.file(0).unwrap().set_config(FaultConfig {
+            latency: FaultRule::on_pages([1]),
+            delay: Duration::from_millis(100),
+            ..FaultConfig::default()
+        });
+        let read = {
+            let tablespace = Arc::clone(&tablespace);
+            thread::spawn(move || tablespace.read_page(PageId::new(0, 1), &mut vec![0; PAGE_SIZE]))
+        };
+        thread::sleep(Duration::from_millis(20));
+        let dropped = tablespace.drop_file(0).unwrap();
+        read.join().unwrap().unwrap();
+        assert_eq!(dropped.get_injected_num(), 1);
+
+        let mut data = vec![0; PAGE_SIZE];
+        let page_id = PageId::new(0, 0);
+        assert_eq!(tablespace.read_page(page_id, &mut data).unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(tablespace.write_page(page_id, &data).unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(tablespace.drop_file(0).err().unwrap().kind(), ErrorKind::NotFound);
+        assert_eq!(tablespace.num_pages(0), None);
+        tablespace.read_page(PageId::new(1, 0), &mut data).unwrap();
+
+        // Neither the dropped id nor the rejected file's is handed out.
+        assert_eq!(tablespace.add_file(file(PAGE_SIZE)).unwrap(), 2);
+        assert_eq!(tablespace.file_ids(), vec![1, 2]);
+    }
+}
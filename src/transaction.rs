@@ -0,0 +1,174 @@
+use crate::buffer_pool_manager::BufferPoolManager;
+use crate::define::{FrameId, PageId};
+use crate::disk_manager::DiskManager;
+use crate::page::{Page, MAINTAINED_HEADER_SIZE};
+use crate::wal::{LogBody, LogManager, LogRecord, Lsn, TxnId, INVALID_LSN};
+use std::io::{Error, ErrorKind};
+
+/// A unit of work whose page updates are logged, so that they can be rolled
+/// back and survive a crash once committed. Started by
+/// `BufferPoolManager::begin` and finished by `commit` or `abort`.
+///
+/// Transactions do not lock anything; callers keep concurrent transactions
+/// off each other's data.
+#[derive(Debug)]
+pub struct Transaction {
+    id: TxnId,
+    last_lsn: Lsn,
+}
+
+impl Transaction {
+    pub fn id(&self) -> TxnId {
+        self.id
+    }
+
+    /// LSN of the transaction's latest log record.
+    pub fn last_lsn(&self) -> Lsn {
+        self.last_lsn
+    }
+}
+
+impl<D: DiskManager> BufferPoolManager<D> {
+    /// Starts a transaction. Fails if the pool has no log.
+    pub fn begin(&self) -> std::io::Result<Transaction> {
+        let log_manager = self.require_log()?;
+        let id = log_manager.new_txn_id();
+        let last_lsn = log_manager.append(id, INVALID_LSN, LogBody::Begin)?;
+        Ok(Transaction { id, last_lsn })
+    }
+
+    /// Overwrites bytes `offset..offset + data.len()` of `page_id` for
+    /// `txn`. The change is logged with its before image and the page
+    /// stamped with the record's LSN while the frame is latched, so the
+    /// page and the log agree on the order of updates. The page LSN and
+    /// checksum at the start of the page header cannot be updated.
+    pub fn update(&self, txn: &mut Transaction, page_id: PageId, offset: usize, data: &[u8]) -> std::io::Result<()> {
+        let log_manager = self.require_log()?;
+        if offset < MAINTAINED_HEADER_SIZE || offset + data.len() > self.get_page_size() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("cannot update bytes {}..{} of a page", offset, offset + data.len()),
+            ));
+        }
+        let lsn = self.with_page(page_id, |frame_id| {
+            let mut page = self.get_page(frame_id);
+            let range = offset..offset + data.len();
+            let before = page.get_data()[range.clone()].to_vec();
+            let lsn = log_change(
+                log_manager,
+                page_id,
+                &mut page,
+                txn.id,
+                txn.last_lsn,
+                LogBody::Update {
+                    page_id,
+                    offset: offset as u32,
+                    before,
+                    after: data.to_vec(),
+                },
+            )?;
+            page.get_data_mut()[range].copy_from_slice(data);
+            page.set_page_lsn(lsn);
+            Ok(lsn)
+        })?;
+        txn.last_lsn = lsn;
+        Ok(())
+    }
+
+    /// Commits `txn`: returns once its commit record is durable.
+    pub fn commit(&self, txn: Transaction) -> std::io::Result<()> {
+        let log_manager = self.require_log()?;
+        let lsn = log_manager.commit(txn.id, txn.last_lsn)?;
+        log_manager.append(txn.id, lsn, LogBody::End)?;
+        Ok(())
+    }
+
+    /// Rolls `txn` back, undoing its updates newest first and logging a
+    /// compensation record for each.
+    pub fn abort(&self, txn: Transaction) -> std::io::Result<()> {
+        let log_manager = self.require_log()?;
+        let mut last_lsn = log_manager.append(txn.id, txn.last_lsn, LogBody::Abort)?;
+        let mut undo_next = txn.last_lsn;
+        while undo_next != INVALID_LSN {
+            let record = log_manager.read(undo_next)?;
+            (undo_next, last_lsn) = self.undo(&record, last_lsn)?;
+        }
+        log_manager.append(txn.id, last_lsn, LogBody::End)?;
+        Ok(())
+    }
+
+    /// Undoes one record of a transaction being rolled back whose latest
+    /// record is `last_lsn`. An update gets its before image written back
+    /// and a CLR logged. Returns the next record to undo and the
+    /// transaction's new latest record.
+    pub(crate) fn undo(&self, record: &LogRecord, last_lsn: Lsn) -> std::io::Result<(Lsn, Lsn)> {
+        match &record.body {
+            LogBody::Update {
+                page_id,
+                offset,
+                before,
+                ..
+            } => {
+                let log_manager = self.require_log()?;
+                let lsn = self.with_page(*page_id, |frame_id| {
+                    let mut page = self.get_page(frame_id);
+                    let lsn = log_change(
+                        log_manager,
+                        *page_id,
+                        &mut page,
+                        record.txn_id,
+                        last_lsn,
+                        LogBody::Clr {
+                            page_id: *page_id,
+                            offset: *offset,
+                            after: before.clone(),
+                            undo_next_lsn: record.prev_lsn,
+                        },
+                    )?;
+                    let offset = *offset as usize;
+                    page.get_data_mut()[offset..offset + before.len()].copy_from_slice(before);
+                    page.set_page_lsn(lsn);
+                    Ok(lsn)
+                })?;
+                Ok((record.prev_lsn, lsn))
+            }
+            // Everything before a CLR up to its `undo_next_lsn` is undone
+            // already.
+            LogBody::Clr { undo_next_lsn, .. } => Ok((*undo_next_lsn, last_lsn)),
+            _ => Ok((record.prev_lsn, last_lsn)),
+        }
+    }
+
+    /// Runs `f` on the frame of `page_id`, fixed dirty for the duration.
+    pub(crate) fn with_page<T>(
+        &self,
+        page_id: PageId,
+        f: impl FnOnce(FrameId) -> std::io::Result<T>,
+    ) -> std::io::Result<T> {
+        let frame_id = self.fix_page(page_id, true)?;
+        let result = f(frame_id);
+        self.unfix_page(page_id);
+        result
+    }
+}
+
+/// Logs `body`, a change to `page_id` latched as `page`, and returns its LSN. The
+/// first change logged for a page since it was last written back is
+/// preceded by an image of the whole page, which redo restores instead
+/// of reading a copy on disk that a crash may have torn.
+fn log_change(
+    log_manager: &LogManager,
+    page_id: PageId,
+    page: &mut Page,
+    txn_id: TxnId,
+    prev_lsn: Lsn,
+    body: LogBody,
+) -> std::io::Result<Lsn> {
+    let mut prev_lsn = prev_lsn;
+    if page.get_rec_lsn() == INVALID_LSN {
+        let image = page.get_data().to_vec();
+        prev_lsn = log_manager.append(txn_id, prev_lsn, LogBody::PageImage { page_id, image })?;
+        page.set_rec_lsn(prev_lsn);
+    }
+    log_manager.append(txn_id, prev_lsn, body)
+}
@@ -0,0 +1,209 @@
+use crate::page::PageData;
+use io_uring::{opcode, types, IoUring};
+use std::fs::File;
+use std::io::{Error, ErrorKind};
+use std::os::unix::io::AsRawFd;
+use std::sync::{Mutex, MutexGuard};
+
+/// io_uring submission/completion for `DSMgr`.
+///
+/// The ring owns `queue_depth` page-sized buffers registered with the kernel,
+/// so every request is a `READ_FIXED`/`WRITE_FIXED` into one of them and a
+/// batch of up to `queue_depth` pages costs a single `io_uring_enter`.
+pub(crate) struct UringIo {
+    inner: Mutex<Ring>,
+    queue_depth: u32,
+    page_size: usize,
+}
+
+struct Ring {
+    ring: IoUring,
+    buffers: Vec<PageData>,
+    /// Set when waiting for completions failed with requests possibly still
+    /// in flight. Such a ring is never used again, see `UringIo::ring`.
+    is_broken: bool,
+}
+
+impl UringIo {
+    pub(crate) fn new(queue_depth: u32, page_size: usize) -> std::io::Result<Self> {
+        Ok(Self {
+            inner: Mutex::new(Ring::new(queue_depth, page_size)?),
+            queue_depth,
+            page_size,
+        })
+    }
+
+    /// The ring, replaced by a new one if the last batch left it broken. The
+    /// kernel may still write into the old ring's buffers and post
+    /// completions for it, so it is leaked rather than dropped.
+    fn ring(&self) -> std::io::Result<MutexGuard<'_, Ring>> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.is_broken {
+            let old = std::mem::replace(&mut *inner, Ring::new(self.queue_depth, self.page_size)?);
+            std::mem::forget(old);
+        }
+        Ok(inner)
+    }
+
+    /// Reads one page at each `(offset, buffer)` pair. Results are returned
+    /// per request, in order.
+    pub(crate) fn read_at(&self, file: &File, reads: &mut [(u64, &mut [u8])]) -> Vec<std::io::Result<()>> {
+        let mut inner = match self.ring() {
+            Ok(inner) => inner,
+            Err(e) => return reads.iter().map(|_| Err(Error::new(e.kind(), e.to_string()))).collect(),
+        };
+        let depth = inner.buffers.len();
+        let mut results = Vec::with_capacity(reads.len());
+        for chunk in reads.chunks_mut(depth) {
+            let offsets: Vec<u64> = chunk.iter().map(|(offset, _)| *offset).collect();
+            let completed = inner.run(file, &offsets, false);
+            for ((_, data), (index, result)) in chunk.iter_mut().zip(completed) {
+                if result.is_ok() {
+                    data.copy_from_slice(&inner.buffers[index][..data.len()]);
+                }
+                results.push(result);
+            }
+        }
+        results
+    }
+
+    /// Writes one page from each `(offset, buffer)` pair. Results are
+    /// returned per request, in order.
+    pub(crate) fn write_at(&self, file: &File, writes: &[(u64, &[u8])]) -> Vec<std::io::Result<()>> {
+        let mut inner = match self.ring() {
+            Ok(inner) => inner,
+            Err(e) => return writes.iter().map(|_| Err(Error::new(e.kind(), e.to_string()))).collect(),
+        };
+        let depth = inner.buffers.len();
+        let mut results = Vec::with_capacity(writes.len());
+        for chunk in writes.chunks(depth) {
+            for (index, (_, data)) in chunk.iter().enumerate() {
+                inner.buffers[index][..data.len()].copy_from_slice(data);
+            }
+            let offsets: Vec<u64> = chunk.iter().map(|(offset, _)| *offset).collect();
+            results.extend(inner.run(file, &offsets, true).into_iter().map(|(_, result)| result));
+        }
+        results
+    }
+}
+
+impl Ring {
+    fn new(queue_depth: u32, page_size: usize) -> std::io::Result<Self> {
+        let ring = IoUring::new(queue_depth)?;
+        let mut buffers: Vec<PageData> = (0..queue_depth).map(|_| PageData::zeroed(page_size)).collect();
+        let iovecs: Vec<libc::iovec> = buffers
+            .iter_mut()
+            .map(|buffer| libc::iovec {
+                iov_base: buffer.as_mut_ptr().cast(),
+                iov_len: buffer.len(),
+            })
+            .collect();
+        // SAFETY: the buffers are never freed before the ring, which
+        // lives in the same struct and is dropped first.
+        unsafe { ring.submitter().register_buffers(&iovecs)? };
+
+        Ok(Self {
+            ring,
+            buffers,
+            is_broken: false,
+        })
+    }
+
+    /// Submits one fixed-buffer request per offset, buffer `i` serving
+    /// offset `i`, and waits for all of them. Returns `(buffer index,
+    /// result)` in submission order.
+    fn run(&mut self, file: &File, offsets: &[u64], write: bool) -> Vec<(usize, std::io::Result<()>)> {
+        let fd = types::Fd(file.as_raw_fd());
+        for (index, &offset) in offsets.iter().enumerate() {
+            let len = self.buffers[index].len() as u32;
+            let buffer = self.buffers[index].as_mut_ptr();
+            let entry = if write {
+                opcode::WriteFixed::new(fd, buffer, len, index as u16)
+                    .offset(offset)
+                    .build()
+            } else {
+                opcode::ReadFixed::new(fd, buffer, len, index as u16)
+                    .offset(offset)
+                    .build()
+            };
+            // SAFETY: the buffer is registered and stays valid until the
+            // completion has been reaped below.
+            unsafe {
+                self.ring
+                    .submission()
+                    .push(&entry.user_data(index as u64))
+                    .expect("chunks never exceed the queue depth");
+            }
+        }
+
+        let page_size = self.buffers[0].len();
+        let mut results: Vec<Option<std::io::Result<()>>> = offsets.iter().map(|_| None).collect();
+        let mut pending = offsets.len();
+        while pending > 0 {
+            if let Err(e) = self.wait(pending) {
+                // The requests not reaped yet may still complete, into
+                // buffers the next batch would reuse.
+                self.is_broken = true;
+                for result in results.iter_mut().filter(|result| result.is_none()) {
+                    *result = Some(Err(Error::new(e.kind(), e.to_string())));
+                }
+                break;
+            }
+            for cqe in self.ring.completion() {
+                let index = cqe.user_data() as usize;
+                results[index] = Some(match cqe.result() {
+                    n if n < 0 => Err(Error::from_raw_os_error(-n)),
+                    n if n as usize == page_size => Ok(()),
+                    _ if write => Err(Error::new(ErrorKind::WriteZero, "short page write")),
+                    _ => Err(Error::new(ErrorKind::UnexpectedEof, "short page read")),
+                });
+                pending -= 1;
+            }
+        }
+
+        results
+            .into_iter()
+            .enumerate()
+            .map(|(index, result)| (index, result.expect("every request completed")))
+            .collect()
+    }
+
+    /// Submits queued entries and waits for `want` completions, retrying
+    /// when interrupted by a signal.
+    fn wait(&mut self, want: usize) -> std::io::Result<()> {
+        loop {
+            match self.ring.submit_and_wait(want) {
+                Ok(_) => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::FileExt;
+
+    #[test]
+    fn broken_ring_is_replaced_before_the_next_batch() {
+        let path = std::env::temp_dir().join(format!("adbs-uring-ring-{}.dbf", std::process::id()));
+        let file = File::options().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let (page_size, page) = (512, vec![0x5a; 512]);
+        file.write_all_at(&page, 0).unwrap();
+
+        let uring = UringIo::new(2, page_size).unwrap();
+        let buffer = uring.inner.lock().unwrap().buffers[0].as_ptr();
+        uring.inner.lock().unwrap().is_broken = true;
+        let mut data = vec![0; page_size];
+        assert!(uring.read_at(&file, &mut [(0, &mut data[..])])[0].is_ok());
+        assert_eq!(data, page);
+
+        let inner = uring.inner.lock().unwrap();
+        assert!(!inner.is_broken);
+        assert_ne!(inner.buffers[0].as_ptr(), buffer);
+        drop(inner);
+        let _ = std::fs::remove_file(&path);
+    }
+}
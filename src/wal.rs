@@ -0,0 +1,974 @@
+use crate::checksum::crc32;
+use crate::define::PageId;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Error, ErrorKind, Read, Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Log sequence number: the byte offset of a record in the log, counted
+/// across all segments, so LSNs grow with every record appended. Segment
+/// `k` holds LSNs `k * segment_size..(k + 1) * segment_size`.
+pub type Lsn = u64;
+
+pub type TxnId = u64;
+
+/// LSN no record has. A page with this `page_lsn` was never logged.
+pub const INVALID_LSN: Lsn = 0;
+
+const LOG_MAGIC: &[u8; 8] = b"ADBSWAL\0";
+
+/// Every segment starts with the magic and the u64 segment size.
+const SEGMENT_HEADER_SIZE: u64 = 16;
+
+/// LSN of the first record of a new log, right after the header of its
+/// first segment.
+pub const FIRST_LSN: Lsn = SEGMENT_HEADER_SIZE;
+
+/// Segment size of a new log opened with `LogManager::open`.
+pub const DEFAULT_SEGMENT_SIZE: u64 = 16 << 20;
+
+const MIN_SEGMENT_SIZE: u64 = 4096;
+
+/// Fixed part of every record: u32 length of the whole record, u32 checksum
+/// of everything after it, u64 LSN, u64 transaction, u64 previous LSN of
+/// the transaction, u8 kind.
+const RECORD_HEADER_SIZE: usize = 33;
+
+/// Appended records are written out once this much is buffered, even if
+/// nobody asked for a flush.
+const LOG_BUFFER_SIZE: usize = 1 << 20;
+
+const KIND_BEGIN: u8 = 1;
+const KIND_COMMIT: u8 = 2;
+const KIND_ABORT: u8 = 3;
+const KIND_UPDATE: u8 = 4;
+const KIND_CLR: u8 = 5;
+const KIND_END: u8 = 6;
+const KIND_IMAGE: u8 = 7;
+
+/// What a log record says happened.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LogBody {
+    Begin,
+    Commit,
+    Abort,
+    /// Bytes `offset..offset + after.len()` of the page changed from
+    /// `before` to `after`; both have the same length.
+    Update {
+        page_id: PageId,
+        offset: u32,
+        before: Vec<u8>,
+        after: Vec<u8>,
+    },
+    /// Compensation log record: undoing an update wrote `after` back. Redo
+    /// only; rolling back continues at `undo_next_lsn`, the update's
+    /// `prev_lsn`.
+    Clr {
+        page_id: PageId,
+        offset: u32,
+        after: Vec<u8>,
+        undo_next_lsn: Lsn,
+    },
+    /// The transaction is finished, committed or fully rolled back, and
+    /// needs no more attention from recovery.
+    End,
+    /// The whole page as it was before the change logged right after it.
+    /// Logged ahead of the first change to a page since the page was last
+    /// written back, so redo can rebuild a page that a crash tore in the
+    /// middle of writing it back.
+    PageImage { page_id: PageId, image: Vec<u8> },
+}
+
+impl LogBody {
+    /// Length of a record with this body once encoded.
+    fn encoded_len(&self) -> usize {
+        RECORD_HEADER_SIZE
+            + match self {
+                LogBody::Update { before, after, .. } => 20 + before.len() + after.len(),
+                LogBody::Clr { after, .. } => 28 + after.len(),
+                LogBody::PageImage { image, .. } => 16 + image.len(),
+                _ => 0,
+            }
+    }
+}
+
+/// Where a transaction in the transaction table stands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TxnState {
+    Running,
+    /// Its commit record is logged; only the end record is missing.
+    Committed,
+    /// Being rolled back.
+    Aborting,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogRecord {
+    pub lsn: Lsn,
+    pub txn_id: TxnId,
+    /// The transaction's previous record, or `INVALID_LSN` for its first.
+    pub prev_lsn: Lsn,
+    pub body: LogBody,
+}
+
+impl LogRecord {
+    fn encode_into(&self, buffer: &mut Vec<u8>) {
+        let start = buffer.len();
+        buffer.extend_from_slice(&[0; 8]);
+        buffer.extend_from_slice(&self.lsn.to_le_bytes());
+        buffer.extend_from_slice(&self.txn_id.to_le_bytes());
+        buffer.extend_from_slice(&self.prev_lsn.to_le_bytes());
+        match &self.body {
+            LogBody::Begin => buffer.push(KIND_BEGIN),
+            LogBody::Commit => buffer.push(KIND_COMMIT),
+            LogBody::Abort => buffer.push(KIND_ABORT),
+            LogBody::Update {
+                page_id,
+                offset,
+                before,
+                after,
+            } => {
+                buffer.push(KIND_UPDATE);
+                buffer.extend_from_slice(&page_id.file_id.to_le_bytes());
+                buffer.extend_from_slice(&page_id.page_no.to_le_bytes());
+                buffer.extend_from_slice(&offset.to_le_bytes());
+                buffer.extend_from_slice(&(after.len() as u32).to_le_bytes());
+                buffer.extend_from_slice(before);
+                buffer.extend_from_slice(after);
+            }
+            LogBody::Clr {
+                page_id,
+                offset,
+                after,
+                undo_next_lsn,
+            } => {
+                buffer.push(KIND_CLR);
+                buffer.extend_from_slice(&page_id.file_id.to_le_bytes());
+                buffer.extend_from_slice(&page_id.page_no.to_le_bytes());
+                buffer.extend_from_slice(&offset.to_le_bytes());
+                buffer.extend_from_slice(&(after.len() as u32).to_le_bytes());
+                buffer.extend_from_slice(&undo_next_lsn.to_le_bytes());
+                buffer.extend_from_slice(after);
+            }
+            LogBody::End => buffer.push(KIND_END),
+            LogBody::PageImage { page_id, image } => {
+                buffer.push(KIND_IMAGE);
+                buffer.extend_from_slice(&page_id.file_id.to_le_bytes());
+                buffer.extend_from_slice(&page_id.page_no.to_le_bytes());
+                buffer.extend_from_slice(&(image.len() as u32).to_le_bytes());
+                buffer.extend_from_slice(image);
+            }
+        }
+        let len = (buffer.len() - start) as u32;
+        let checksum = crc32(&buffer[start + 8..]);
+        buffer[start..start + 4].copy_from_slice(&len.to_le_bytes());
+        buffer[start + 4..start + 8].copy_from_slice(&checksum.to_le_bytes());
+    }
+
+    /// Decodes the record at the start of `bytes` and returns it with its
+    /// encoded length, or `None` if there is no intact record there.
+    fn decode(bytes: &[u8]) -> Option<(Self, usize)> {
+        let len = u32::from_le_bytes(bytes.get(..4)?.try_into().unwrap()) as usize;
+        if len < RECORD_HEADER_SIZE || len > bytes.len() {
+            return None;
+        }
+        let bytes = &bytes[..len];
+        let checksum = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        if crc32(&bytes[8..len]) != checksum {
+            return None;
+        }
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+        let body = match bytes[32] {
+            KIND_BEGIN => LogBody::Begin,
+            KIND_COMMIT => LogBody::Commit,
+            KIND_ABORT => LogBody::Abort,
+            KIND_UPDATE if len >= 53 => {
+                let size = u32_at(49) as usize;
+                let before = bytes.get(53..53 + size)?;
+                let after = bytes.get(53 + size..53 + 2 * size)?;
+                LogBody::Update {
+                    page_id: PageId::new(u32_at(33), u64_at(37)),
+                    offset: u32_at(45),
+                    before: before.to_vec(),
+                    after: after.to_vec(),
+                }
+            }
+            KIND_CLR if len >= 61 => {
+                let size = u32_at(49) as usize;
+                let after = bytes.get(61..61 + size)?;
+                LogBody::Clr {
+                    page_id: PageId::new(u32_at(33), u64_at(37)),
+                    offset: u32_at(45),
+                    after: after.to_vec(),
+                    undo_next_lsn: u64_at(53),
+                }
+            }
+            KIND_END => LogBody::End,
+            KIND_IMAGE if len >= 49 => {
+                let size = u32_at(45) as usize;
+                LogBody::PageImage {
+                    page_id: PageId::new(u32_at(33), u64_at(37)),
+                    image: bytes.get(49..49 + size)?.to_vec(),
+                }
+            }
+            _ => return None,
+        };
+        let record = Self {
+            lsn: u64_at(8),
+            txn_id: u64_at(16),
+            prev_lsn: u64_at(24),
+            body,
+        };
+        Some((record, len))
+    }
+}
+
+struct LogState {
+    /// Records appended but not yet handed to a flush.
+    buffer: Vec<u8>,
+    /// LSN of the first byte in `buffer`.
+    buffer_lsn: Lsn,
+    /// LSN the next record gets.
+    next_lsn: Lsn,
+    /// Everything before this LSN is on stable storage.
+    flushed_lsn: Lsn,
+    /// A thread is writing out records; others wait for it instead of
+    /// starting their own flush.
+    flushing: bool,
+    /// A flush failed; its records are lost, so nothing after them can be
+    /// trusted either.
+    failed: bool,
+    /// Transactions with records appended since the log was opened and no
+    /// end record yet, with their state, first LSN and last LSN.
+    transactions: HashMap<TxnId, (TxnState, Lsn, Lsn)>,
+}
+
+/// Append-only write-ahead log, split into segment files of a fixed size,
+/// `<filename>.000000`, `<filename>.000001` and so on. A record never
+/// straddles two segments; the end of a segment it does not fit in is
+/// zero-filled. Segments holding only records nobody needs anymore are
+/// dropped by `truncate`.
+///
+/// Records are buffered in memory and written out by `flush`. Flushes use
+/// group commit: while one thread writes and syncs the log, every thread
+/// that asks for a flush in the meantime waits, and the next flush writes
+/// all their records with a single sync.
+///
+/// The log also keeps the table of active transactions, updated as their
+/// records are appended. Opening a log does not recover anything; see
+/// `BufferPoolManager::recover`.
+pub struct LogManager {
+    filename: String,
+    segment_size: u64,
+    segments: Mutex<BTreeMap<u64, Arc<File>>>,
+    state: Mutex<LogState>,
+    flushed: Condvar,
+    next_txn_id: AtomicU64,
+    num_records: AtomicU64,
+    num_flushes: AtomicU64,
+}
+
+impl LogManager {
+    /// Opens the log in `filename`, creating it with segments of
+    /// `DEFAULT_SEGMENT_SIZE` if needed.
+    pub fn open(filename: &str) -> std::io::Result<Self> {
+        Self::with_segment_size(filename, DEFAULT_SEGMENT_SIZE)
+    }
+
+    /// Opens the log in `filename`, creating it with segments of
+    /// `segment_size` bytes if needed; an existing log keeps the size it was
+    /// created with. A torn record at the end, left by a crash in the middle
+    /// of a flush, is cut off. Segments must be large enough for an image of
+    /// a whole page, or logging the first change to a page fails.
+    pub fn with_segment_size(filename: &str, segment_size: u64) -> std::io::Result<Self> {
+        if segment_size < MIN_SEGMENT_SIZE {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("log segments must be at least {} bytes", MIN_SEGMENT_SIZE),
+            ));
+        }
+        let mut segments = BTreeMap::new();
+        for segment in list_segments(filename)? {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(segment_path(filename, segment))?;
+            segments.insert(segment, Arc::new(file));
+        }
+
+        let mut max_txn_id = 0;
+        let (segment_size, end) = match segments.first_key_value() {
+            None => {
+                let file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(segment_path(filename, 0))?;
+                file.write_all_at(&segment_header(segment_size), 0)?;
+                file.sync_all()?;
+                sync_parent_dir(filename)?;
+                segments.insert(0, Arc::new(file));
+                (segment_size, FIRST_LSN)
+            }
+            Some((&first, file)) => {
+                let mut header = [0; SEGMENT_HEADER_SIZE as usize];
+                file.read_exact_at(&mut header, 0)
+                    .map_err(|_| Error::new(ErrorKind::InvalidData, "not a log segment: too short"))?;
+                if !header.starts_with(LOG_MAGIC) {
+                    return Err(Error::new(ErrorKind::InvalidData, "not a log segment: bad magic"));
+                }
+                let segment_size = u64::from_le_bytes(header[8..].try_into().unwrap());
+                if segment_size < MIN_SEGMENT_SIZE {
+                    return Err(Error::new(ErrorKind::InvalidData, "bad log segment size"));
+                }
+                let end = Self::scan(&segments, first, segment_size, &mut max_txn_id)?;
+                Self::cut_tail(filename, &mut segments, segment_size, end)?;
+                (segment_size, end)
+            }
+        };
+
+        Ok(Self {
+            filename: filename.to_string(),
+            segment_size,
+            segments: Mutex::new(segments),
+            state: Mutex::new(LogState {
+                buffer: Vec::new(),
+                buffer_lsn: end,
+                next_lsn: end,
+                flushed_lsn: end,
+                flushing: false,
+                failed: false,
+                transactions: HashMap::new(),
+            }),
+            flushed: Condvar::new(),
+            next_txn_id: AtomicU64::new(max_txn_id + 1),
+            num_records: AtomicU64::new(0),
+            num_flushes: AtomicU64::new(0),
+        })
+    }
+
+    /// Reads the records from the start of segment `first` on and returns
+    /// the end of the intact part of the log.
+    fn scan(
+        segments: &BTreeMap<u64, Arc<File>>,
+        first: u64,
+        segment_size: u64,
+        max_txn_id: &mut TxnId,
+    ) -> std::io::Result<Lsn> {
+        let header = segment_header(segment_size);
+        let read_segment = |file: &File| -> std::io::Result<Vec<u8>> {
+            let mut bytes = vec![0; file.metadata()?.len() as usize];
+            file.read_exact_at(&mut bytes, 0)?;
+            Ok(bytes)
+        };
+
+        let mut segment = first;
+        let mut bytes = read_segment(&segments[&first])?;
+        let mut lsn = first * segment_size + SEGMENT_HEADER_SIZE;
+        let mut end = lsn;
+        loop {
+            let offset = (lsn - segment * segment_size) as usize;
+            let rest = &bytes[offset.min(bytes.len())..];
+            // A complete segment ends in zeros or exactly after a record;
+            // the log continues in the next one if that one got its header.
+            if bytes.len() as u64 == segment_size && (rest.len() < 4 || rest[..4] == [0; 4]) {
+                let next = match segments.get(&(segment + 1)) {
+                    Some(file) => read_segment(file)?,
+                    None => break,
+                };
+                if !next.starts_with(&header) {
+                    break;
+                }
+                segment += 1;
+                bytes = next;
+                lsn = segment * segment_size + SEGMENT_HEADER_SIZE;
+                end = lsn;
+                continue;
+            }
+            match LogRecord::decode(rest) {
+                Some((record, len)) if record.lsn == lsn => {
+                    *max_txn_id = (*max_txn_id).max(record.txn_id);
+                    lsn += len as Lsn;
+                    end = lsn;
+                }
+                _ => break,
+            }
+        }
+        Ok(end)
+    }
+
+    /// Cuts the log off at `end`: shortens the segment it falls in and
+    /// deletes every later one.
+    fn cut_tail(
+        filename: &str,
+        segments: &mut BTreeMap<u64, Arc<File>>,
+        segment_size: u64,
+        end: Lsn,
+    ) -> std::io::Result<()> {
+        let last = (end - 1) / segment_size;
+        let file = &segments[&last];
+        let len = end - last * segment_size;
+        if file.metadata()?.len() > len {
+            file.set_len(len)?;
+            file.sync_all()?;
+        }
+        let stale: Vec<u64> = segments.range(last + 1..).map(|(&segment, _)| segment).collect();
+        for segment in &stale {
+            segments.remove(segment);
+            fs::remove_file(segment_path(filename, *segment))?;
+        }
+        if !stale.is_empty() {
+            sync_parent_dir(filename)?;
+        }
+        Ok(())
+    }
+
+    /// Appends a record to the log buffer and returns its LSN. The record is
+    /// only durable once `flush` has covered it.
+    pub fn append(&self, txn_id: TxnId, prev_lsn: Lsn, body: LogBody) -> std::io::Result<Lsn> {
+        if let LogBody::Update { before, after, .. } = &body {
+            if before.len() != after.len() {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "before and after images differ in length",
+                ));
+            }
+        }
+        let len = body.encoded_len() as u64;
+        if len > self.segment_size - SEGMENT_HEADER_SIZE {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "log record does not fit in a segment",
+            ));
+        }
+        let (lsn, full) = {
+            let mut state = self.state.lock().unwrap();
+            if state.failed {
+                return Err(Self::failed());
+            }
+            let mut lsn = state.next_lsn;
+            let offset = lsn % self.segment_size;
+            if offset == 0 || offset + len > self.segment_size {
+                let start = lsn - offset + if offset == 0 { 0 } else { self.segment_size };
+                let padded_len = (start - state.buffer_lsn) as usize;
+                state.buffer.resize(padded_len, 0);
+                state.buffer.extend_from_slice(&segment_header(self.segment_size));
+                lsn = start + SEGMENT_HEADER_SIZE;
+            }
+            let txn_state = match &body {
+                LogBody::Commit => Some(TxnState::Committed),
+                LogBody::Abort | LogBody::Clr { .. } => Some(TxnState::Aborting),
+                LogBody::End => None,
+                LogBody::Begin | LogBody::Update { .. } | LogBody::PageImage { .. } => Some(
+                    state
+                        .transactions
+                        .get(&txn_id)
+                        .map_or(TxnState::Running, |(txn_state, ..)| *txn_state),
+                ),
+            };
+            match txn_state {
+                Some(txn_state) => {
+                    let first_lsn = state
+                        .transactions
+                        .get(&txn_id)
+                        .map_or(lsn, |&(_, first_lsn, _)| first_lsn);
+                    state.transactions.insert(txn_id, (txn_state, first_lsn, lsn));
+                }
+                None => {
+                    state.transactions.remove(&txn_id);
+                }
+            }
+            let record = LogRecord {
+                lsn,
+                txn_id,
+                prev_lsn,
+                body,
+            };
+            record.encode_into(&mut state.buffer);
+            state.next_lsn = state.buffer_lsn + state.buffer.len() as Lsn;
+            (lsn, state.buffer.len() >= LOG_BUFFER_SIZE)
+        };
+        self.num_records.fetch_add(1, Ordering::SeqCst);
+        if full {
+            self.flush(lsn)?;
+        }
+        Ok(lsn)
+    }
+
+    /// Appends a commit record for `txn_id` and waits until it is durable.
+    pub fn commit(&self, txn_id: TxnId, prev_lsn: Lsn) -> std::io::Result<Lsn> {
+        let lsn = self.append(txn_id, prev_lsn, LogBody::Commit)?;
+        self.flush(lsn)?;
+        Ok(lsn)
+    }
+
+    /// Makes the record at `lsn`, and everything before it, durable. An LSN
+    /// past the end of the log flushes everything appended so far.
+    pub fn flush(&self, lsn: Lsn) -> std::io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.failed {
+                return Err(Self::failed());
+            }
+            if state.flushed_lsn > lsn || state.flushed_lsn == state.next_lsn {
+                return Ok(());
+            }
+            if state.flushing {
+                state = self.flushed.wait(state).unwrap();
+                continue;
+            }
+
+            // Lead a flush of everything buffered, including records other
+            // threads are waiting on.
+            state.flushing = true;
+            let buffer = std::mem::take(&mut state.buffer);
+            let start = state.buffer_lsn;
+            let end = state.next_lsn;
+            state.buffer_lsn = end;
+            drop(state);
+
+            let result = self.write_out(&buffer, start);
+
+            state = self.state.lock().unwrap();
+            state.flushing = false;
+            match &result {
+                Ok(()) => state.flushed_lsn = end,
+                Err(_) => state.failed = true,
+            }
+            self.flushed.notify_all();
+            result?;
+            self.num_flushes.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Writes `buffer` to the log at `start`, split over the segments it
+    /// covers, and syncs them.
+    fn write_out(&self, buffer: &[u8], start: Lsn) -> std::io::Result<()> {
+        let mut lsn = start;
+        let mut rest = buffer;
+        let mut written = Vec::new();
+        while !rest.is_empty() {
+            let offset = lsn % self.segment_size;
+            let len = rest.len().min((self.segment_size - offset) as usize);
+            let file = self.segment_file(lsn / self.segment_size, true)?;
+            file.write_all_at(&rest[..len], offset)?;
+            written.push(file);
+            rest = &rest[len..];
+            lsn += len as Lsn;
+        }
+        written.iter().try_for_each(|file| file.sync_data())
+    }
+
+    /// The file of `segment`, created if `create` is set and it does not
+    /// exist yet.
+    fn segment_file(&self, segment: u64, create: bool) -> std::io::Result<Arc<File>> {
+        let mut segments = self.segments.lock().unwrap();
+        if let Some(file) = segments.get(&segment) {
+            return Ok(Arc::clone(file));
+        }
+        if !create {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("log segment {} has been dropped", segment),
+            ));
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(segment_path(&self.filename, segment))?;
+        sync_parent_dir(&self.filename)?;
+        let file = Arc::new(file);
+        segments.insert(segment, Arc::clone(&file));
+        Ok(file)
+    }
+
+    /// Reads the record at `lsn`, flushing the log up to it first.
+    pub fn read(&self, lsn: Lsn) -> std::io::Result<LogRecord> {
+        self.flush(lsn)?;
+        let file = self.segment_file(lsn / self.segment_size, false)?;
+        let offset = lsn % self.segment_size;
+        let mut len = [0; 4];
+        file.read_exact_at(&mut len, offset)?;
+        let mut bytes = vec![0; (u32::from_le_bytes(len) as usize).max(4)];
+        file.read_exact_at(&mut bytes, offset)?;
+        match LogRecord::decode(&bytes) {
+            Some((record, _)) if record.lsn == lsn => Ok(record),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("no log record at LSN {}", lsn),
+            )),
+        }
+    }
+
+    /// Iterates over the records from `lsn` to the end of the log as of the
+    /// call, flushing everything appended so far first. `lsn` may also be an
+    /// earlier `get_next_lsn`.
+    pub fn iter(&self, lsn: Lsn) -> std::io::Result<LogIterator> {
+        self.flush(Lsn::MAX)?;
+        if lsn < self.get_first_lsn() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("LSN {} is in a dropped log segment", lsn),
+            ));
+        }
+        Ok(LogIterator {
+            filename: self.filename.clone(),
+            segment_size: self.segment_size,
+            reader: None,
+            lsn,
+            end: self.get_flushed_lsn(),
+        })
+    }
+
+    /// Deletes the segments that only hold records before `lsn`. The
+    /// segment with the latest durable record is always kept, so LSNs keep
+    /// growing across restarts. Returns how many segments were dropped.
+    pub fn truncate(&self, lsn: Lsn) -> std::io::Result<usize> {
+        let flushed_lsn = self.get_flushed_lsn();
+        let keep_from = (lsn / self.segment_size).min((flushed_lsn - 1) / self.segment_size);
+        let dropped: Vec<u64> = {
+            let mut segments = self.segments.lock().unwrap();
+            let dropped: Vec<u64> = segments.range(..keep_from).map(|(&segment, _)| segment).collect();
+            for segment in &dropped {
+                segments.remove(segment);
+            }
+            dropped
+        };
+        for segment in &dropped {
+            fs::remove_file(segment_path(&self.filename, *segment))?;
+        }
+        if !dropped.is_empty() {
+            sync_parent_dir(&self.filename)?;
+        }
+        Ok(dropped.len())
+    }
+
+    /// Picks an id for a new transaction, unused in this log so far.
+    pub fn new_txn_id(&self) -> TxnId {
+        self.next_txn_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// The transaction table: transactions without an end record, with
+    /// their state and last LSN, ordered by id.
+    pub fn active_transactions(&self) -> Vec<(TxnId, TxnState, Lsn)> {
+        let state = self.state.lock().unwrap();
+        let mut transactions: Vec<_> = state
+            .transactions
+            .iter()
+            .map(|(&txn_id, &(txn_state, _, lsn))| (txn_id, txn_state, lsn))
+            .collect();
+        transactions.sort_unstable_by_key(|&(txn_id, ..)| txn_id);
+        transactions
+    }
+
+    /// First LSN of the oldest transaction in the transaction table; rolling
+    /// it back may need every record from there on.
+    pub fn oldest_active_lsn(&self) -> Option<Lsn> {
+        let state = self.state.lock().unwrap();
+        state.transactions.values().map(|&(_, first_lsn, _)| first_lsn).min()
+    }
+
+    /// Where the checkpoint of the database this log belongs to is kept.
+    pub(crate) fn checkpoint_path(&self) -> String {
+        format!("{}.ckpt", self.filename)
+    }
+
+    /// LSN of the oldest record still in the log.
+    pub fn get_first_lsn(&self) -> Lsn {
+        let segments = self.segments.lock().unwrap();
+        let first = segments.keys().next().copied().unwrap_or(0);
+        first * self.segment_size + SEGMENT_HEADER_SIZE
+    }
+
+    /// Everything before this LSN is on stable storage.
+    pub fn get_flushed_lsn(&self) -> Lsn {
+        self.state.lock().unwrap().flushed_lsn
+    }
+
+    /// LSN the next appended record will get, unless it has to go to the
+    /// next segment.
+    pub fn get_next_lsn(&self) -> Lsn {
+        self.state.lock().unwrap().next_lsn
+    }
+
+    pub fn get_segment_size(&self) -> u64 {
+        self.segment_size
+    }
+
+    /// Segment files the log currently consists of.
+    pub fn get_segment_num(&self) -> usize {
+        self.segments.lock().unwrap().len()
+    }
+
+    /// Records appended since the log was opened.
+    pub fn get_record_num(&self) -> u64 {
+        self.num_records.load(Ordering::SeqCst)
+    }
+
+    /// Writes and syncs of the log file; with group commit usually far fewer
+    /// than the commits they made durable.
+    pub fn get_flush_num(&self) -> u64 {
+        self.num_flushes.load(Ordering::SeqCst)
+    }
+
+    fn failed() -> Error {
+        Error::other("the log is unusable after a failed flush")
+    }
+}
+
+fn segment_path(filename: &str, segment: u64) -> String {
+    format!("{}.{:06}", filename, segment)
+}
+
+fn segment_header(segment_size: u64) -> [u8; SEGMENT_HEADER_SIZE as usize] {
+    let mut header = [0; SEGMENT_HEADER_SIZE as usize];
+    header[..8].copy_from_slice(LOG_MAGIC);
+    header[8..].copy_from_slice(&segment_size.to_le_bytes());
+    header
+}
+
+/// Numbers of the existing segment files of the log in `filename`.
+fn list_segments(filename: &str) -> std::io::Result<Vec<u64>> {
+    let path = Path::new(filename);
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let prefix = format!(
+        "{}.",
+        path.file_name().and_then(|name| name.to_str()).unwrap_or_default()
+    );
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let suffix = match name.to_str().and_then(|name| name.strip_prefix(&prefix)) {
+            Some(suffix) => suffix,
+            None => continue,
+        };
+        if suffix.len() >= 6 && suffix.bytes().all(|byte| byte.is_ascii_digit()) {
+            if let Ok(segment) = suffix.parse() {
+                segments.push(segment);
+            }
+        }
+    }
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+/// Syncs the directory holding `path`, making files created, renamed or
+/// deleted in it durable.
+pub(crate) fn sync_parent_dir(path: &str) -> std::io::Result<()> {
+    match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+/// Records of a log in LSN order, see `LogManager::iter`.
+pub struct LogIterator {
+    filename: String,
+    segment_size: u64,
+    /// Reader positioned at `lsn` in the given segment.
+    reader: Option<(u64, BufReader<File>)>,
+    lsn: Lsn,
+    end: Lsn,
+}
+
+impl LogIterator {
+    /// Reads the record at `lsn`, or returns `None` after moving on to the
+    /// next segment.
+    fn read_next(&mut self) -> std::io::Result<Option<LogRecord>> {
+        let segment = self.lsn / self.segment_size;
+        let offset = self.lsn % self.segment_size;
+        if offset == 0 {
+            self.lsn += SEGMENT_HEADER_SIZE;
+            return Ok(None);
+        }
+        if self.segment_size - offset < 4 {
+            self.next_segment(segment);
+            return Ok(None);
+        }
+        if self.reader.as_ref().is_none_or(|(current, _)| *current != segment) {
+            let mut file = File::open(segment_path(&self.filename, segment))?;
+            file.seek(SeekFrom::Start(offset))?;
+            self.reader = Some((segment, BufReader::new(file)));
+        }
+        let reader = &mut self.reader.as_mut().unwrap().1;
+
+        let mut bytes = vec![0; 4];
+        reader.read_exact(&mut bytes)?;
+        let len = u32::from_le_bytes(bytes[..4].try_into().unwrap()) as usize;
+        if len == 0 {
+            self.next_segment(segment);
+            return Ok(None);
+        }
+        bytes.resize(len.max(4), 0);
+        reader.read_exact(&mut bytes[4..])?;
+        match LogRecord::decode(&bytes) {
+            Some((record, len)) if record.lsn == self.lsn => {
+                self.lsn += len as Lsn;
+                Ok(Some(record))
+            }
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("no log record at LSN {}", self.lsn),
+            )),
+        }
+    }
+
+    /// Skips the zero-filled rest of `segment`.
+    fn next_segment(&mut self, segment: u64) {
+        self.lsn = (segment + 1) * self.segment_size + SEGMENT_HEADER_SIZE;
+        self.reader = None;
+    }
+}
+
+impl Iterator for LogIterator {
+    type Item = std::io::Result<LogRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.lsn < self.end {
+            match self.read_next() {
+                Ok(Some(record)) => return Some(Ok(record)),
+                Ok(None) => continue,
+                Err(e) => {
+                    self.lsn = self.end;
+                    return Some(Err(e));
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory for the segments of one test's log.
+    fn log_path(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("adbs-wal-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("log").to_str().unwrap().to_owned()
+    }
+
+    fn update(i: u8) -> LogBody {
+        LogBody::Update {
+            page_id: PageId::new(0, i as u64),
+            offset: 100,
+            before: vec![i; 200],
+            after: vec![i.wrapping_add(1); 200],
+        }
+    }
+
+    fn append_updates(log: &LogManager, n: u8) -> Vec<LogRecord> {
+        let mut prev_lsn = INVALID_LSN;
+        (0..n)
+            .map(|i| {
+                let lsn = log.append(1, prev_lsn, update(i)).unwrap();
+                let record = LogRecord {
+                    lsn,
+                    txn_id: 1,
+                    prev_lsn,
+                    body: update(i),
+                };
+                prev_lsn = lsn;
+                record
+            })
+            .collect()
+    }
+
+    fn records_from(log: &LogManager, lsn: Lsn) -> Vec<LogRecord> {
+        log.iter(lsn).unwrap().collect::<std::io::Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn records_span_segments_and_survive_reopen() {
+        let path = log_path("segments");
+        let log = LogManager::with_segment_size(&path, MIN_SEGMENT_SIZE).unwrap();
+        let records = append_updates(&log, 60);
+        for record in &records {
+            let end = record.lsn + record.body.encoded_len() as Lsn;
+            assert_eq!(record.lsn / MIN_SEGMENT_SIZE, (end - 1) / MIN_SEGMENT_SIZE, "record straddles segments");
+        }
+        assert_eq!(log.get_flushed_lsn(), FIRST_LSN, "nothing is written before a flush");
+        log.flush(records[30].lsn).unwrap();
+        assert!(log.get_flushed_lsn() > records[30].lsn);
+        assert!(log.get_segment_num() > 1);
+
+        assert_eq!(records_from(&log, FIRST_LSN), records);
+        assert_eq!(log.read(records[45].lsn).unwrap(), records[45]);
+        let next_lsn = log.get_next_lsn();
+        let segment_num = log.get_segment_num();
+        drop(log);
+
+        let log = LogManager::with_segment_size(&path, DEFAULT_SEGMENT_SIZE).unwrap();
+        assert_eq!(log.get_segment_size(), MIN_SEGMENT_SIZE, "a log keeps its segment size");
+        assert_eq!(log.get_next_lsn(), next_lsn);
+        assert_eq!(log.get_segment_num(), segment_num);
+        assert_eq!(records_from(&log, FIRST_LSN), records);
+        assert!(log.new_txn_id() > 1);
+        let _ = fs::remove_dir_all(Path::new(&path).parent().unwrap());
+    }
+
+    #[test]
+    fn truncate_drops_whole_segments_before_the_lsn() {
+        let path = log_path("truncate");
+        let log = LogManager::with_segment_size(&path, MIN_SEGMENT_SIZE).unwrap();
+        let records = append_updates(&log, 60);
+        log.flush(Lsn::MAX).unwrap();
+        let segment_num = log.get_segment_num();
+
+        let keep = &records[40];
+        let dropped = log.truncate(keep.lsn).unwrap();
+        assert_eq!(dropped as u64, keep.lsn / MIN_SEGMENT_SIZE);
+        assert_eq!(log.get_segment_num(), segment_num - dropped);
+        let first_lsn = log.get_first_lsn();
+        assert!(first_lsn <= keep.lsn);
+        assert_eq!(log.iter(FIRST_LSN).err().unwrap().kind(), ErrorKind::NotFound);
+        let kept = records_from(&log, first_lsn);
+        assert_eq!(kept.last(), records.last());
+        assert!(kept.contains(keep));
+
+        // The newest segment stays, so LSNs keep growing after a reopen.
+        let segment_num = log.get_segment_num();
+        assert_eq!(log.truncate(Lsn::MAX).unwrap(), segment_num - 1);
+        assert_eq!(log.get_segment_num(), 1);
+        let next_lsn = log.get_next_lsn();
+        drop(log);
+        let log = LogManager::with_segment_size(&path, MIN_SEGMENT_SIZE).unwrap();
+        assert_eq!(log.get_next_lsn(), next_lsn);
+        assert!(log.append(2, INVALID_LSN, LogBody::Begin).unwrap() >= next_lsn);
+        let _ = fs::remove_dir_all(Path::new(&path).parent().unwrap());
+    }
+
+    #[test]
+    fn torn_tail_is_cut_off_at_open() {
+        let path = log_path("torn");
+        let log = LogManager::with_segment_size(&path, MIN_SEGMENT_SIZE).unwrap();
+        let records = append_updates(&log, 30);
+        log.flush(Lsn::MAX).unwrap();
+        drop(log);
+
+        // Damage the last record, as a crash in the middle of its write would.
+        let torn = records.last().unwrap();
+        let segment = OpenOptions::new()
+            .write(true)
+            .open(segment_path(&path, torn.lsn / MIN_SEGMENT_SIZE))
+            .unwrap();
+        segment.write_all_at(&[0xff; 8], torn.lsn % MIN_SEGMENT_SIZE + 40).unwrap();
+
+        let log = LogManager::with_segment_size(&path, MIN_SEGMENT_SIZE).unwrap();
+        assert_eq!(log.get_next_lsn(), torn.lsn);
+        assert_eq!(records_from(&log, FIRST_LSN), records[..records.len() - 1]);
+        let _ = fs::remove_dir_all(Path::new(&path).parent().unwrap());
+    }
+}